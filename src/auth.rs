@@ -0,0 +1,247 @@
+//! Secure netcode connections. Connect tokens are signed with the server's private key and
+//! handed out by the token issuer listening next to the game port, or written to a file by
+//! `water token` for players that can't reach it.
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::netcode::{generate_random_bytes, ConnectToken, NETCODE_KEY_BYTES};
+
+use crate::{
+    config::TokenSettings,
+    protocol::{decode, Handshake, GAME_PROTOCOL_ID},
+};
+
+const DEFAULT_PRIVATE_KEY_PATH: &str = "private.key";
+
+// a token has to be used within this many seconds
+const TOKEN_EXPIRE_SECONDS: u64 = 300;
+const CLIENT_TIMEOUT_SECONDS: i32 = 15;
+// in seconds, for connecting to the issuer and for its answer
+const TOKEN_REQUEST_TIMEOUT_SECONDS: u64 = 5;
+// a bincode `Handshake` is way smaller, anything bigger is garbage
+const MAX_TOKEN_REQUEST_BYTES: u64 = 1024;
+
+/// The issuer listens on the game address, on the port following the game port. Anyone who
+/// can reach the game can get a token.
+pub fn issuer_addr(game_addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(game_addr.ip(), game_addr.port().wrapping_add(1))
+}
+
+/// Where the private key lives, `WATER_PRIVATE_KEY` overrides the default.
+pub fn private_key_path() -> PathBuf {
+    env::var_os("WATER_PRIVATE_KEY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_PRIVATE_KEY_PATH.into())
+}
+
+/// Reads the raw key at `path`, generating a random one the first time.
+pub fn load_or_create_private_key(path: &Path) -> io::Result<[u8; NETCODE_KEY_BYTES]> {
+    match fs::read(path) {
+        Ok(bytes) => bytes.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} must contain exactly {NETCODE_KEY_BYTES} bytes",
+                    path.display()
+                ),
+            )
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let private_key = generate_random_bytes();
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            // anyone who can read the key can mint tokens for the server
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&private_key)?;
+            info!("Generated a new private key in {}", path.display());
+            Ok(private_key)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Token for a fresh random client id. The handshake ends up in the token user data, which is
+/// what the server checks on connection.
+pub fn issue_token(
+    private_key: &[u8; NETCODE_KEY_BYTES],
+    server_addresses: Vec<SocketAddr>,
+    handshake: &Handshake,
+) -> io::Result<ConnectToken> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let client_id = u64::from_le_bytes(generate_random_bytes());
+    ConnectToken::generate(
+        current_time,
+        GAME_PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        CLIENT_TIMEOUT_SECONDS,
+        server_addresses,
+        Some(&handshake.to_user_data()),
+        private_key,
+    )
+    .map_err(io::Error::other)
+}
+
+/// Serves tokens for the server at `game_addr` on `issuer_addr(game_addr)` from a background
+/// thread, each request on its own thread so a slow client doesn't hold up the others. A
+/// request is a bincode `Handshake`, the response the token bytes.
+pub fn spawn_token_issuer(
+    game_addr: SocketAddr,
+    public_addr: SocketAddr,
+    private_key: [u8; NETCODE_KEY_BYTES],
+) -> io::Result<()> {
+    let listener = TcpListener::bind(issuer_addr(game_addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to accept a token request: {err}");
+                    continue;
+                }
+            };
+            thread::spawn(move || {
+                if let Err(err) = serve_token(stream, game_addr, public_addr, &private_key) {
+                    warn!("Failed to issue a connect token: {err}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve_token(
+    mut stream: TcpStream,
+    game_addr: SocketAddr,
+    public_addr: SocketAddr,
+    private_key: &[u8; NETCODE_KEY_BYTES],
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(TOKEN_REQUEST_TIMEOUT_SECONDS)))?;
+    let mut request = Vec::new();
+    (&mut stream)
+        .take(MAX_TOKEN_REQUEST_BYTES)
+        .read_to_end(&mut request)?;
+    let handshake: Handshake =
+        decode(&request).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    // the server only accepts tokens for its public address, the address the client reached us
+    // on is where it connects to when bound to every interface
+    let reached_addr = SocketAddr::new(stream.local_addr()?.ip(), game_addr.port());
+    let mut server_addresses = vec![public_addr];
+    if reached_addr != public_addr {
+        server_addresses.push(reached_addr);
+    }
+    let token = issue_token(private_key, server_addresses, &handshake)?;
+    debug!(
+        "Issued a token for client {} ({})",
+        token.client_id, handshake.display_name
+    );
+    token.write(&mut stream)
+}
+
+/// Asks the issuer of the server at `server_addr` for a token. Fails if the token isn't good
+/// for `server_addr`, it would connect somewhere else.
+pub fn request_token(server_addr: SocketAddr, handshake: &Handshake) -> io::Result<ConnectToken> {
+    let issuer_addr = issuer_addr(server_addr);
+    let timeout = Duration::from_secs(TOKEN_REQUEST_TIMEOUT_SECONDS);
+    let mut stream = TcpStream::connect_timeout(&issuer_addr, timeout).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("no token issuer on {issuer_addr} ({err}), get a token with `water token`"),
+        )
+    })?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(&bincode::serialize(handshake).unwrap())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut token = ConnectToken::read(&mut stream).map_err(io::Error::other)?;
+    let Some(index) = token
+        .server_addresses
+        .iter()
+        .position(|addr| *addr == Some(server_addr))
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the server at {issuer_addr} is not {server_addr}, get a token with `water token`"
+            ),
+        ));
+    };
+    // the client connects to the first address, the server only checks the private copy
+    token.server_addresses.swap(0, index);
+    Ok(token)
+}
+
+pub fn write_token_file(path: &Path, token: &ConnectToken) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    token.write(&mut bytes)?;
+    fs::write(path, bytes)
+}
+
+pub fn read_token_file(path: &Path) -> io::Result<ConnectToken> {
+    ConnectToken::read(&mut fs::File::open(path)?).map_err(io::Error::other)
+}
+
+/// `water token`, for clients that can't reach the issuer.
+pub fn token_command(settings: &TokenSettings) -> io::Result<()> {
+    let private_key = load_or_create_private_key(&settings.private_key)?;
+    let token = issue_token(
+        &private_key,
+        vec![settings.server_addr],
+        &Handshake::current(&settings.name),
+    )?;
+    write_token_file(&settings.output, &token)?;
+    println!(
+        "Wrote a token for client {} to {}",
+        token.client_id,
+        settings.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_get_unique_ids() {
+        let private_key = generate_random_bytes();
+        let server_addr = "127.0.0.1:5000".parse().unwrap();
+        let handshake = Handshake::current("player");
+        let first = issue_token(&private_key, vec![server_addr], &handshake).unwrap();
+        let second = issue_token(&private_key, vec![server_addr], &handshake).unwrap();
+        assert_ne!(first.client_id, second.client_id);
+
+        let mut bytes = Vec::new();
+        first.write(&mut bytes).unwrap();
+        let read = ConnectToken::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.client_id, first.client_id);
+        assert_eq!(read.protocol_id, GAME_PROTOCOL_ID);
+    }
+
+    #[test]
+    fn test_token_connects_where_it_was_requested() {
+        // a free port for the issuer, the game port is the one before it
+        let issuer_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let game_addr = SocketAddr::from(([127, 0, 0, 1], issuer_port - 1));
+        let public_addr = SocketAddr::from(([192, 0, 2, 1], game_addr.port()));
+        spawn_token_issuer(game_addr, public_addr, generate_random_bytes()).unwrap();
+
+        let token = request_token(game_addr, &Handshake::current("player")).unwrap();
+        assert_eq!(token.server_addresses[0], Some(game_addr));
+        assert_eq!(token.server_addresses[1], Some(public_addr));
+    }
+}
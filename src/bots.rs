@@ -0,0 +1,344 @@
+//! `water bots`: fake players for load testing the server. Every bot is a real netcode
+//! connection sending what a human client sends, driven by random input instead of a keyboard.
+//! Runs without bevy or a window, a report is printed every second.
+use std::{
+    collections::HashSet,
+    io, thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{DisconnectReason, RenetClient};
+use leafwing_input_manager::action_diff::ActionDiff;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use renetcode::ClientAuthentication;
+
+use crate::{
+    auth::request_token,
+    client::{
+        bind_client_socket, ClientAction, ClientChannel, ClientLookDirection, ClientMouseMovement,
+        SnapshotAck,
+    },
+    config::BotSettings,
+    consts::PITCH_LIMIT,
+    input::Action,
+    protocol::{decode, Handshake},
+    server::{connection_config, ServerChannel, ServerMessages, StampedMessage},
+    snapshot::{self, SnapshotDelta, SnapshotHistory},
+    transport::ConditionedClientTransport,
+};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// chance per tick to press or release one of the movement keys
+const MOVE_CHANGE_CHANCE: f64 = 0.05;
+const JUMP_CHANCE: f64 = 0.02;
+const SHOOT_CHANCE: f64 = 0.01;
+const RAILGUN_CHANCE: f64 = 0.005;
+// radians per second, well under `MAX_TURN_SPEED` so the server never has to clamp
+const MAX_BOT_TURN_SPEED: f32 = 4.0;
+const MAX_BOT_PITCH: f32 = PITCH_LIMIT - 0.1;
+
+const MOVEMENT: [Action; 4] = [Action::Forward, Action::Left, Action::Back, Action::Right];
+
+/// Random but plausible input: keys held for a while, smooth turning and pitch inside the limit.
+#[derive(Debug, Default)]
+struct BotScript {
+    pressed: HashSet<Action>,
+    yaw: f32,
+    pitch: f32,
+    turn_speed: f32,
+}
+
+impl BotScript {
+    /// Advances the script by one tick and returns the keys that changed.
+    fn step(&mut self, dt: f32, rng: &mut impl Rng) -> Vec<ActionDiff<Action>> {
+        let mut diffs = Vec::new();
+        // taps from the previous tick
+        for action in [Action::Jump, Action::Shoot, Action::Railgun] {
+            if self.pressed.remove(&action) {
+                diffs.push(ActionDiff::Released { action });
+            }
+        }
+        if rng.gen_bool(MOVE_CHANGE_CHANCE) {
+            let action = MOVEMENT[rng.gen_range(0..MOVEMENT.len())];
+            if self.pressed.remove(&action) {
+                diffs.push(ActionDiff::Released { action });
+            } else {
+                self.pressed.insert(action);
+                diffs.push(ActionDiff::Pressed { action, value: 1.0 });
+            }
+        }
+        for (action, chance) in [
+            (Action::Jump, JUMP_CHANCE),
+            (Action::Shoot, SHOOT_CHANCE),
+            (Action::Railgun, RAILGUN_CHANCE),
+        ] {
+            if rng.gen_bool(chance) {
+                self.pressed.insert(action);
+                diffs.push(ActionDiff::Pressed { action, value: 1.0 });
+            }
+        }
+
+        self.turn_speed = (self.turn_speed + rng.gen_range(-1.0..1.0))
+            .clamp(-MAX_BOT_TURN_SPEED, MAX_BOT_TURN_SPEED);
+        self.yaw = (self.yaw + self.turn_speed * dt).rem_euclid(std::f32::consts::TAU);
+        self.pitch =
+            (self.pitch + rng.gen_range(-1.0..1.0) * dt).clamp(-MAX_BOT_PITCH, MAX_BOT_PITCH);
+        diffs
+    }
+
+    fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw)
+    }
+
+    fn look_direction(&self) -> Vec3 {
+        Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.0) * Vec3::NEG_Z
+    }
+}
+
+/// Server ticks seen by one bot since the last report, from the stamps on its messages.
+#[derive(Debug, Default)]
+struct TickStats {
+    first: Option<(Instant, u32)>,
+    last: Option<(Instant, u32)>,
+    snapshots: u32,
+}
+
+impl TickStats {
+    fn observe(&mut self, now: Instant, tick: u32) {
+        if self.last.is_none_or(|(_, last)| tick > last) {
+            self.first.get_or_insert((now, tick));
+            self.last = Some((now, tick));
+        }
+    }
+
+    /// Milliseconds of wall time between the server ticks this bot saw, as observed through the
+    /// network and its jitter. Only rises above `1000 / tick_rate` once the server falls behind,
+    /// its actual tick time is in the server metrics.
+    fn tick_spacing(&self) -> Option<f64> {
+        let ((start, first), (end, last)) = (self.first?, self.last?);
+        (last > first).then(|| (end - start).as_secs_f64() * 1000.0 / f64::from(last - first))
+    }
+}
+
+struct Bot {
+    name: String,
+    client: RenetClient,
+    transport: ConditionedClientTransport,
+    script: BotScript,
+    sequence: u32,
+    snapshots: SnapshotHistory,
+    stats: TickStats,
+    rejection: Option<String>,
+}
+
+impl Bot {
+    fn connect(settings: &BotSettings, name: String) -> io::Result<Self> {
+        let handshake = Handshake::current(&name);
+        let connect_token = request_token(settings.server_addr, &handshake)?;
+        let authentication = ClientAuthentication::Secure { connect_token };
+        let socket = bind_client_socket(settings.server_addr)?;
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let transport = ConditionedClientTransport::new(current_time, authentication, socket)
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            name,
+            client: RenetClient::new(connection_config()),
+            transport,
+            script: BotScript::default(),
+            sequence: 0,
+            snapshots: SnapshotHistory::default(),
+            stats: TickStats::default(),
+            rejection: None,
+        })
+    }
+
+    fn update(&mut self, dt: Duration, rng: &mut impl Rng) {
+        if self.client.is_disconnected() {
+            return;
+        }
+        self.client.update(dt);
+        if self.transport.update(dt, &mut self.client).is_err() {
+            return;
+        }
+        if self.client.is_connected() {
+            self.receive();
+            self.send_input(dt, rng);
+        }
+        // a failed send disconnects the client, `status` reports why
+        let _ = self.transport.send_packets(&mut self.client);
+    }
+
+    fn receive(&mut self) {
+        let now = Instant::now();
+        while let Some(message) = self.client.receive_message(ServerChannel::Rejection) {
+            self.rejection = Some(String::from_utf8_lossy(&message).into_owned());
+            self.client.disconnect();
+        }
+        while let Some(message) = self.client.receive_message(ServerChannel::ServerMessages) {
+            if let Ok(stamped) = decode::<StampedMessage<ServerMessages>>(&message) {
+                self.stats.observe(now, stamped.tick);
+            }
+        }
+        while let Some(message) = self
+            .client
+            .receive_message(ServerChannel::NetworkedEntities)
+        {
+            let Ok(delta) = decode::<SnapshotDelta>(&message) else {
+                continue;
+            };
+            let baseline = match delta.baseline {
+                Some(tick) => match self.snapshots.get(tick) {
+                    Some(baseline) => Some(baseline),
+                    // not acked, the server falls back to a full snapshot
+                    None => continue,
+                },
+                None => None,
+            };
+            let networked_entities = snapshot::apply(baseline, &delta);
+            let tick = networked_entities.tick;
+            self.snapshots.push(networked_entities);
+
+            let ack = bincode::serialize(&SnapshotAck { tick }).unwrap();
+            self.client.send_message(ClientChannel::SnapshotAck, ack);
+            self.stats.observe(now, tick);
+            self.stats.snapshots += 1;
+        }
+    }
+
+    fn send_input(&mut self, dt: Duration, rng: &mut impl Rng) {
+        self.sequence = self.sequence.wrapping_add(1);
+        let input = ClientAction {
+            sequence: self.sequence,
+            action_diffs: self.script.step(dt.as_secs_f32(), rng),
+        };
+        self.client
+            .send_message(ClientChannel::Input, bincode::serialize(&input).unwrap());
+        let rotation = ClientMouseMovement {
+            rotation: self.script.rotation(),
+        };
+        self.client.send_message(
+            ClientChannel::MouseInput,
+            bincode::serialize(&rotation).unwrap(),
+        );
+        let look = ClientLookDirection {
+            dir: self.script.look_direction(),
+            sequence: self.sequence,
+        };
+        self.client.send_message(
+            ClientChannel::ClientData,
+            bincode::serialize(&look).unwrap(),
+        );
+    }
+
+    fn status(&self) -> String {
+        if let Some(reason) = &self.rejection {
+            return format!("rejected: {reason}");
+        }
+        match self.client.disconnect_reason() {
+            Some(DisconnectReason::Transport) => match self.transport.disconnect_reason() {
+                Some(reason) => format!("disconnected: {reason}"),
+                None => "disconnected by the transport".to_string(),
+            },
+            Some(reason) => format!("disconnected: {reason}"),
+            None if self.client.is_connecting() => "connecting".to_string(),
+            None => {
+                let info = self.client.network_info();
+                let tick_spacing = self
+                    .stats
+                    .tick_spacing()
+                    .map_or("-".to_string(), |ms| format!("{ms:.2}"));
+                format!(
+                    "rtt {:>6.1} ms | up {:>7.1} kbps | down {:>7.1} kbps | loss {:>5.1}% | {:>3} snapshots | {tick_spacing} ms between ticks",
+                    info.rtt * 1000.0,
+                    info.bytes_sent_per_second * 8.0 / 1000.0,
+                    info.bytes_received_per_second * 8.0 / 1000.0,
+                    info.packet_loss * 100.0,
+                    self.stats.snapshots,
+                )
+            }
+        }
+    }
+}
+
+/// Connects `settings.count` bots and drives them at the tick rate until the duration runs out.
+pub fn run(settings: &BotSettings) -> io::Result<()> {
+    let mut rng = match settings.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut bots = (0..settings.count)
+        .map(|index| Bot::connect(settings, format!("{}{index}", settings.name_prefix)))
+        .collect::<io::Result<Vec<_>>>()?;
+    println!("{} bots connecting to {}", bots.len(), settings.server_addr);
+
+    let tick = Duration::from_secs_f64(1.0 / settings.tick_rate);
+    let start = Instant::now();
+    let mut last_tick = start;
+    let mut last_report = start;
+    loop {
+        let now = Instant::now();
+        let dt = now - last_tick;
+        last_tick = now;
+        for bot in bots.iter_mut() {
+            bot.update(dt, &mut rng);
+        }
+
+        if now - last_report >= REPORT_INTERVAL {
+            report(&mut bots, now - start);
+            last_report = now;
+        }
+        if settings
+            .duration
+            .is_some_and(|duration| now - start >= duration)
+            || bots.iter().all(|bot| bot.client.is_disconnected())
+        {
+            break;
+        }
+        thread::sleep(tick.saturating_sub(now.elapsed()));
+    }
+
+    report(&mut bots, start.elapsed());
+    for bot in bots.iter_mut() {
+        bot.transport.disconnect();
+    }
+    Ok(())
+}
+
+fn report(bots: &mut [Bot], elapsed: Duration) {
+    let connected = bots.iter().filter(|bot| bot.client.is_connected()).count();
+    println!(
+        "--- {:.0}s, {connected}/{} connected",
+        elapsed.as_secs_f64(),
+        bots.len()
+    );
+    for bot in bots.iter_mut() {
+        println!("{:>12}: {}", bot.name, bot.status());
+        bot.stats = TickStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_only_sends_changes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut script = BotScript::default();
+        let mut pressed = HashSet::new();
+        for _ in 0..10_000 {
+            for diff in script.step(1.0 / 64.0, &mut rng) {
+                match diff {
+                    ActionDiff::Pressed { action, .. } => assert!(pressed.insert(action)),
+                    ActionDiff::Released { action } => assert!(pressed.remove(&action)),
+                    other => panic!("unexpected diff {other:?}"),
+                }
+            }
+            assert_eq!(pressed, script.pressed);
+            assert!(script.look_direction().y.asin().abs() <= PITCH_LIMIT);
+        }
+    }
+}
@@ -5,12 +5,11 @@ use bevy::{input::mouse::AccumulatedMouseMotion, prelude::*};
 use bevy_renet::renet::RenetClient;
 
 use crate::character::PlayerAction;
-use crate::client::{
-    ClientChannel, ClientLookDirection, ClientMouseMovement, ControlledPlayer, CurrentClientId,
-};
+use crate::client::{ClientChannel, ClientLookDirection, ClientMouseMovement, ControlledPlayer};
+use crate::consts::PITCH_LIMIT;
 use crate::input::LookDirection;
+use crate::prediction::InputSequence;
 use crate::AppState;
-use std::f32::consts::FRAC_PI_2;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
@@ -94,14 +93,10 @@ fn camera_look_around(
     mut camera_q: Query<&mut Transform, (With<WorldCamera>, Without<ControlledPlayer>)>,
     mut movement_action: EventWriter<PlayerAction>,
     client: Option<ResMut<RenetClient>>,
-    client_id: Option<Res<CurrentClientId>>,
 ) {
     let Some(mut client) = client else {
         return;
     };
-    let Some(client_id) = client_id else {
-        return;
-    };
     let Ok((transform, camera_sensitivity)) = player_q.get_single() else {
         return;
     };
@@ -117,7 +112,6 @@ fn camera_look_around(
         let (yaw, pitch, roll) = transform.rotation.to_euler(EulerRot::YXZ);
         let yaw = yaw + delta_yaw;
 
-        const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01;
         let pitch = (pitch + delta_pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
 
         let rotation = Quat::from_euler(EulerRot::YXZ, yaw, 0., roll);
@@ -127,11 +121,7 @@ fn camera_look_around(
         let (yaw, pitch, roll) = camera_tf.rotation.to_euler(EulerRot::YXZ);
         let pitch = (pitch + delta_pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
 
-        let input_message = bincode::serialize(&ClientMouseMovement {
-            rotation,
-            client_id: client_id.0.into(),
-        })
-        .unwrap();
+        let input_message = bincode::serialize(&ClientMouseMovement { rotation }).unwrap();
         client.send_message(ClientChannel::MouseInput, input_message);
 
         camera_tf.rotation = Quat::from_euler(EulerRot::YXZ, 0., pitch, 0.);
@@ -157,18 +147,18 @@ fn sync_look_direction(
 fn send_look_direction(
     look_direction_q: Query<&LookDirection, With<ControlledPlayer>>,
     client: Option<ResMut<RenetClient>>,
-    client_id: Option<Res<CurrentClientId>>,
+    sequence: Option<Res<InputSequence>>,
 ) {
     let Some(mut client) = client else {
         return;
     };
-    let Some(client_id) = client_id else {
+    let Some(sequence) = sequence else {
         return;
     };
     for look_dir in look_direction_q.iter() {
         let input_message = bincode::serialize(&ClientLookDirection {
             dir: look_dir.0,
-            client_id: client_id.0.into(),
+            sequence: sequence.0,
         })
         .unwrap();
         client.send_message(ClientChannel::ClientData, input_message);
@@ -12,11 +12,14 @@ use serde::{Deserialize, Serialize};
 use crate::camera::{CameraSensitivity, PlayerMarker, WorldCamera};
 use crate::client::{ClientAction, ControlledPlayer};
 use crate::consts::{
-    CHARACTER_MODEL_PATH, PLAYER_HEALTH, PSEUDO_MAX_AIR_SPEED, SHOOT_COOLDOWN,
+    CHARACTER_MODEL_PATH, PLAYER_HEALTH, PSEUDO_MAX_AIR_SPEED, RAILGUN_COOLDOWN, SHOOT_COOLDOWN,
     VIEW_MODEL_RENDER_LAYER,
 };
 use crate::input::{build_input_map, Action, LookDirection, MovementIntent};
-use crate::server::{Player, WeaponCooldown};
+use crate::replication::Replicated;
+use crate::server::{
+    LastInputSequence, LastLookSequence, PendingInputs, Player, RailgunCooldown, WeaponCooldown,
+};
 
 pub struct CharacterControllerPlugin;
 
@@ -45,6 +48,7 @@ impl Plugin for CharacterControllerPlugin {
 pub enum PlayerAction {
     Jump,
     Shoot,
+    Railgun,
     Rotate([f32; 4]),
 }
 
@@ -239,20 +243,35 @@ pub fn movement_2(
         //linear_velocity.x += move_intent.0.x * movement_acceleration.0 * delta_time;
         //linear_velocity.z += move_intent.0.z * movement_acceleration.0 * delta_time;
         //debug!("velocity: {:?}", linear_velocity.length());
+        linear_velocity.0 = accelerate(
+            linear_velocity.0,
+            move_intent.0,
+            movement_acceleration.0,
+            delta_time,
+        );
+    }
+}
 
-        // Vector projection of Current velocity onto accelDir.
-        let proj_vel = linear_velocity.0.dot(move_intent.0);
-
-        // Accelerated velocity in direction of movment
-        let mut accel_vel = movement_acceleration.0 * delta_time;
-
-        // If necessary, truncate the accelerated velocity so the vector projection does not exceed max_velocity
-        if proj_vel + accel_vel > PSEUDO_MAX_AIR_SPEED {
-            accel_vel = PSEUDO_MAX_AIR_SPEED - proj_vel;
-        }
-
-        linear_velocity.0 += move_intent.0 * accel_vel;
+/// Air strafing acceleration used by [`movement_2`]. Pulled out so that client-side prediction
+/// can replay it without going through the ECS.
+pub fn accelerate(
+    velocity: Vector3,
+    intent: Vector3,
+    acceleration: Scalar,
+    delta_time: Scalar,
+) -> Vector3 {
+    // Vector projection of Current velocity onto accelDir.
+    let proj_vel = velocity.dot(intent);
+
+    // Accelerated velocity in direction of movment
+    let mut accel_vel = acceleration * delta_time;
+
+    // If necessary, truncate the accelerated velocity so the vector projection does not exceed max_velocity
+    if proj_vel + accel_vel > PSEUDO_MAX_AIR_SPEED {
+        accel_vel = PSEUDO_MAX_AIR_SPEED - proj_vel;
     }
+
+    velocity + intent * accel_vel
 }
 
 /// Slows down movement in the XZ plane.
@@ -260,12 +279,20 @@ pub fn apply_movement_damping(
     mut query: Query<(&MovementDampingFactor, &mut LinearVelocity), With<Grounded>>,
 ) {
     for (damping_factor, mut linear_velocity) in &mut query {
-        // We could use `LinearDamping`, but we don't want to dampen movement along the Y axis
-        linear_velocity.x *= damping_factor.0;
-        linear_velocity.z *= damping_factor.0;
+        linear_velocity.0 = damp(linear_velocity.0, damping_factor.0);
     }
 }
 
+/// Damping applied by [`apply_movement_damping`] while grounded.
+pub fn damp(velocity: Vector3, damping_factor: Scalar) -> Vector3 {
+    // We could use `LinearDamping`, but we don't want to dampen movement along the Y axis
+    Vector3::new(
+        velocity.x * damping_factor,
+        velocity.y,
+        velocity.z * damping_factor,
+    )
+}
+
 #[derive(Component)]
 pub struct PlayerHealthUi;
 
@@ -328,43 +355,82 @@ pub fn build_player_ent(
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
 ) -> Entity {
-    let player_entity = commands
-        .spawn((
-            Name::new("Player entity"),
-            NotShadowCaster,
-            Transform::from_xyz(0.0, 1.5, 0.0),
-            // note: there is a bug with avian
-            //https://github.com/Jondolf/avian/issues/640
-            // box collider get stuck on triangles/ledges
-            // so use capsule instead
-            CharacterControllerBundle::new(Collider::capsule(0.5, 1.2)).with_movement(
-                50.0,
-                0.9,
-                7.0,
-                (20.0 as Scalar).to_radians(),
-            ),
-            Friction::ZERO.with_combine_rule(CoefficientCombine::Min),
-            Restitution::ZERO.with_combine_rule(CoefficientCombine::Min),
-            GravityScale(2.0),
-            PlayerMarker,
-            MovementIntent::default(),
-            TransformInterpolation,
-            CameraSensitivity::default(),
-            InputManagerBundle::with_map(build_input_map()),
-            Player { id: client_id },
-        ))
-        .id();
+    let player_entity = commands.spawn_empty().id();
+    insert_player_ent(
+        commands,
+        player_entity,
+        asset_server,
+        client_id,
+        scenario,
+        meshes,
+        materials,
+    );
+    player_entity
+}
+
+/// Makes `player_entity` a player. Components it already has are kept, clients call this on
+/// entities spawned by replication.
+pub fn insert_player_ent(
+    commands: &mut Commands,
+    player_entity: Entity,
+    asset_server: &Res<AssetServer>,
+    client_id: u64,
+    scenario: NetworkScenario,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+) {
+    commands.entity(player_entity).insert_if_new((
+        Name::new("Player entity"),
+        NotShadowCaster,
+        Transform::from_xyz(0.0, 1.5, 0.0),
+        // note: there is a bug with avian
+        //https://github.com/Jondolf/avian/issues/640
+        // box collider get stuck on triangles/ledges
+        // so use capsule instead
+        CharacterControllerBundle::new(Collider::capsule(0.5, 1.2)).with_movement(
+            50.0,
+            0.9,
+            7.0,
+            (20.0 as Scalar).to_radians(),
+        ),
+        Friction::ZERO.with_combine_rule(CoefficientCombine::Min),
+        Restitution::ZERO.with_combine_rule(CoefficientCombine::Min),
+        GravityScale(2.0),
+        PlayerMarker,
+        MovementIntent::default(),
+        TransformInterpolation,
+        CameraSensitivity::default(),
+        InputManagerBundle::with_map(build_input_map()),
+        Player { id: client_id },
+    ));
 
     commands
         .entity(player_entity)
         .insert(LookDirection::default());
-    commands.entity(player_entity).insert(Health(PLAYER_HEALTH));
+    commands
+        .entity(player_entity)
+        .insert_if_new(Health(PLAYER_HEALTH));
+    commands.entity(player_entity).insert((
+        LastInputSequence::default(),
+        LastLookSequence::default(),
+        PendingInputs::default(),
+    ));
     commands
         .entity(player_entity)
         .insert(WeaponCooldown(Timer::new(
             Duration::from_secs_f32(SHOOT_COOLDOWN),
             TimerMode::Once,
         )));
+    commands
+        .entity(player_entity)
+        .insert(RailgunCooldown(Timer::new(
+            Duration::from_secs_f32(RAILGUN_COOLDOWN),
+            TimerMode::Once,
+        )));
+
+    if matches!(scenario, NetworkScenario::Server) {
+        commands.entity(player_entity).insert(Replicated);
+    }
 
     match scenario {
         NetworkScenario::Server | NetworkScenario::OtherClient => {
@@ -459,6 +525,4 @@ pub fn build_player_ent(
             commands.entity(player_entity).insert(Name::new("MyPlayer"));
         }
     }
-
-    return player_entity;
 }
@@ -0,0 +1,216 @@
+//! In-game text chat, client side. Enter opens the chat box and sends what was typed, Escape
+//! closes it and Tab switches between talking to everyone and to the team. Gameplay input is
+//! disabled while the box is open. Messages relayed by the server (see `server::chat`) show up
+//! in the bottom left corner and fade out after `CHAT_FADE_SECS`.
+use std::{collections::VecDeque, time::Instant};
+
+use bevy::{input::InputSystem, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use bevy_renet::renet::RenetClient;
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{ClientChannel, ControlledPlayer},
+    connection::ConnectionState,
+    consts::{CHAT_FADE_SECS, CHAT_HISTORY_SIZE, MAX_CHAT_MESSAGE_LEN},
+    input::Action,
+};
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatBox>()
+            .init_resource::<ChatHistory>()
+            .add_systems(
+                PreUpdate,
+                (handle_chat_keys, suppress_gameplay_input)
+                    .chain()
+                    .after(InputSystem)
+                    .run_if(in_state(ConnectionState::Connected)),
+            )
+            .add_systems(Update, show_chat)
+            .add_systems(OnExit(ConnectionState::Connected), close_chat);
+    }
+}
+
+/// Who gets a chat message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChatScope {
+    #[default]
+    All,
+    Team,
+}
+
+/// A line typed in the chat box.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientChat {
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// Trims `text` and drops control characters. `None` if nothing is left or it's longer than
+/// `MAX_CHAT_MESSAGE_LEN`.
+pub fn sanitize_chat(text: &str) -> Option<String> {
+    let text: String = text.trim().chars().filter(|c| !c.is_control()).collect();
+    (!text.is_empty() && text.chars().count() <= MAX_CHAT_MESSAGE_LEN).then_some(text)
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct ChatBox {
+    pub open: bool,
+    pub scope: ChatScope,
+    input: String,
+}
+
+/// Messages received from the server, oldest first.
+#[derive(Resource, Debug, Default)]
+pub struct ChatHistory(VecDeque<ChatLine>);
+
+#[derive(Debug)]
+pub struct ChatLine {
+    pub name: String,
+    pub scope: ChatScope,
+    pub text: String,
+    received: Instant,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, name: String, scope: ChatScope, text: String) {
+        if self.0.len() == CHAT_HISTORY_SIZE {
+            self.0.pop_front();
+        }
+        self.0.push_back(ChatLine {
+            name,
+            scope,
+            text,
+            received: Instant::now(),
+        });
+    }
+}
+
+// the keys are read before leafwing and the rest of the game get to see them, so enter and
+// escape don't do anything else
+fn handle_chat_keys(
+    mut keys: ResMut<ButtonInput<KeyCode>>,
+    mut chat: ResMut<ChatBox>,
+    mut client: ResMut<RenetClient>,
+) {
+    if !chat.open {
+        if keys.clear_just_pressed(KeyCode::Enter) {
+            chat.open = true;
+        }
+        return;
+    }
+
+    if keys.clear_just_pressed(KeyCode::Tab) {
+        chat.scope = match chat.scope {
+            ChatScope::All => ChatScope::Team,
+            ChatScope::Team => ChatScope::All,
+        };
+    }
+    if keys.clear_just_pressed(KeyCode::Escape) {
+        chat.open = false;
+        chat.input.clear();
+    }
+    if keys.clear_just_pressed(KeyCode::Enter) {
+        chat.open = false;
+        let input = std::mem::take(&mut chat.input);
+        if let Some(text) = sanitize_chat(&input) {
+            let message = bincode::serialize(&ClientChat {
+                scope: chat.scope,
+                text,
+            })
+            .unwrap();
+            client.send_message(ClientChannel::Chat, message);
+        }
+    }
+}
+
+fn suppress_gameplay_input(
+    chat: Res<ChatBox>,
+    mut action_state_q: Query<&mut ActionState<Action>, With<ControlledPlayer>>,
+) {
+    for mut action_state in action_state_q.iter_mut() {
+        if chat.open && !action_state.disabled() {
+            action_state.disable();
+        } else if !chat.open && action_state.disabled() {
+            action_state.enable();
+        }
+    }
+}
+
+fn close_chat(
+    mut chat: ResMut<ChatBox>,
+    mut history: ResMut<ChatHistory>,
+    mut action_state_q: Query<&mut ActionState<Action>, With<ControlledPlayer>>,
+) {
+    *chat = ChatBox::default();
+    *history = ChatHistory::default();
+    for mut action_state in action_state_q.iter_mut() {
+        action_state.enable();
+    }
+}
+
+fn show_chat(mut contexts: EguiContexts, mut chat: ResMut<ChatBox>, history: Res<ChatHistory>) {
+    let now = Instant::now();
+    egui::Area::new(egui::Id::new("chat"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(10.0, -10.0))
+        .interactable(chat.open)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.set_max_width(400.0);
+            for line in history.0.iter() {
+                // fades out over the last second, everything is visible while typing
+                let age = (now - line.received).as_secs_f32();
+                let alpha = if chat.open {
+                    1.0
+                } else {
+                    (CHAT_FADE_SECS - age).clamp(0.0, 1.0)
+                };
+                if alpha == 0.0 {
+                    continue;
+                }
+                let (prefix, color) = match line.scope {
+                    ChatScope::All => ("", egui::Color32::WHITE),
+                    ChatScope::Team => ("(team) ", egui::Color32::LIGHT_BLUE),
+                };
+                ui.label(
+                    egui::RichText::new(format!("{prefix}{}: {}", line.name, line.text))
+                        .color(color.gamma_multiply(alpha))
+                        .background_color(egui::Color32::from_black_alpha((120.0 * alpha) as u8)),
+                );
+            }
+
+            if chat.open {
+                let scope = match chat.scope {
+                    ChatScope::All => "All",
+                    ChatScope::Team => "Team",
+                };
+                ui.horizontal(|ui| {
+                    ui.label(format!("{scope}:"));
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut chat.input)
+                            .char_limit(MAX_CHAT_MESSAGE_LEN)
+                            .desired_width(340.0),
+                    );
+                    response.request_focus();
+                });
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_is_trimmed_and_length_limited() {
+        assert_eq!(sanitize_chat("  gg\n").as_deref(), Some("gg"));
+        assert_eq!(sanitize_chat("a\u{7}b").as_deref(), Some("ab"));
+        assert_eq!(sanitize_chat(" \t "), None);
+        let long = "é".repeat(MAX_CHAT_MESSAGE_LEN);
+        assert_eq!(sanitize_chat(&long), Some(long.clone()));
+        assert_eq!(sanitize_chat(&(long + "!")), None);
+    }
+}
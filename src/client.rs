@@ -1,29 +1,49 @@
+use std::time::Duration;
+#[cfg(feature = "netcode")]
 use std::{
-    net::UdpSocket,
-    time::{Duration, SystemTime},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::SystemTime,
 };
 
 use crate::{
-    camera::PlayerMarker,
-    character::{build_player_ent, Health, NetworkScenario},
-    consts::ROCKET_SPEED,
+    character::{insert_player_ent, NetworkScenario},
+    chat::{ChatHistory, ChatPlugin},
+    clock::{observe_server_tick, ClockPlugin, ServerClock},
+    config::ClientSettings,
+    connection::{track_connection, ConnectionFailed, ConnectionPlugin, ConnectionState},
+    consts::{RAILGUN_BEAM_DURATION, ROCKET_EXPLOSION_EFFECT_DURATION, ROCKET_EXPLOSION_RADIUS},
+    demo::{start_recording, stop_recording, DemoPlayback, DemoRecorder},
     input::Action,
-    server::{connection_config, NetworkedEntities},
-    water::{GameState, Rocket},
+    interpolation::{InterpolationPlugin, InterpolationSettings, Snapshot, SnapshotBuffer},
+    prediction::{AuthoritativeState, InputSequence, PredictionHistory, PredictionPlugin},
+    protocol::decode,
+    replication::{
+        apply_snapshots, AppliedSnapshot, NetworkMapping, PendingSnapshots, Replicated,
+        ReplicationPlugin, ServerMotion,
+    },
+    server::{connection_config, Player},
+    snapshot::{self, SnapshotDelta, SnapshotHistory},
+    water::{rocket_visuals, Rocket, RocketExplosion},
     AppState,
 };
-use avian3d::prelude::{Collider, LinearVelocity, RigidBody};
-use bevy::{prelude::*, utils::HashMap};
+use avian3d::prelude::LinearVelocity;
+use bevy::prelude::*;
+#[cfg(feature = "netcode")]
+use bevy::tasks::{block_on, futures_lite::future, IoTaskPool, Task};
 use bevy_egui::EguiContexts;
+#[cfg(feature = "netcode")]
 use bevy_renet::{
     client_connected,
-    netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport},
-    renet::{ChannelConfig, ClientId, RenetClient, SendType},
-    RenetClientPlugin,
+    netcode::{ClientAuthentication, ConnectToken, NetcodeTransportError},
+};
+use bevy_renet::{
+    renet::{ChannelConfig, RenetClient, SendType},
+    RenetClientPlugin, RenetReceive,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::server::{ServerChannel, ServerMessages};
+use crate::server::{ServerChannel, ServerMessages, StampedMessage};
 
 use crate::network_visualizer::visualizer::{RenetClientVisualizer, RenetVisualizerStyle};
 use leafwing_input_manager::action_diff::{ActionDiff, ActionDiffEvent};
@@ -33,42 +53,61 @@ use leafwing_input_manager::systems::generate_action_diffs;
 pub struct ClientPlugin;
 
 impl Plugin for ClientPlugin {
-    fn build(&self, mut app: &mut App) {
+    fn build(&self, app: &mut App) {
         app.add_plugins(RenetClientPlugin);
 
         let client = RenetClient::new(connection_config());
         app.insert_resource(client);
 
-        // Setup the transport layer
-        app.add_plugins(NetcodeClientPlugin);
+        app.add_plugins(ConnectionPlugin);
 
         #[cfg(feature = "netcode")]
-        setup_client_netcode(&mut app);
+        setup_client_netcode(app);
 
         #[cfg(feature = "steam")]
-        add_steam_network(&mut app);
+        add_steam_network(app);
 
         app.add_plugins(InputManagerPlugin::<Action>::default());
+        app.add_plugins(ClockPlugin);
+        app.add_plugins(PredictionPlugin);
+        app.add_plugins(ServerStatePlugin);
 
-        app.insert_resource(ClientLobby::default());
         //app.insert_resource(PlayerInput::default());
-        app.insert_resource(NetworkMapping::default());
+        app.init_resource::<ReceivedSnapshots>();
         app.insert_resource(RenetClientVisualizer::<200>::new(
             RenetVisualizerStyle::default(),
         ));
 
-        app.add_systems(PostUpdate, generate_action_diffs::<Action>);
+        // diffed every fixed tick, so each input message holds what changed for that tick
         app.add_systems(
             FixedUpdate,
-            send_action_diffs::<Action>
-                .run_if(in_state(AppState::Main))
-                .in_set(Connected),
+            (
+                generate_action_diffs::<Action>,
+                send_action_diffs::<Action>
+                    .run_if(in_state(AppState::Main))
+                    .in_set(Connected),
+            )
+                .chain(),
         );
+        app.configure_sets(FixedUpdate, ServerStateSet::Receive.in_set(Connected));
         app.add_systems(
             FixedUpdate,
-            (send_message_system, receive_message_system).in_set(Connected),
+            (
+                send_message_system.in_set(Connected),
+                receive_message_system.in_set(ServerStateSet::Receive),
+            ),
         );
         app.add_systems(Update, update_visualizer_system);
+        app.add_systems(OnEnter(ConnectionState::Connected), send_client_settings);
+        // the demo header needs the server's tick rate
+        app.add_systems(
+            Update,
+            start_recording.run_if(resource_added::<ServerTickRate>),
+        );
+        app.add_systems(
+            OnExit(ConnectionState::Connected),
+            (clear_session, stop_recording),
+        );
         app.add_event::<ActionDiffEvent<Action>>();
     }
 }
@@ -76,27 +115,157 @@ impl Plugin for ClientPlugin {
 #[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct Connected;
 
+/// Turns what the server sent into the world: its messages, the snapshots and the entities they
+/// spawn. Fed by `receive_message_system` when connected, or by `demo::play_demo`.
+pub struct ServerStatePlugin;
+
+impl Plugin for ServerStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InterpolationPlugin)
+            .add_plugins(ReplicationPlugin)
+            .add_plugins(ChatPlugin)
+            .init_resource::<NetworkMapping>()
+            .init_resource::<PendingMessages>()
+            .init_resource::<PendingSnapshots>()
+            .init_resource::<AppliedSnapshot>()
+            .add_event::<AuthoritativeState>()
+            .configure_sets(
+                FixedUpdate,
+                (ServerStateSet::Receive, ServerStateSet::Apply).chain(),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    handle_server_messages,
+                    apply_snapshots,
+                    spawn_replicated_players,
+                    spawn_replicated_rockets,
+                    route_server_motion,
+                )
+                    .chain()
+                    .in_set(ServerStateSet::Apply),
+            )
+            .add_systems(Update, (draw_railgun_beams, draw_rocket_explosions));
+    }
+}
+
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerStateSet {
+    /// fills `PendingMessages` and `PendingSnapshots`
+    Receive,
+    Apply,
+}
+
 #[cfg(feature = "netcode")]
-fn setup_client_netcode(mut app: &mut App) {
-    let server_addr = "127.0.0.1:5000".parse().unwrap();
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    let client_id = current_time.as_millis() as u64;
-    let authentication = ClientAuthentication::Unsecure {
-        server_addr,
-        client_id,
-        user_data: None,
-        protocol_id: 0,
-    };
-    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+fn setup_client_netcode(app: &mut App) {
+    use crate::transport::ConditionedClientPlugin;
+
+    // Setup the transport layer
+    app.add_plugins(ConditionedClientPlugin);
     app.configure_sets(FixedUpdate, Connected.run_if(client_connected));
-    app.insert_resource(transport);
-    app.insert_resource(CurrentClientId(client_id));
+    app.add_systems(OnEnter(ConnectionState::Connecting), request_connect_token);
+    app.add_systems(
+        PreUpdate,
+        (
+            connect_with_token.run_if(resource_exists::<PendingConnectToken>),
+            forward_transport_errors,
+        )
+            .after(RenetReceive)
+            .before(track_connection),
+    );
+}
+
+/// Token request running on the IO pool, the issuer might take a while to answer.
+#[cfg(feature = "netcode")]
+#[derive(Resource)]
+struct PendingConnectToken(Task<std::io::Result<ConnectToken>>);
+
+#[cfg(feature = "netcode")]
+fn request_connect_token(mut commands: Commands, settings: Res<ClientSettings>) {
+    use crate::auth::{read_token_file, request_token};
+    use crate::protocol::Handshake;
+    use crate::transport::ConditionedClientTransport;
+
+    let settings = settings.clone();
+    // either a token file made with `water token`, or ask the issuer next to the server
+    let task = IoTaskPool::get().spawn(async move {
+        match &settings.token {
+            Some(token_path) => read_token_file(token_path),
+            None => {
+                let handshake = Handshake::current(&settings.name);
+                request_token(settings.server_addr, &handshake)
+            }
+        }
+    });
+    // a disconnected client stays disconnected, every attempt starts from scratch
+    commands.remove_resource::<ConditionedClientTransport>();
+    commands.insert_resource(RenetClient::new(connection_config()));
+    commands.insert_resource(PendingConnectToken(task));
+}
+
+#[cfg(feature = "netcode")]
+fn connect_with_token(
+    mut commands: Commands,
+    mut pending: ResMut<PendingConnectToken>,
+    mut failures: EventWriter<ConnectionFailed>,
+) {
+    use crate::transport::ConditionedClientTransport;
+
+    let Some(connect_token) = block_on(future::poll_once(&mut pending.0)) else {
+        return;
+    };
+    commands.remove_resource::<PendingConnectToken>();
+
+    let transport = connect_token.and_then(|connect_token| {
+        let client_id = connect_token.client_id;
+        let server_addr = connect_token.server_addresses[0].ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the token has no server address",
+            )
+        })?;
+        let authentication = ClientAuthentication::Secure { connect_token };
+        let socket = bind_client_socket(server_addr)?;
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let transport = ConditionedClientTransport::new(current_time, authentication, socket)
+            .map_err(io::Error::other)?;
+        Ok((transport, client_id))
+    });
+    match transport {
+        Ok((transport, client_id)) => {
+            commands.insert_resource(transport);
+            commands.insert_resource(CurrentClientId(client_id));
+        }
+        Err(err) => {
+            failures.send(ConnectionFailed(format!(
+                "Can't get a connect token: {err}"
+            )));
+        }
+    }
+}
+
+/// The transport knows why it gave up, renet only knows it was the transport.
+/// A socket on every local interface of the same family as `server_addr`, the only ones that
+/// can reach it.
+#[cfg(feature = "netcode")]
+pub fn bind_client_socket(server_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let any_ip: IpAddr = match server_addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    UdpSocket::bind((any_ip, 0))
+}
+
+#[cfg(feature = "netcode")]
+fn forward_transport_errors(
+    mut transport_errors: EventReader<NetcodeTransportError>,
+    mut failures: EventWriter<ConnectionFailed>,
+) {
+    for err in transport_errors.read() {
+        failures.send(ConnectionFailed(err.to_string()));
+    }
 }
 
 #[cfg(feature = "steam")]
@@ -109,17 +278,17 @@ fn add_steam_network(app: &mut App) {
 
     steam_client.networking_utils().init_relay_network_access();
 
-    let args: Vec<String> = std::env::args().collect();
-    let server_steam_id: u64 = args[2].parse().unwrap();
-    let server_steam_id = SteamId::from_raw(server_steam_id);
-
-    let client = RenetClient::new(connection_config());
-    let transport = SteamClientTransport::new(&steam_client, &server_steam_id).unwrap();
+    let Some(server_steam_id) = app.world().resource::<ClientSettings>().steam_server else {
+        error!("Steam builds need the server steam id, pass --steam-server");
+        std::process::exit(2);
+    };
 
     app.add_plugins(SteamClientPlugin);
-    app.insert_resource(client);
-    app.insert_resource(transport);
     app.insert_resource(CurrentClientId(steam_client.user().steam_id().raw()));
+    app.insert_resource(SteamServer {
+        client: steam_client,
+        server: SteamId::from_raw(server_steam_id),
+    });
 
     app.configure_sets(FixedUpdate, Connected.run_if(client_connected));
 
@@ -130,15 +299,43 @@ fn add_steam_network(app: &mut App) {
 
     app.add_systems(PreUpdate, steam_callbacks);
 
-    // If any error is found we just panic
-    #[allow(clippy::never_loop)]
-    fn panic_on_error_system(mut renet_error: EventReader<SteamTransportError>) {
-        for e in renet_error.read() {
-            panic!("{}", e);
+    #[derive(Resource)]
+    struct SteamServer {
+        client: steamworks::Client,
+        server: SteamId,
+    }
+
+    fn connect_steam(
+        mut commands: Commands,
+        steam: Res<SteamServer>,
+        mut failures: EventWriter<ConnectionFailed>,
+    ) {
+        commands.insert_resource(RenetClient::new(connection_config()));
+        match SteamClientTransport::new(&steam.client, &steam.server) {
+            Ok(transport) => commands.insert_resource(transport),
+            Err(err) => {
+                commands.remove_resource::<SteamClientTransport>();
+                failures.send(ConnectionFailed(format!("Can't reach the server: {err:?}")));
+            }
+        }
+    }
+
+    fn forward_steam_errors(
+        mut steam_errors: EventReader<SteamTransportError>,
+        mut failures: EventWriter<ConnectionFailed>,
+    ) {
+        for err in steam_errors.read() {
+            failures.send(ConnectionFailed(err.to_string()));
         }
     }
 
-    app.add_systems(Update, panic_on_error_system);
+    app.add_systems(OnEnter(ConnectionState::Connecting), connect_steam);
+    app.add_systems(
+        PreUpdate,
+        forward_steam_errors
+            .after(RenetReceive)
+            .before(track_connection),
+    );
 }
 
 fn send_message_system(client: ResMut<RenetClient>) {
@@ -147,14 +344,68 @@ fn send_message_system(client: ResMut<RenetClient>) {
     //client.send_message(DefaultChannel::ReliableOrdered, "server message");
 }
 
+/// Tracer left by a railgun shot, see `ServerMessages::RailgunShot`.
+#[derive(Component)]
+struct RailgunBeam {
+    start: Vec3,
+    end: Vec3,
+    timer: Timer,
+}
+
+fn draw_railgun_beams(
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+    time: Res<Time>,
+    mut beams_q: Query<(Entity, &mut RailgunBeam)>,
+) {
+    for (entity, mut beam) in beams_q.iter_mut() {
+        beam.timer.tick(time.delta());
+        if beam.timer.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        gizmos.line(beam.start, beam.end, Color::srgb_u8(120, 200, 255));
+    }
+}
+
+/// Where the server detonated a rocket, see `ServerMessages::RocketExploded`.
+#[derive(Component)]
+struct RocketExplosionEffect {
+    pos: Vec3,
+    timer: Timer,
+}
+
+fn draw_rocket_explosions(
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+    time: Res<Time>,
+    mut explosions_q: Query<(Entity, &mut RocketExplosionEffect)>,
+) {
+    for (entity, mut explosion) in explosions_q.iter_mut() {
+        explosion.timer.tick(time.delta());
+        if explosion.timer.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        // grows to the blast radius, anyone it reached was knocked back
+        let radius = ROCKET_EXPLOSION_RADIUS * explosion.timer.fraction();
+        gizmos.sphere(explosion.pos, radius, Color::srgb_u8(255, 150, 50));
+    }
+}
+
 fn update_visualizer_system(
     mut egui_contexts: EguiContexts,
     mut visualizer: ResMut<RenetClientVisualizer<200>>,
     client: Res<RenetClient>,
+    settings: Res<ClientSettings>,
+    tick_rate: Option<Res<ServerTickRate>>,
     mut show_visualizer: Local<bool>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
 ) {
     visualizer.add_network_info(client.network_info());
+    if let Some(tick_rate) = tick_rate.filter(|rate| rate.is_changed() || settings.is_changed()) {
+        visualizer.set_rates(tick_rate.0, settings.update_rate);
+    }
     if keyboard_input.just_pressed(KeyCode::F1) {
         *show_visualizer = !*show_visualizer;
     }
@@ -163,38 +414,102 @@ fn update_visualizer_system(
     }
 }
 
+fn send_client_settings(
+    mut client: ResMut<RenetClient>,
+    settings: Res<ClientSettings>,
+    interpolation: Res<InterpolationSettings>,
+) {
+    if let Some(rate) = settings.update_rate {
+        request_update_rate(&mut client, rate);
+    }
+    request_interpolation_delay(&mut client, interpolation.delay);
+}
+
+/// Client side entities that aren't replicated but belong to the session.
+type SessionLeftovers = Or<(With<Rocket>, With<RailgunBeam>, With<RocketExplosionEffect>)>;
+
+/// Forgets everything about the session that just ended, a reconnect starts from a clean slate
+/// with a new client id and fresh snapshots.
+#[allow(clippy::too_many_arguments)]
+fn clear_session(
+    mut commands: Commands,
+    mut network_mapping: ResMut<NetworkMapping>,
+    mut pending_snapshots: ResMut<PendingSnapshots>,
+    mut pending_messages: ResMut<PendingMessages>,
+    mut applied_snapshot: ResMut<AppliedSnapshot>,
+    mut received_snapshots: ResMut<ReceivedSnapshots>,
+    mut server_clock: ResMut<ServerClock>,
+    mut input_sequence: ResMut<InputSequence>,
+    mut prediction_history: ResMut<PredictionHistory>,
+    leftovers_q: Query<Entity, SessionLeftovers>,
+) {
+    let mapped = network_mapping.0.drain().map(|(_, entity)| entity);
+    for entity in mapped.chain(leftovers_q.iter()) {
+        if let Some(entity) = commands.get_entity(entity) {
+            entity.despawn_recursive();
+        }
+    }
+    *pending_snapshots = PendingSnapshots::default();
+    *pending_messages = PendingMessages::default();
+    *applied_snapshot = AppliedSnapshot::default();
+    *received_snapshots = ReceivedSnapshots::default();
+    *server_clock = ServerClock::default();
+    *input_sequence = InputSequence::default();
+    *prediction_history = PredictionHistory::default();
+    commands.remove_resource::<ServerTickRate>();
+}
+
 #[derive(Component)]
 pub struct ControlledPlayer;
 
-#[derive(Debug, Default, Resource)]
-struct ClientLobby {
-    players: HashMap<ClientId, PlayerInfo>,
-}
 #[derive(Debug, Resource)]
 pub struct CurrentClientId(pub u64);
 
-#[derive(Debug)]
-struct PlayerInfo {
-    client_entity: Entity,
-    server_entity: Entity,
-}
-
 #[derive(Event, Clone, Deserialize, Serialize, Debug)]
 pub struct ClientAction<A: Actionlike> {
-    pub action_diff: ActionDiff<A>,
-    pub client_id: u64,
+    // input sequence of the fixed tick these changes were simulated on, sent even when empty
+    pub sequence: u32,
+    pub action_diffs: Vec<ActionDiff<A>>,
 }
 
 #[derive(Event, Clone, Deserialize, Serialize, Debug)]
 pub struct ClientMouseMovement {
     pub rotation: Quat, // this is mouse delta * camera sens of player
-    pub client_id: u64,
 }
 
 #[derive(Event, Clone, Deserialize, Serialize, Debug)]
 pub struct ClientLookDirection {
     pub dir: Vec3,
-    pub client_id: u64,
+    // input sequence of the fixed tick this was sampled on, only used to drop reordered ones
+    pub sequence: u32,
+}
+
+/// Tells the server which snapshot we decoded so it can be used as the next delta baseline.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct SnapshotAck {
+    pub tick: u32,
+}
+
+/// Client side settings the server adapts to, sent on `ClientChannel::Settings`.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub enum ClientSetting {
+    /// Snapshots per second we want, the server caps it to its own send rate.
+    UpdateRate(f64),
+    /// How far behind the server we render remote players, our shots are rewound by it.
+    InterpolationDelay(Duration),
+}
+
+pub fn request_update_rate(client: &mut RenetClient, rate: f64) {
+    send_setting(client, &ClientSetting::UpdateRate(rate));
+}
+
+pub fn request_interpolation_delay(client: &mut RenetClient, delay: Duration) {
+    send_setting(client, &ClientSetting::InterpolationDelay(delay));
+}
+
+fn send_setting(client: &mut RenetClient, setting: &ClientSetting) {
+    let message = bincode::serialize(setting).unwrap();
+    client.send_message(ClientChannel::Settings, message);
 }
 
 #[derive(Deserialize, Serialize, Copy, Clone, Eq, Hash, PartialEq, Debug)]
@@ -207,10 +522,14 @@ pub enum ClientInput {
     Shoot,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum ClientChannel {
     Input,
     MouseInput,
     ClientData, // client authoritative data (?)
+    SnapshotAck,
+    Settings,
+    Chat,
 }
 
 impl From<ClientChannel> for u8 {
@@ -219,6 +538,9 @@ impl From<ClientChannel> for u8 {
             ClientChannel::Input => 0,
             ClientChannel::MouseInput => 1,
             ClientChannel::ClientData => 2,
+            ClientChannel::SnapshotAck => 3,
+            ClientChannel::Settings => 4,
+            ClientChannel::Chat => 5,
         }
     }
 }
@@ -242,167 +564,283 @@ impl ClientChannel {
                 max_memory_usage_bytes: 5 * 1024 * 1024,
                 send_type: SendType::Unreliable,
             },
+            ChannelConfig {
+                channel_id: Self::SnapshotAck.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: Self::Settings.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
+            ChannelConfig {
+                channel_id: Self::Chat.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
         ]
     }
 }
 
+/// Snapshots decoded from the server, kept around as baselines for the next deltas.
 #[derive(Default, Resource)]
-// maps from server enttiy to client entity
-struct NetworkMapping(HashMap<Entity, Entity>);
+struct ReceivedSnapshots(SnapshotHistory);
+
+/// Server messages decoded this frame, oldest first, waiting for `handle_server_messages`.
+#[derive(Resource, Debug, Default)]
+pub struct PendingMessages(pub Vec<StampedMessage<ServerMessages>>);
+
+/// Ticks per second of the server we're connected to, our fixed ticks run at the same rate.
+#[derive(Resource, Debug)]
+pub struct ServerTickRate(pub f64);
 
 fn receive_message_system(
     mut client: ResMut<RenetClient>,
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut lobby: ResMut<ClientLobby>,
-    mut network_mapping: ResMut<NetworkMapping>,
-    asset_server: Res<AssetServer>,
-    client_id: Option<Res<CurrentClientId>>,
-    mut players_q: Query<
-        (&mut Transform, &mut LinearVelocity, &mut Health, Entity),
-        With<PlayerMarker>,
-    >,
+    time_fixed: Res<Time<Fixed>>,
+    mut server_clock: ResMut<ServerClock>,
+    mut received_snapshots: ResMut<ReceivedSnapshots>,
+    mut pending_snapshots: ResMut<PendingSnapshots>,
+    mut pending_messages: ResMut<PendingMessages>,
+    mut recorder: Option<ResMut<DemoRecorder>>,
 ) {
-    let Some(client_id) = client_id else {
-        return;
-    };
     while let Some(message) = client.receive_message(ServerChannel::ServerMessages) {
-        let server_message = bincode::deserialize(&message).unwrap();
-        match server_message {
-            ServerMessages::PlayerCreate {
-                id,
-                translation,
-                entity,
-            } => {
-                debug!("Spawning player entity for  client:{}", id);
-
-                let client_entity = if client_id.0 == id {
-                    build_player_ent(
-                        &mut commands,
-                        &asset_server,
-                        id,
-                        NetworkScenario::MyClient,
-                        &mut meshes,
-                        &mut materials,
-                    )
-                } else {
-                    build_player_ent(
-                        &mut commands,
-                        &asset_server,
-                        id,
-                        NetworkScenario::OtherClient,
-                        &mut meshes,
-                        &mut materials,
-                    )
-                };
-
-                let player_info = PlayerInfo {
-                    server_entity: entity,
-                    client_entity,
-                };
-                lobby.players.insert(id, player_info);
-                network_mapping.0.insert(entity, client_entity);
+        let stamped: StampedMessage<ServerMessages> = match decode(&message) {
+            Ok(stamped) => stamped,
+            Err(err) => {
+                warn!(
+                    "Malformed {:?} message from the server: {err}",
+                    ServerChannel::ServerMessages
+                );
+                continue;
+            }
+        };
+        observe_server_tick(&mut server_clock, &client, &time_fixed, stamped.tick);
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record_message(&stamped);
+        }
+        pending_messages.0.push(stamped);
+    }
+
+    while let Some(message) = client.receive_message(ServerChannel::NetworkedEntities) {
+        let delta: SnapshotDelta = match decode(&message) {
+            Ok(delta) => delta,
+            Err(err) => {
+                warn!(
+                    "Malformed {:?} message from the server: {err}",
+                    ServerChannel::NetworkedEntities
+                );
+                continue;
             }
-            ServerMessages::PlayerRemove { id } => {
-                println!("Player {} disconnected.", id);
-                if let Some(PlayerInfo {
-                    server_entity,
-                    client_entity,
-                }) = lobby.players.remove(&id)
-                {
-                    commands.entity(client_entity).despawn();
-                    network_mapping.0.remove(&server_entity);
+        };
+        let baseline = match delta.baseline {
+            Some(tick) => match received_snapshots.0.get(tick) {
+                Some(baseline) => Some(baseline),
+                None => {
+                    // the server will send a full snapshot once it notices we stopped acking
+                    debug!("missing baseline {tick} for snapshot {}", delta.tick);
+                    continue;
                 }
+            },
+            None => None,
+        };
+        let networked_entities = snapshot::apply(baseline, &delta);
+
+        let ack = bincode::serialize(&SnapshotAck {
+            tick: networked_entities.tick,
+        })
+        .unwrap();
+        client.send_message(ClientChannel::SnapshotAck, ack);
+
+        observe_server_tick(
+            &mut server_clock,
+            &client,
+            &time_fixed,
+            networked_entities.tick,
+        );
+
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record_snapshot(&networked_entities);
+        }
+        // written to the world by `apply_snapshots`
+        pending_snapshots.0.push(networked_entities.clone());
+        received_snapshots.0.push(networked_entities);
+    }
+}
+
+fn handle_server_messages(
+    mut pending_messages: ResMut<PendingMessages>,
+    mut commands: Commands,
+    network_mapping: Res<NetworkMapping>,
+    mut explosions: EventWriter<RocketExplosion>,
+    mut chat_history: ResMut<ChatHistory>,
+    mut time_fixed: ResMut<Time<Fixed>>,
+) {
+    for StampedMessage { tick, message } in pending_messages.0.drain(..) {
+        match message {
+            ServerMessages::RocketExploded { id, pos } => {
+                let pos = pos.get();
+                // `handle_rocket_explosion` despawns our copy and knocks our player back
+                explosions.send(RocketExplosion {
+                    pos,
+                    ent: network_mapping.0.get(&id).copied(),
+                    tick: Some(tick),
+                });
+                commands.spawn(RocketExplosionEffect {
+                    pos,
+                    timer: Timer::from_seconds(ROCKET_EXPLOSION_EFFECT_DURATION, TimerMode::Once),
+                });
             }
-            ServerMessages::BulletCreate { translation, dir } => {
-                let rocket_speed = Vec3::from_array(dir) * ROCKET_SPEED;
-                commands.spawn((
-                    Name::new("Bullet"),
-                    Rocket,
-                    LinearVelocity(rocket_speed),
-                    RigidBody::Kinematic,
-                    Collider::cuboid(0.2, 0.2, 0.2),
-                    Mesh3d(meshes.add(Cuboid::from_length(0.2))),
-                    MeshMaterial3d(materials.add(Color::srgb_u8(154, 109, 100))),
-                    Transform::from_translation(translation.into()),
-                ));
+
+            ServerMessages::RailgunShot {
+                id,
+                start,
+                end,
+                target,
+            } => {
+                debug!("client {} fired the railgun, hit {:?}", id, target);
+                commands.spawn(RailgunBeam {
+                    start: start.get(),
+                    end: end.get(),
+                    timer: Timer::from_seconds(RAILGUN_BEAM_DURATION, TimerMode::Once),
+                });
             }
 
-            ServerMessages::PlayerDeath { server_ent, id } => {
-                let client_ent = network_mapping.0.get(&server_ent);
-                if let Some(client_ent) = client_ent {
-                    if let Some(commands) = commands.get_entity(*client_ent) {
-                        debug!("received player death, despawning");
-                        commands.despawn_recursive();
-                    }
+            ServerMessages::Chat {
+                name, scope, text, ..
+            } => {
+                info!("[{scope:?}] {name}: {text}");
+                chat_history.push(name, scope, text);
+            }
+
+            ServerMessages::ServerInfo { tick_rate } => {
+                if !(tick_rate.is_finite() && tick_rate > 0.0) {
+                    warn!("Ignoring the server's tick rate of {tick_rate}");
+                    continue;
                 }
+                time_fixed.set_timestep_hz(tick_rate);
+                commands.insert_resource(ServerTickRate(tick_rate));
             }
         }
     }
+}
 
-    while let Some(message) = client.receive_message(ServerChannel::NetworkedEntities) {
-        let networked_entities: NetworkedEntities = bincode::deserialize(&message).unwrap();
+/// Entities with `C` that replication spawned this frame.
+type NewlyReplicated<C> = (Added<C>, With<Replicated>);
 
-        for i in 0..networked_entities.entities.len() {
-            if let Some(entity) = network_mapping.0.get(&networked_entities.entities[i]) {
-                let translation = networked_entities.translations[i].into();
-                let velocity = LinearVelocity(networked_entities.velocities[i].into());
+/// Players spawned by replication only have what the server sent, this adds everything else.
+fn spawn_replicated_players(
+    mut commands: Commands,
+    new_players_q: Query<(Entity, &Player), NewlyReplicated<Player>>,
+    client_id: Option<Res<CurrentClientId>>,
+    playback: Option<Res<DemoPlayback>>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // nobody is ours when watching a demo
+    let own_id = match client_id {
+        Some(client_id) => Some(client_id.0),
+        None if playback.is_some() => None,
+        None => return,
+    };
+    for (entity, player) in new_players_q.iter() {
+        debug!("Spawning player entity for  client:{}", player.id);
+        let scenario = if Some(player.id) == own_id {
+            NetworkScenario::MyClient
+        } else {
+            commands.entity(entity).insert(SnapshotBuffer::default());
+            NetworkScenario::OtherClient
+        };
+        insert_player_ent(
+            &mut commands,
+            entity,
+            &asset_server,
+            player.id,
+            scenario,
+            &mut meshes,
+            &mut materials,
+        );
+    }
+}
 
-                /*
-                debug!(
-                    "Updating transform of {:?}, New Transform: {:?}",
-                    entity, transform
-                );
-                 */
+/// Rockets spawned by replication, interpolated like remote players.
+fn spawn_replicated_rockets(
+    mut commands: Commands,
+    new_rockets_q: Query<Entity, NewlyReplicated<Rocket>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for entity in new_rockets_q.iter() {
+        commands.entity(entity).insert((
+            rocket_visuals(&mut meshes, &mut materials),
+            LinearVelocity::default(),
+            SnapshotBuffer::default(),
+        ));
+    }
+}
 
-                let Ok((mut player_tf, mut player_velocity, mut player_health, _)) =
-                    players_q.get_mut(*entity)
-                else {
-                    continue;
-                };
-
-                player_tf.translation = translation;
-                *player_velocity = velocity;
-                player_health.0 = networked_entities.health[i];
-
-                if lobby
-                    .players
-                    .get(&client_id.0)
-                    .is_some_and(|inner| inner.client_entity != *entity)
-                {
-                    player_tf.rotation = Quat::from_array(networked_entities.rotations[i]);
-                }
-                //commands.entity(*entity).insert(transform);
+/// Everything `route_server_motion` can hand the server motion to.
+type MotionTargets<'a> = (
+    &'a ServerMotion,
+    &'a mut Transform,
+    Option<&'a mut LinearVelocity>,
+    Option<&'a mut SnapshotBuffer>,
+    Has<ControlledPlayer>,
+);
+
+/// Hands what the server says about an entity to whoever is in charge of moving it.
+fn route_server_motion(
+    mut motion_q: Query<MotionTargets, Changed<ServerMotion>>,
+    mut authoritative_state: EventWriter<AuthoritativeState>,
+) {
+    for (motion, mut transform, velocity, snapshot_buffer, controlled) in motion_q.iter_mut() {
+        if controlled {
+            // our own player is predicted, let reconciliation decide what to do with it
+            authoritative_state.send(AuthoritativeState {
+                sequence: motion.input_sequence,
+                translation: motion.translation,
+                velocity: motion.velocity,
+            });
+        } else if let Some(mut buffer) = snapshot_buffer {
+            // remote players and rockets are rendered later by `interpolate_remote_players`
+            buffer.push(Snapshot {
+                tick: motion.tick,
+                translation: motion.translation,
+                rotation: motion.rotation,
+                velocity: motion.velocity,
+            });
+        } else {
+            transform.translation = motion.translation;
+            transform.rotation = motion.rotation;
+            if let Some(mut velocity) = velocity {
+                velocity.0 = motion.velocity;
             }
         }
     }
 }
 
 fn send_action_diffs<A: Actionlike + Serialize>(
-    mut action_state_query: Query<&mut ActionState<A>>,
     mut action_diff_events: EventReader<ActionDiffEvent<A>>,
+    sequence: Res<InputSequence>,
     client: Option<ResMut<RenetClient>>,
-    client_id: Option<Res<CurrentClientId>>,
 ) {
     let Some(mut client) = client else {
         return;
     };
-    let Some(client_id) = client_id else {
-        return;
-    };
-    for action_diff_event in action_diff_events.read() {
-        if let Some(owner) = action_diff_event.owner {
-            let action_state = action_state_query.get_mut(owner).unwrap();
-            action_diff_event.action_diffs.iter().for_each(|diff| {
-                // @performance should we send entire vec maybe?
-                let input_message = bincode::serialize(&ClientAction {
-                    action_diff: diff.clone(),
-                    client_id: client_id.0.into(),
-                })
-                .unwrap();
-                client.send_message(ClientChannel::Input, input_message);
-            });
-        }
-    }
+    let action_diffs = action_diff_events
+        .read()
+        .filter(|event| event.owner.is_some())
+        .flat_map(|event| event.action_diffs.iter().cloned())
+        .collect();
+    let input_message = bincode::serialize(&ClientAction {
+        sequence: sequence.0,
+        action_diffs,
+    })
+    .unwrap();
+    client.send_message(ClientChannel::Input, input_message);
 }
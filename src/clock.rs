@@ -0,0 +1,129 @@
+pub struct ClockPlugin;
+
+use bevy::prelude::*;
+use bevy_renet::renet::RenetClient;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerClock>()
+            .add_systems(PreUpdate, advance_server_clock);
+    }
+}
+
+// in ticks. Past this we stop smoothing and jump to the new estimate
+const MAX_CLOCK_DRIFT_TICKS: f64 = 8.0;
+// fraction of the error corrected per sample, keeps the clock from jittering with packet arrival
+const CLOCK_CORRECTION: f64 = 0.1;
+
+/// Client side estimate of the tick the server is simulating right now.
+///
+/// Every stamped message gives a sample: the tick it was sent on plus half the RTT. Samples
+/// are smoothed and the clock keeps running on local time in between.
+#[derive(Resource, Debug, Default)]
+pub struct ServerClock {
+    tick: f64,
+    rtt: f64,
+    synced: bool,
+}
+
+impl ServerClock {
+    /// Feeds a message stamped with `server_tick`, received with the given round trip time
+    /// in seconds.
+    pub fn observe(&mut self, server_tick: u32, rtt: f64, tick_duration: f64) {
+        self.rtt = rtt;
+        let estimate = server_tick as f64 + (rtt / 2.0) / tick_duration;
+        if !self.synced || (estimate - self.tick).abs() > MAX_CLOCK_DRIFT_TICKS {
+            self.tick = estimate;
+            self.synced = true;
+        } else {
+            self.tick += (estimate - self.tick) * CLOCK_CORRECTION;
+        }
+    }
+
+    /// Puts the clock at `tick`, for demos where the server tick is known rather than
+    /// estimated.
+    pub fn set(&mut self, tick: f64) {
+        self.tick = tick;
+        self.synced = true;
+    }
+
+    pub fn advance(&mut self, delta_secs: f64, tick_duration: f64) {
+        if self.synced {
+            self.tick += delta_secs / tick_duration;
+        }
+    }
+
+    /// Estimated current server tick, with the fraction of the tick that already elapsed.
+    pub fn estimated_tick(&self) -> Option<f64> {
+        self.synced.then_some(self.tick)
+    }
+
+    /// Half of the last round trip time, in ticks.
+    pub fn one_way_ticks(&self, tick_duration: f64) -> f64 {
+        (self.rtt / 2.0) / tick_duration
+    }
+}
+
+/// Same fixed rate as the server, see `ServerTick`.
+pub fn tick_duration(time_fixed: &Time<Fixed>) -> f64 {
+    time_fixed.timestep().as_secs_f64()
+}
+
+fn advance_server_clock(
+    time: Res<Time>,
+    time_fixed: Res<Time<Fixed>>,
+    mut clock: ResMut<ServerClock>,
+) {
+    clock.advance(time.delta_secs_f64(), tick_duration(&time_fixed));
+}
+
+/// Samples the clock with the tick of a message that just arrived.
+pub fn observe_server_tick(
+    clock: &mut ServerClock,
+    client: &RenetClient,
+    time_fixed: &Time<Fixed>,
+    server_tick: u32,
+) {
+    clock.observe(
+        server_tick,
+        client.network_info().rtt,
+        tick_duration(time_fixed),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: f64 = 1.0 / 64.0;
+
+    #[test]
+    fn test_first_sample_syncs() {
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.estimated_tick(), None);
+        // 100ms rtt is 3.2 ticks one way
+        clock.observe(100, 0.1, TICK);
+        assert!((clock.estimated_tick().unwrap() - 103.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_jitter_is_smoothed() {
+        let mut clock = ServerClock::default();
+        clock.observe(100, 0.0, TICK);
+        clock.observe(102, 0.0, TICK);
+        let tick = clock.estimated_tick().unwrap();
+        assert!(tick > 100.0 && tick < 102.0);
+        clock.observe(200, 0.0, TICK);
+        assert_eq!(clock.estimated_tick(), Some(200.0));
+    }
+
+    #[test]
+    fn test_advances_with_local_time() {
+        let mut clock = ServerClock::default();
+        clock.advance(1.0, TICK);
+        assert_eq!(clock.estimated_tick(), None);
+        clock.observe(100, 0.0, TICK);
+        clock.advance(0.5, TICK);
+        assert_eq!(clock.estimated_tick(), Some(132.0));
+    }
+}
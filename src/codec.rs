@@ -0,0 +1,211 @@
+//! Compact wire encodings for the values we replicate the most. Everything is bounded so the
+//! packed integers have a known precision, see the constants below.
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// positions are clamped to [-POSITION_BOUND, POSITION_BOUND] meters on every axis
+pub const POSITION_BOUND: f32 = 512.0;
+const POSITION_BITS: u32 = 20;
+const POSITION_MAX: u64 = (1 << POSITION_BITS) - 1;
+// one quantization step (~1mm), the error of a decoded position component stays below it
+#[cfg(test)]
+const POSITION_PRECISION: f32 = 2.0 * POSITION_BOUND / POSITION_MAX as f32;
+
+// velocities are clamped to [-VELOCITY_BOUND, VELOCITY_BOUND) meters per sec on every axis
+pub const VELOCITY_BOUND: f32 = 128.0;
+const VELOCITY_SCALE: f32 = i16::MAX as f32 / VELOCITY_BOUND;
+#[cfg(test)]
+const VELOCITY_PRECISION: f32 = 0.5 / VELOCITY_SCALE;
+
+const ROTATION_BITS: u32 = 10;
+const ROTATION_MAX: u32 = (1 << ROTATION_BITS) - 1;
+// the three smallest components of a unit quaternion are always within +-1/sqrt(2)
+const ROTATION_BOUND: f32 = std::f32::consts::FRAC_1_SQRT_2;
+#[cfg(test)]
+const ROTATION_PRECISION: f32 = ROTATION_BOUND / ROTATION_MAX as f32;
+
+fn quantize(value: f32, bound: f32, max: u64) -> u64 {
+    let normalized = (value.clamp(-bound, bound) + bound) / (2.0 * bound);
+    (normalized * max as f32).round() as u64
+}
+
+fn dequantize(value: u64, bound: f32, max: u64) -> f32 {
+    (value as f32 / max as f32) * 2.0 * bound - bound
+}
+
+/// Position packed as three 20 bit fixed point values.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuantizedPosition(u64);
+
+impl QuantizedPosition {
+    pub fn new(position: Vec3) -> Self {
+        let x = quantize(position.x, POSITION_BOUND, POSITION_MAX);
+        let y = quantize(position.y, POSITION_BOUND, POSITION_MAX);
+        let z = quantize(position.z, POSITION_BOUND, POSITION_MAX);
+        Self(x | (y << POSITION_BITS) | (z << (2 * POSITION_BITS)))
+    }
+
+    pub fn get(self) -> Vec3 {
+        let x = self.0 & POSITION_MAX;
+        let y = (self.0 >> POSITION_BITS) & POSITION_MAX;
+        let z = (self.0 >> (2 * POSITION_BITS)) & POSITION_MAX;
+        Vec3::new(
+            dequantize(x, POSITION_BOUND, POSITION_MAX),
+            dequantize(y, POSITION_BOUND, POSITION_MAX),
+            dequantize(z, POSITION_BOUND, POSITION_MAX),
+        )
+    }
+}
+
+/// Unit quaternion in "smallest three" form: the index of the largest component in the two
+/// low bits, then the three remaining components on 10 bits each. The largest component is
+/// rebuilt from the unit length.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizedRotation(u32);
+
+impl Default for QuantizedRotation {
+    fn default() -> Self {
+        Self::new(Quat::IDENTITY)
+    }
+}
+
+impl QuantizedRotation {
+    pub fn new(rotation: Quat) -> Self {
+        let rotation = rotation.normalize();
+        let components = rotation.to_array();
+        let largest = (0..4)
+            .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+            .unwrap();
+        // q and -q are the same rotation, make the dropped component positive
+        let sign = components[largest].signum();
+
+        let mut packed = largest as u32;
+        let mut shift = 2;
+        for (index, component) in components.iter().enumerate() {
+            if index == largest {
+                continue;
+            }
+            let value = quantize(component * sign, ROTATION_BOUND, ROTATION_MAX as u64) as u32;
+            packed |= value << shift;
+            shift += ROTATION_BITS;
+        }
+        Self(packed)
+    }
+
+    pub fn get(self) -> Quat {
+        let largest = (self.0 & 0b11) as usize;
+        let mut components = [0.0; 4];
+        let mut shift = 2;
+        let mut sum_squares = 0.0;
+        for (index, component) in components.iter_mut().enumerate() {
+            if index == largest {
+                continue;
+            }
+            let value = (self.0 >> shift) & ROTATION_MAX;
+            *component = dequantize(value as u64, ROTATION_BOUND, ROTATION_MAX as u64);
+            sum_squares += *component * *component;
+            shift += ROTATION_BITS;
+        }
+        components[largest] = (1.0 - sum_squares).max(0.0).sqrt();
+        Quat::from_array(components).normalize()
+    }
+}
+
+/// Velocity with 1/256 m/s steps.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuantizedVelocity([i16; 3]);
+
+impl QuantizedVelocity {
+    pub fn new(velocity: Vec3) -> Self {
+        let quantized = (velocity * VELOCITY_SCALE)
+            .round()
+            .clamp(Vec3::splat(i16::MIN as f32), Vec3::splat(i16::MAX as f32));
+        Self([quantized.x as i16, quantized.y as i16, quantized.z as i16])
+    }
+
+    pub fn get(self) -> Vec3 {
+        Vec3::new(self.0[0] as f32, self.0[1] as f32, self.0[2] as f32) / VELOCITY_SCALE
+    }
+}
+
+/// Health doesn't need more than a byte, `PLAYER_HEALTH` fits.
+pub fn quantize_health(health: usize) -> u8 {
+    health.min(u8::MAX as usize) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    #[test]
+    fn test_position_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let position = Vec3::new(
+                rng.gen_range(-POSITION_BOUND..POSITION_BOUND),
+                rng.gen_range(-POSITION_BOUND..POSITION_BOUND),
+                rng.gen_range(-POSITION_BOUND..POSITION_BOUND),
+            );
+            let decoded = QuantizedPosition::new(position).get();
+            assert!((decoded - position).abs().max_element() <= POSITION_PRECISION);
+        }
+    }
+
+    #[test]
+    fn test_position_is_clamped() {
+        let decoded = QuantizedPosition::new(Vec3::new(1000.0, -1000.0, 0.0)).get();
+        assert_eq!(decoded.x, POSITION_BOUND);
+        assert_eq!(decoded.y, -POSITION_BOUND);
+    }
+
+    #[test]
+    fn test_rotation_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut rotations = vec![
+            Quat::IDENTITY,
+            Quat::from_rotation_y(PI),
+            Quat::from_xyzw(0.0, 0.0, 0.0, -1.0),
+        ];
+        for _ in 0..1000 {
+            rotations.push(Quat::from_euler(
+                EulerRot::YXZ,
+                rng.gen_range(-PI..PI),
+                rng.gen_range(-FRAC_PI_2..FRAC_PI_2),
+                rng.gen_range(-PI..PI),
+            ));
+        }
+        for rotation in rotations {
+            let decoded = QuantizedRotation::new(rotation).get();
+            // q and -q are the same rotation
+            let error = (decoded.dot(rotation).abs() - 1.0).abs();
+            assert!(error <= 4.0 * ROTATION_PRECISION, "{rotation} -> {decoded}");
+            assert!(decoded.angle_between(rotation) <= 0.01);
+        }
+    }
+
+    #[test]
+    fn test_velocity_round_trip() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..1000 {
+            let velocity = Vec3::new(
+                rng.gen_range(-VELOCITY_BOUND..VELOCITY_BOUND),
+                rng.gen_range(-VELOCITY_BOUND..VELOCITY_BOUND),
+                rng.gen_range(-VELOCITY_BOUND..VELOCITY_BOUND),
+            );
+            let decoded = QuantizedVelocity::new(velocity).get();
+            assert!((decoded - velocity).abs().max_element() <= VELOCITY_PRECISION * 1.01);
+        }
+    }
+
+    #[test]
+    fn test_encoded_sizes() {
+        let position = bincode::serialize(&QuantizedPosition::new(Vec3::ONE)).unwrap();
+        let rotation = bincode::serialize(&QuantizedRotation::new(Quat::IDENTITY)).unwrap();
+        let velocity = bincode::serialize(&QuantizedVelocity::new(Vec3::ONE)).unwrap();
+        assert_eq!(position.len(), 8);
+        assert_eq!(rotation.len(), 4);
+        assert_eq!(velocity.len(), 6);
+    }
+}
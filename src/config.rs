@@ -0,0 +1,604 @@
+//! Launch options, read from the command line and an optional TOML config file. Options given
+//! on the command line override the ones from the file.
+#[cfg(feature = "netcode")]
+use std::time::Duration;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+#[cfg(feature = "netcode")]
+use crate::auth::private_key_path;
+use crate::consts::{DEFAULT_MAP, MAX_DEMO_SPEED, MIN_DEMO_SPEED, MIN_UPDATE_RATE};
+
+const DEFAULT_PORT: u16 = 5000;
+const DEFAULT_MAX_CLIENTS: usize = 64;
+// bevy's default fixed rate
+const DEFAULT_TICK_RATE: f64 = 64.0;
+const DEFAULT_SEND_RATE: f64 = 20.0;
+// about one packet
+const DEFAULT_SNAPSHOT_BUDGET: usize = 1200;
+const DEFAULT_PLAYER_NAME: &str = "player";
+#[cfg(feature = "netcode")]
+const DEFAULT_SERVER_NAME: &str = "water";
+#[cfg(feature = "netcode")]
+const DEFAULT_BOT_COUNT: usize = 8;
+// netcode can't handle more
+const MAX_CLIENTS_LIMIT: usize = 1024;
+
+#[derive(Parser, Debug)]
+#[command(name = "water", version)]
+pub struct Cli {
+    /// TOML file with `[server]` and `[client]` tables using the option names below
+    #[arg(long, short, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Host a game
+    Server(ServerArgs),
+    /// Join a game
+    Client(ClientArgs),
+    /// Write a connect token to a file, for clients that can't reach the token issuer
+    #[cfg(feature = "netcode")]
+    Token(TokenArgs),
+    /// Connect headless bots sending random input, to load test a netcode server
+    #[cfg(feature = "netcode")]
+    Bots(BotsArgs),
+    /// Watch a demo recorded with `water client --record`
+    Demo(DemoArgs),
+}
+
+#[derive(Args, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerArgs {
+    /// Name shown in the LAN games list
+    #[cfg_attr(not(feature = "netcode"), arg(hide = true))]
+    #[arg(long)]
+    name: Option<String>,
+    /// Address to listen on
+    #[cfg_attr(not(feature = "netcode"), arg(hide = true))]
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// Game port, the token issuer listens on the next one
+    #[cfg_attr(not(feature = "netcode"), arg(hide = true))]
+    #[arg(long)]
+    port: Option<u16>,
+    /// Address clients connect to, the one in the connect tokens. Defaults to the bind address,
+    /// or localhost when bound to every interface
+    #[cfg_attr(not(feature = "netcode"), arg(hide = true))]
+    #[arg(long)]
+    public_addr: Option<SocketAddr>,
+    #[arg(long)]
+    max_clients: Option<usize>,
+    /// Map to load from assets/maps, without the extension
+    #[arg(long)]
+    map: Option<String>,
+    /// Simulation ticks per second
+    #[arg(long)]
+    tick_rate: Option<f64>,
+    /// Snapshots sent per second
+    #[arg(long)]
+    send_rate: Option<f64>,
+    /// Bytes of entity state per snapshot and client, the most important entities go first
+    #[arg(long, value_name = "BYTES")]
+    snapshot_budget: Option<usize>,
+    /// Raw 32 byte key used to sign connect tokens, created if missing
+    #[cfg_attr(not(feature = "netcode"), arg(hide = true))]
+    #[arg(long, value_name = "FILE")]
+    private_key: Option<PathBuf>,
+    /// Run without a window or GPU, for dedicated servers
+    #[arg(long)]
+    headless: bool,
+    /// Serve metrics in the Prometheus text format on this localhost port
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Write a metrics sample every second to rotating files in this directory
+    #[arg(long, value_name = "DIR")]
+    metrics_dir: Option<PathBuf>,
+    /// Format of the metrics files
+    #[arg(long)]
+    metrics_format: Option<MetricsFormat>,
+    /// Console commands to run on startup, e.g. `+interp_delay 150 +show_hitbox_debug`
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "+COMMAND"
+    )]
+    #[serde(skip)]
+    console_commands: Vec<String>,
+    // whole commands in the config file: `commands = ["interp_delay 150"]`
+    #[arg(skip)]
+    commands: Vec<String>,
+}
+
+#[derive(Args, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ClientArgs {
+    /// Address of the server to join
+    #[arg(long)]
+    connect: Option<IpAddr>,
+    #[arg(long)]
+    port: Option<u16>,
+    /// Display name sent to the server
+    #[arg(long)]
+    name: Option<String>,
+    /// Connect with a token written by `water token` instead of asking the token issuer
+    #[arg(long, value_name = "FILE")]
+    token: Option<PathBuf>,
+    /// Steam id of the server, for steam builds
+    #[arg(long)]
+    steam_server: Option<u64>,
+    /// Snapshots per second to ask the server for, capped by its send rate
+    #[arg(long)]
+    update_rate: Option<f64>,
+    /// Record the game to a demo file, replaced on every connection
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Console commands to run on startup, e.g. `+interp_delay 150 +show_hitbox_debug`
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "+COMMAND"
+    )]
+    #[serde(skip)]
+    console_commands: Vec<String>,
+    // whole commands in the config file: `commands = ["interp_delay 150"]`
+    #[arg(skip)]
+    commands: Vec<String>,
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Args, Debug)]
+struct TokenArgs {
+    /// Display name bound to the token
+    name: String,
+    /// Where to write the token
+    output: PathBuf,
+    /// Public address of the server the token is for
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    connect: IpAddr,
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    #[arg(long, value_name = "FILE")]
+    private_key: Option<PathBuf>,
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Args, Debug)]
+struct BotsArgs {
+    /// Number of bots to connect
+    #[arg(long, short = 'n', default_value_t = DEFAULT_BOT_COUNT)]
+    count: usize,
+    /// Address of the server to load
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    connect: IpAddr,
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// Bots are named with this followed by their index
+    #[arg(long, default_value = "bot")]
+    name_prefix: String,
+    /// Simulation ticks per second, has to match the server
+    #[arg(long, default_value_t = DEFAULT_TICK_RATE)]
+    tick_rate: f64,
+    /// Seconds to run for, until interrupted if not given
+    #[arg(long)]
+    duration: Option<f64>,
+    /// Seed for the bots' input, for repeatable runs
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct DemoArgs {
+    /// Demo file to play
+    file: PathBuf,
+    /// Playback speed, changeable while watching
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerArgs,
+    client: ClientArgs,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct ServerSettings {
+    #[cfg(feature = "netcode")]
+    pub name: String,
+    #[cfg(feature = "netcode")]
+    pub addr: SocketAddr,
+    #[cfg(feature = "netcode")]
+    pub public_addr: SocketAddr,
+    pub max_clients: usize,
+    pub map: String,
+    pub tick_rate: f64,
+    pub send_rate: f64,
+    pub snapshot_budget: usize,
+    #[cfg(feature = "netcode")]
+    pub private_key: PathBuf,
+    pub headless: bool,
+    pub metrics_port: Option<u16>,
+    pub metrics_dir: Option<PathBuf>,
+    pub metrics_format: MetricsFormat,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    /// `time,metric,label,value` rows, the label is a client or a message variant
+    #[default]
+    Csv,
+    /// one object per line
+    Json,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct ClientSettings {
+    pub server_addr: SocketAddr,
+    pub name: String,
+    pub token: Option<PathBuf>,
+    // only read by steam builds
+    #[cfg_attr(not(feature = "steam"), allow(dead_code))]
+    pub steam_server: Option<u64>,
+    // the server's send rate if not given
+    pub update_rate: Option<f64>,
+    pub record: Option<PathBuf>,
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Debug)]
+pub struct TokenSettings {
+    pub name: String,
+    pub output: PathBuf,
+    pub server_addr: SocketAddr,
+    pub private_key: PathBuf,
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Debug)]
+pub struct BotSettings {
+    pub count: usize,
+    pub server_addr: SocketAddr,
+    pub name_prefix: String,
+    pub tick_rate: f64,
+    pub duration: Option<Duration>,
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct DemoSettings {
+    pub file: PathBuf,
+    pub speed: f64,
+}
+
+pub enum Launch {
+    Server(ServerSettings, Vec<String>),
+    Client(ClientSettings, Vec<String>),
+    #[cfg(feature = "netcode")]
+    Token(TokenSettings),
+    #[cfg(feature = "netcode")]
+    Bots(BotSettings),
+    Demo(DemoSettings),
+}
+
+/// Parses the command line and the config file. Prints usage and exits on invalid input.
+pub fn parse() -> Launch {
+    let cli = Cli::parse();
+    let file = match cli.config.as_deref().map(read_config_file).transpose() {
+        Ok(file) => file.unwrap_or_default(),
+        Err(message) => usage_error(ErrorKind::Io, message),
+    };
+    match resolve(cli.command, file) {
+        Ok(launch) => launch,
+        Err(message) => usage_error(ErrorKind::ValueValidation, message),
+    }
+}
+
+fn usage_error(kind: ErrorKind, message: String) -> ! {
+    Cli::command().error(kind, message).exit()
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("can't read config {}: {err}", path.display()))?;
+    toml::from_str(&contents).map_err(|err| format!("invalid config {}: {err}", path.display()))
+}
+
+fn resolve(command: Command, file: ConfigFile) -> Result<Launch, String> {
+    match command {
+        Command::Server(args) => {
+            // the same config file may serve netcode builds, so only its netcode options are
+            // ignored, on the command line they are a mistake
+            #[cfg(not(feature = "netcode"))]
+            if args.name.is_some()
+                || args.bind.is_some()
+                || args.port.is_some()
+                || args.public_addr.is_some()
+                || args.private_key.is_some()
+            {
+                return Err(
+                    "--name, --bind, --port, --public-addr and --private-key need a netcode build"
+                        .to_string(),
+                );
+            }
+            #[cfg(feature = "netcode")]
+            let addr = SocketAddr::new(
+                args.bind
+                    .or(file.server.bind)
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                args.port.or(file.server.port).unwrap_or(DEFAULT_PORT),
+            );
+            let settings = ServerSettings {
+                #[cfg(feature = "netcode")]
+                name: args
+                    .name
+                    .or(file.server.name)
+                    .unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string()),
+                #[cfg(feature = "netcode")]
+                addr,
+                #[cfg(feature = "netcode")]
+                public_addr: args
+                    .public_addr
+                    .or(file.server.public_addr)
+                    .unwrap_or_else(|| default_public_addr(addr)),
+                max_clients: args
+                    .max_clients
+                    .or(file.server.max_clients)
+                    .unwrap_or(DEFAULT_MAX_CLIENTS),
+                map: args
+                    .map
+                    .or(file.server.map)
+                    .unwrap_or_else(|| DEFAULT_MAP.to_string()),
+                tick_rate: args
+                    .tick_rate
+                    .or(file.server.tick_rate)
+                    .unwrap_or(DEFAULT_TICK_RATE),
+                send_rate: args
+                    .send_rate
+                    .or(file.server.send_rate)
+                    .unwrap_or(DEFAULT_SEND_RATE),
+                snapshot_budget: args
+                    .snapshot_budget
+                    .or(file.server.snapshot_budget)
+                    .unwrap_or(DEFAULT_SNAPSHOT_BUDGET),
+                #[cfg(feature = "netcode")]
+                private_key: args
+                    .private_key
+                    .or(file.server.private_key)
+                    .unwrap_or_else(private_key_path),
+                headless: args.headless || file.server.headless,
+                metrics_port: args.metrics_port.or(file.server.metrics_port),
+                metrics_dir: args.metrics_dir.or(file.server.metrics_dir),
+                metrics_format: args
+                    .metrics_format
+                    .or(file.server.metrics_format)
+                    .unwrap_or_default(),
+            };
+            if settings.max_clients == 0 || settings.max_clients > MAX_CLIENTS_LIMIT {
+                return Err(format!(
+                    "max clients must be between 1 and {MAX_CLIENTS_LIMIT}"
+                ));
+            }
+            validate_tick_rate(settings.tick_rate)?;
+            if !(settings.send_rate > 0.0 && settings.send_rate <= settings.tick_rate) {
+                return Err("send rate must be positive and at most the tick rate".to_string());
+            }
+            if settings.snapshot_budget == 0 {
+                return Err("snapshot budget must be positive".to_string());
+            }
+            let commands = console_commands(file.server.commands, args.console_commands)?;
+            Ok(Launch::Server(settings, commands))
+        }
+        Command::Client(args) => {
+            let settings = ClientSettings {
+                server_addr: SocketAddr::new(
+                    args.connect
+                        .or(file.client.connect)
+                        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    args.port.or(file.client.port).unwrap_or(DEFAULT_PORT),
+                ),
+                name: args
+                    .name
+                    .or(file.client.name)
+                    .unwrap_or_else(|| DEFAULT_PLAYER_NAME.to_string()),
+                token: args.token.or(file.client.token),
+                steam_server: args.steam_server.or(file.client.steam_server),
+                update_rate: args.update_rate.or(file.client.update_rate),
+                record: args.record.or(file.client.record),
+            };
+            if settings.name.trim().is_empty() {
+                return Err("player name can't be empty".to_string());
+            }
+            if settings
+                .update_rate
+                .is_some_and(|rate| !(rate.is_finite() && rate >= MIN_UPDATE_RATE))
+            {
+                return Err(format!("update rate must be at least {MIN_UPDATE_RATE}"));
+            }
+            let commands = console_commands(file.client.commands, args.console_commands)?;
+            Ok(Launch::Client(settings, commands))
+        }
+        #[cfg(feature = "netcode")]
+        Command::Token(args) => Ok(Launch::Token(TokenSettings {
+            name: args.name,
+            output: args.output,
+            server_addr: SocketAddr::new(args.connect, args.port),
+            private_key: args.private_key.unwrap_or_else(private_key_path),
+        })),
+        #[cfg(feature = "netcode")]
+        Command::Bots(args) => {
+            if args.count == 0 || args.count > MAX_CLIENTS_LIMIT {
+                return Err(format!(
+                    "bot count must be between 1 and {MAX_CLIENTS_LIMIT}"
+                ));
+            }
+            validate_tick_rate(args.tick_rate)?;
+            let duration = args
+                .duration
+                .map(|seconds| {
+                    Duration::try_from_secs_f64(seconds)
+                        .map_err(|_| "duration must be a positive number of seconds".to_string())
+                })
+                .transpose()?;
+            Ok(Launch::Bots(BotSettings {
+                count: args.count,
+                server_addr: SocketAddr::new(args.connect, args.port),
+                name_prefix: args.name_prefix,
+                tick_rate: args.tick_rate,
+                duration,
+                seed: args.seed,
+            }))
+        }
+        Command::Demo(args) => {
+            if !(MIN_DEMO_SPEED..=MAX_DEMO_SPEED).contains(&args.speed) {
+                return Err(format!(
+                    "demo speed must be between {MIN_DEMO_SPEED} and {MAX_DEMO_SPEED}"
+                ));
+            }
+            Ok(Launch::Demo(DemoSettings {
+                file: args.file,
+                speed: args.speed,
+            }))
+        }
+    }
+}
+
+/// Nobody can connect to an unspecified address, a server bound to every interface is at
+/// least reachable from this machine.
+#[cfg(feature = "netcode")]
+fn default_public_addr(addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+    } else {
+        addr
+    }
+}
+
+fn validate_tick_rate(tick_rate: f64) -> Result<(), String> {
+    if tick_rate.is_finite() && tick_rate > 0.0 {
+        Ok(())
+    } else {
+        Err("tick rate must be positive".to_string())
+    }
+}
+
+/// Commands from the config file first, then the `+command args` groups of the command line.
+fn console_commands(file: Vec<String>, cli: Vec<String>) -> Result<Vec<String>, String> {
+    let mut commands = file;
+    for arg in cli {
+        if let Some(command) = arg.strip_prefix('+') {
+            commands.push(command.to_string());
+        } else if let Some(current) = commands.last_mut().filter(|_| !arg.is_empty()) {
+            current.push(' ');
+            current.push_str(&arg);
+        } else {
+            return Err(format!(
+                "unexpected argument '{arg}', console commands start with '+'"
+            ));
+        }
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launch(args: &[&str], file: &str) -> Result<Launch, String> {
+        let cli = Cli::try_parse_from(args).map_err(|err| err.to_string())?;
+        resolve(cli.command, toml::from_str(file).unwrap())
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let file = "[server]\nsend_rate = 10\nmap = \"arena\"\nheadless = true\ncommands = [\"show_rocket_debug\"]";
+        let Ok(Launch::Server(settings, commands)) = launch(
+            &[
+                "water",
+                "server",
+                "--send-rate",
+                "30",
+                "+interp_delay",
+                "150",
+            ],
+            file,
+        ) else {
+            panic!("expected a server launch");
+        };
+        assert_eq!(settings.send_rate, 30.0);
+        assert_eq!(settings.map, "arena");
+        assert_eq!(settings.max_clients, DEFAULT_MAX_CLIENTS);
+        assert!(settings.headless);
+        assert_eq!(commands, vec!["show_rocket_debug", "interp_delay 150"]);
+    }
+
+    #[cfg(not(feature = "netcode"))]
+    #[test]
+    fn test_netcode_options_need_netcode() {
+        let file = "[server]\nname = \"lan\"\nbind = \"0.0.0.0\"\nport = 7000";
+        assert!(matches!(
+            launch(&["water", "server"], file),
+            Ok(Launch::Server(..))
+        ));
+        assert!(launch(&["water", "server", "--port", "7000"], "").is_err());
+    }
+
+    #[cfg(feature = "netcode")]
+    #[test]
+    fn test_public_addr_defaults_to_something_reachable() {
+        let Ok(Launch::Server(settings, _)) = launch(&["water", "server", "--port", "7000"], "")
+        else {
+            panic!("expected a server launch");
+        };
+        assert_eq!(settings.public_addr, settings.addr);
+
+        let Ok(Launch::Server(settings, _)) = launch(&["water", "server", "--bind", "0.0.0.0"], "")
+        else {
+            panic!("expected a server launch");
+        };
+        assert_eq!(settings.public_addr, "127.0.0.1:5000".parse().unwrap());
+
+        let Ok(Launch::Server(settings, _)) = launch(
+            &[
+                "water",
+                "server",
+                "--bind",
+                "0.0.0.0",
+                "--public-addr",
+                "203.0.113.7:5000",
+            ],
+            "",
+        ) else {
+            panic!("expected a server launch");
+        };
+        assert_eq!(settings.public_addr, "203.0.113.7:5000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_input_is_an_error() {
+        assert!(launch(&["water"], "").is_err());
+        assert!(launch(&["water", "server", "--max-clients", "nope"], "").is_err());
+        assert!(launch(&["water", "server", "--send-rate", "1000"], "").is_err());
+        assert!(launch(&["water", "server", "--snapshot-budget", "0"], "").is_err());
+        assert!(launch(&["water", "server", "--metrics-format", "xml"], "").is_err());
+        assert!(launch(&["water", "client", "interp_delay"], "").is_err());
+        assert!(launch(&["water", "client", "--update-rate", "0"], "").is_err());
+        assert!(launch(&["water", "bots", "--count", "0"], "").is_err());
+        assert!(launch(&["water", "bots", "--duration", "-1"], "").is_err());
+        assert!(launch(&["water", "demo", "match.demo", "--speed", "0"], "").is_err());
+        assert!(toml::from_str::<ConfigFile>("[server]\nprot = 1").is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Client connection lifecycle. The transport specific part of connecting lives in `client.rs`,
+//! it runs on entering `ConnectionState::Connecting` and reports failures as `ConnectionFailed`.
+//! Lost connections are retried with backoff, after too many failed attempts or when the server
+//! refuses us the client goes back to the main menu.
+use std::{net::SocketAddr, time::Duration};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use bevy_renet::{renet::RenetClient, RenetReceive};
+
+use crate::{
+    config::ClientSettings,
+    consts::{MAX_RECONNECT_ATTEMPTS, RECONNECT_BACKOFF_BASE_SECS, RECONNECT_BACKOFF_MAX_SECS},
+    server::ServerChannel,
+    water::GameState,
+};
+
+pub struct ConnectionPlugin;
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_state::<ConnectionState>()
+            .init_resource::<ConnectionStatus>()
+            .add_event::<ConnectionFailed>()
+            .add_event::<JoinServer>()
+            .add_systems(
+                PreUpdate,
+                (receive_rejection, track_connection)
+                    .chain()
+                    .after(RenetReceive),
+            )
+            .add_systems(
+                Update,
+                (
+                    join_server,
+                    retry_connection.run_if(in_state(ConnectionState::Reconnecting)),
+                )
+                    .chain(),
+            )
+            .add_systems(OnEnter(ConnectionState::Disconnected), return_to_menu)
+            .add_systems(OnEnter(GameState::Game), connect_from_menu)
+            .add_systems(Update, show_connection_status);
+    }
+}
+
+#[derive(States, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ConnectionState {
+    /// waiting for a transport, or for the handshake to finish
+    #[default]
+    Connecting,
+    Connected,
+    /// lost the connection, waiting out the backoff before the next attempt
+    Reconnecting,
+    /// gave up, nothing happens until the player hits play again
+    Disconnected,
+}
+
+/// The transport failed in a way renet can't describe, e.g. the token issuer is unreachable.
+#[derive(Event, Debug)]
+pub struct ConnectionFailed(pub String);
+
+/// Leaves the current server, if any, for another one. Sent by the LAN games menu.
+#[derive(Event, Debug)]
+pub struct JoinServer(pub SocketAddr);
+
+#[derive(Resource, Debug, Default)]
+pub struct ConnectionStatus {
+    /// why the last connection ended
+    pub reason: Option<String>,
+    /// failed attempts since we were last connected
+    pub attempts: u32,
+    // the server sent a reason on `ServerChannel::Rejection`, retrying won't help
+    refused: bool,
+    retry: Timer,
+}
+
+/// Wait before reconnecting after `attempts` failures in a row, doubling every time.
+pub fn reconnect_backoff(attempts: u32) -> Duration {
+    let secs = RECONNECT_BACKOFF_BASE_SECS * 2f32.powi(attempts.min(16) as i32);
+    Duration::from_secs_f32(secs.min(RECONNECT_BACKOFF_MAX_SECS))
+}
+
+fn receive_rejection(mut client: ResMut<RenetClient>, mut status: ResMut<ConnectionStatus>) {
+    while let Some(message) = client.receive_message(ServerChannel::Rejection) {
+        let reason = String::from_utf8_lossy(&message).into_owned();
+        warn!("Rejected by the server: {reason}");
+        status.reason = Some(reason);
+        status.refused = true;
+        // nothing else the server sends can be trusted to decode
+        client.disconnect();
+    }
+}
+
+pub fn track_connection(
+    client: Res<RenetClient>,
+    state: Res<State<ConnectionState>>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+    mut status: ResMut<ConnectionStatus>,
+    mut failures: EventReader<ConnectionFailed>,
+) {
+    // transports keep failing every frame once they're dead, only the first one matters
+    let failure = failures.read().next().map(|failure| failure.0.clone());
+    if !matches!(
+        state.get(),
+        ConnectionState::Connecting | ConnectionState::Connected
+    ) {
+        return;
+    }
+
+    if failure.is_none() && !client.is_disconnected() {
+        if *state.get() == ConnectionState::Connecting && client.is_connected() {
+            info!("Connected to the server");
+            status.attempts = 0;
+            status.reason = None;
+            next_state.set(ConnectionState::Connected);
+        }
+        return;
+    }
+
+    if !status.refused {
+        status.reason = Some(
+            failure
+                .or_else(|| client.disconnect_reason().map(|reason| reason.to_string()))
+                .unwrap_or_else(|| "Disconnected from the server".to_string()),
+        );
+    }
+    if status.refused || status.attempts >= MAX_RECONNECT_ATTEMPTS {
+        warn!(
+            "Giving up on the server: {}",
+            status.reason.as_deref().unwrap_or_default()
+        );
+        next_state.set(ConnectionState::Disconnected);
+        return;
+    }
+    let backoff = reconnect_backoff(status.attempts);
+    status.attempts += 1;
+    warn!(
+        "Connection lost: {}, retrying in {:.1}s",
+        status.reason.as_deref().unwrap_or_default(),
+        backoff.as_secs_f32()
+    );
+    status.retry = Timer::new(backoff, TimerMode::Once);
+    next_state.set(ConnectionState::Reconnecting);
+}
+
+fn retry_connection(
+    time: Res<Time>,
+    mut status: ResMut<ConnectionStatus>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+) {
+    if status.retry.tick(time.delta()).finished() {
+        next_state.set(ConnectionState::Connecting);
+    }
+}
+
+fn join_server(
+    mut joins: EventReader<JoinServer>,
+    mut settings: ResMut<ClientSettings>,
+    mut status: ResMut<ConnectionStatus>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+) {
+    let Some(JoinServer(server_addr)) = joins.read().last() else {
+        return;
+    };
+    info!("Joining {server_addr}");
+    settings.server_addr = *server_addr;
+    // a token file is only good for the server it was written for, the token issuer next to
+    // the joined server hands out one for it
+    settings.token = None;
+    *status = ConnectionStatus::default();
+    // through `Reconnecting`, entering `Connecting` again is what starts a connection
+    next_state.set(ConnectionState::Reconnecting);
+}
+
+fn return_to_menu(mut game_state: ResMut<NextState<GameState>>) {
+    game_state.set(GameState::MainMenu);
+}
+
+fn connect_from_menu(
+    state: Res<State<ConnectionState>>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+    mut status: ResMut<ConnectionStatus>,
+) {
+    if *state.get() == ConnectionState::Disconnected {
+        status.attempts = 0;
+        status.refused = false;
+        next_state.set(ConnectionState::Connecting);
+    }
+}
+
+fn show_connection_status(
+    mut egui_contexts: EguiContexts,
+    state: Res<State<ConnectionState>>,
+    status: Res<ConnectionStatus>,
+) {
+    let reason = status
+        .reason
+        .as_deref()
+        .unwrap_or("Disconnected from the server");
+    let (title, retry) = match state.get() {
+        ConnectionState::Connected => return,
+        ConnectionState::Connecting | ConnectionState::Reconnecting if status.attempts == 0 => {
+            return
+        }
+        ConnectionState::Connecting => (
+            "Connection lost",
+            format!(
+                "Reconnecting (attempt {}/{MAX_RECONNECT_ATTEMPTS})",
+                status.attempts
+            ),
+        ),
+        ConnectionState::Reconnecting => (
+            "Connection lost",
+            format!(
+                "Retrying in {:.0}s (attempt {}/{MAX_RECONNECT_ATTEMPTS})",
+                status.retry.remaining_secs().ceil(),
+                status.attempts
+            ),
+        ),
+        ConnectionState::Disconnected => ("Disconnected", String::new()),
+    };
+    egui::Window::new(title).show(egui_contexts.ctx_mut(), |ui| {
+        ui.label(reason);
+        if !retry.is_empty() {
+            ui.label(retry);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        assert_eq!(
+            reconnect_backoff(0),
+            Duration::from_secs_f32(RECONNECT_BACKOFF_BASE_SECS)
+        );
+        assert_eq!(reconnect_backoff(1), reconnect_backoff(0) * 2);
+        assert_eq!(
+            reconnect_backoff(u32::MAX),
+            Duration::from_secs_f32(RECONNECT_BACKOFF_MAX_SECS)
+        );
+    }
+}
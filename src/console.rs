@@ -3,13 +3,28 @@ pub struct ConsolePlugin;
 use std::{
     collections::{HashMap, VecDeque},
     mem,
+    path::PathBuf,
 };
 
 use bevy::{ecs::system::SystemId, prelude::*};
-use bevy_egui::{egui, EguiContexts};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use bevy_renet::renet::RenetClient;
+
+use crate::client::{request_interpolation_delay, request_update_rate};
+use crate::config::ClientSettings;
+use crate::consts::MIN_UPDATE_RATE;
+use crate::demo::{start_recording, stop_recording};
+use crate::interpolation::InterpolationSettings;
+use crate::link_conditioner::LinkConditions;
 impl Plugin for ConsolePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (ui_example_system, handle_console_commands))
+        // headless servers have no egui, only the startup commands run there
+        if app.is_plugin_added::<EguiPlugin>() {
+            app.add_systems(Update, ui_example_system);
+        }
+        app.add_systems(Update, handle_console_commands)
+            .add_systems(Startup, run_startup_commands)
+            .init_resource::<StartupCommands>()
             .init_resource::<ConsoleInput>()
             .init_resource::<ConsoleHistory>()
             .init_resource::<ConsoleCommands>()
@@ -27,18 +42,24 @@ struct ConsoleHistory(VecDeque<String>);
 #[derive(Event, Clone)]
 struct UserInput(String);
 
+/// Commands from the launch options, run once as if they were typed in the console.
+#[derive(Resource, Default)]
+pub struct StartupCommands(pub Vec<String>);
+
 #[derive(Resource)]
 struct ConsoleCommands(HashMap<String, SystemId<In<Vec<String>>>>);
 
 #[derive(Resource)]
 pub struct GameSettings {
     pub show_debug_rocket: bool,
+    pub show_debug_hitboxes: bool,
 }
 
 impl Default for GameSettings {
     fn default() -> Self {
         Self {
             show_debug_rocket: false,
+            show_debug_hitboxes: false,
         }
     }
 }
@@ -50,6 +71,16 @@ impl FromWorld for ConsoleCommands {
 
         console_commands.0.insert("clear".into(), world.register_system(console_clear));
         console_commands.0.insert("show_rocket_debug".into(), world.register_system(console_show_rocket_debug));
+        console_commands.0.insert("show_hitbox_debug".into(), world.register_system(console_show_hitbox_debug));
+        console_commands.0.insert("interp_delay".into(), world.register_system(console_interp_delay));
+        console_commands.0.insert("net_latency".into(), world.register_system(console_net_latency));
+        console_commands.0.insert("net_jitter".into(), world.register_system(console_net_jitter));
+        console_commands.0.insert("net_loss".into(), world.register_system(console_net_loss));
+        console_commands.0.insert("net_duplicate".into(), world.register_system(console_net_duplicate));
+        console_commands.0.insert("net_reorder".into(), world.register_system(console_net_reorder));
+        console_commands.0.insert("update_rate".into(), world.register_system(console_update_rate));
+        console_commands.0.insert("record".into(), world.register_system(console_record));
+        console_commands.0.insert("stop_record".into(), world.register_system(console_stop_record));
 
 
         //register_command!("clear",console_clear)
@@ -65,6 +96,156 @@ fn console_show_rocket_debug(In(_input): In<Vec<String>>, mut settings: ResMut<G
     settings.show_debug_rocket = !settings.show_debug_rocket;
 }
 
+fn console_show_hitbox_debug(In(_input): In<Vec<String>>, mut settings: ResMut<GameSettings>) {
+    settings.show_debug_hitboxes = !settings.show_debug_hitboxes;
+}
+
+// interp_delay <milliseconds>
+fn console_interp_delay(
+    In(input): In<Vec<String>>,
+    settings: Option<ResMut<InterpolationSettings>>,
+    client: Option<ResMut<RenetClient>>,
+) {
+    let Some(mut settings) = settings else {
+        return;
+    };
+    let Some(Ok(delay_ms)) = input.get(1).map(|arg| arg.parse::<u64>()) else {
+        info!("interp_delay is {}ms", settings.delay.as_millis());
+        return;
+    };
+    settings.delay = std::time::Duration::from_millis(delay_ms);
+    if let Some(mut client) = client.filter(|client| client.is_connected()) {
+        request_interpolation_delay(&mut client, settings.delay);
+    }
+}
+
+// update_rate <snapshots per second>
+fn console_update_rate(
+    In(input): In<Vec<String>>,
+    settings: Option<ResMut<ClientSettings>>,
+    client: Option<ResMut<RenetClient>>,
+) {
+    let Some(mut settings) = settings else {
+        return;
+    };
+    let Some(Ok(rate)) = input.get(1).map(|arg| arg.parse::<f64>()) else {
+        match settings.update_rate {
+            Some(rate) => info!("update_rate is {rate}"),
+            None => info!("update_rate is the server's send rate"),
+        }
+        return;
+    };
+    if !(rate.is_finite() && rate >= MIN_UPDATE_RATE) {
+        info!("update_rate must be at least {MIN_UPDATE_RATE}");
+        return;
+    }
+    settings.update_rate = Some(rate);
+    if let Some(mut client) = client.filter(|client| client.is_connected()) {
+        request_update_rate(&mut client, rate);
+    }
+}
+
+// record [file], the file given with --record without one
+fn console_record(
+    In(input): In<Vec<String>>,
+    mut commands: Commands,
+    settings: Option<ResMut<ClientSettings>>,
+    client: Option<Res<RenetClient>>,
+) {
+    let Some(mut settings) = settings else {
+        return;
+    };
+    if let Some(file) = input.get(1) {
+        settings.record = Some(PathBuf::from(file));
+    }
+    let Some(path) = settings.record.as_ref() else {
+        info!("record needs a file");
+        return;
+    };
+    if client.is_some_and(|client| client.is_connected()) {
+        commands.run_system_cached(start_recording);
+    } else {
+        info!("Recording to {} once connected", path.display());
+    }
+}
+
+// stop_record
+fn console_stop_record(
+    In(_input): In<Vec<String>>,
+    mut commands: Commands,
+    settings: Option<ResMut<ClientSettings>>,
+) {
+    if let Some(mut settings) = settings {
+        settings.record = None;
+    }
+    commands.run_system_cached(stop_recording);
+}
+
+// net_latency <milliseconds>
+fn console_net_latency(In(input): In<Vec<String>>, conditions: Option<ResMut<LinkConditions>>) {
+    let Some(mut conditions) = conditions else {
+        return;
+    };
+    let Some(Ok(latency_ms)) = input.get(1).map(|arg| arg.parse::<u64>()) else {
+        info!("net_latency is {}ms", conditions.latency.as_millis());
+        return;
+    };
+    conditions.latency = std::time::Duration::from_millis(latency_ms);
+}
+
+// net_jitter <milliseconds>
+fn console_net_jitter(In(input): In<Vec<String>>, conditions: Option<ResMut<LinkConditions>>) {
+    let Some(mut conditions) = conditions else {
+        return;
+    };
+    let Some(Ok(jitter_ms)) = input.get(1).map(|arg| arg.parse::<u64>()) else {
+        info!("net_jitter is {}ms", conditions.jitter.as_millis());
+        return;
+    };
+    conditions.jitter = std::time::Duration::from_millis(jitter_ms);
+}
+
+// net_loss <percent>
+fn console_net_loss(In(input): In<Vec<String>>, conditions: Option<ResMut<LinkConditions>>) {
+    let Some(mut conditions) = conditions else {
+        return;
+    };
+    match parse_percent(&input) {
+        Some(loss) => conditions.loss = loss,
+        None => info!("net_loss is {}%", conditions.loss * 100.0),
+    }
+}
+
+// net_duplicate <percent>
+fn console_net_duplicate(In(input): In<Vec<String>>, conditions: Option<ResMut<LinkConditions>>) {
+    let Some(mut conditions) = conditions else {
+        return;
+    };
+    match parse_percent(&input) {
+        Some(duplication) => conditions.duplication = duplication,
+        None => info!("net_duplicate is {}%", conditions.duplication * 100.0),
+    }
+}
+
+// net_reorder <percent>
+fn console_net_reorder(In(input): In<Vec<String>>, conditions: Option<ResMut<LinkConditions>>) {
+    let Some(mut conditions) = conditions else {
+        return;
+    };
+    match parse_percent(&input) {
+        Some(reordering) => conditions.reordering = reordering,
+        None => info!("net_reorder is {}%", conditions.reordering * 100.0),
+    }
+}
+
+/// First argument as a chance between 0 and 1, from a percentage.
+fn parse_percent(input: &[String]) -> Option<f64> {
+    let percent = input.get(1)?.parse::<f64>().ok()?;
+    percent
+        .is_finite()
+        .then(|| percent.clamp(0.0, 100.0) / 100.0)
+}
+
 fn ui_example_system(
     mut contexts: EguiContexts,
     mut console_input: ResMut<ConsoleInput>,
@@ -93,6 +274,15 @@ fn ui_example_system(
         });
 }
 
+fn run_startup_commands(
+    startup_commands: Res<StartupCommands>,
+    mut input_event: EventWriter<UserInput>,
+) {
+    for command in startup_commands.0.iter() {
+        input_event.send(UserInput(command.clone()));
+    }
+}
+
 fn handle_console_commands(
     mut user_input: EventReader<UserInput>,
     console_commands: Res<ConsoleCommands>,
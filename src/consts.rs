@@ -1,3 +1,5 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
 pub const RES_HEIGHT: u32 = 1080;
 pub const RES_WIDTH: u32 = 1920;
 
@@ -11,6 +13,16 @@ pub const ROCKET_SPEED: f32 = 20.0;
 pub const ROCKET_EXPLOSION_RADIUS: f32 = 4.0;
 pub const ROCKET_EXPLOSION_FORCE: f32 = 20.0;
 pub const MAX_ROCKET_DAMAGE: usize = 50;
+// how long the explosion stays visible on clients, in seconds
+pub const ROCKET_EXPLOSION_EFFECT_DURATION: f32 = 0.4;
+
+// in seconds
+pub const RAILGUN_COOLDOWN: f32 = 1.5;
+pub const RAILGUN_DAMAGE: usize = 80;
+// in meters
+pub const RAILGUN_RANGE: f32 = 200.0;
+// how long the beam stays visible on clients, in seconds
+pub const RAILGUN_BEAM_DURATION: f32 = 0.3;
 
 // used for air strafing calculations. Not the actual max air speed
 pub const PSEUDO_MAX_AIR_SPEED: f32 = 7.0;
@@ -21,4 +33,70 @@ pub const PLAYER_DEATH_TIMER: f32 = 1.0;
 
 pub const SERVER_CAMERA_SPEED: f32 = 32.0;
 
+// in radians, how far players can look up or down
+pub const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01;
+// in radians per sec, 8 turns a second. Faster flicks are spread over a few ticks
+pub const MAX_TURN_SPEED: f32 = 16.0 * PI;
+
+// malformed messages tolerated from a client before it gets disconnected
+pub const MAX_MALFORMED_MESSAGES: u32 = 10;
+// inputs buffered per player, past this many ticks the oldest are simulated together
+pub const MAX_BUFFERED_INPUTS: usize = 8;
+// mouse and look direction messages a client can send per second, more is flooding. Clients
+// send one look direction per tick and one rotation per rendered frame
+pub const MAX_LOOK_MESSAGES_PER_SECOND: f64 = 1024.0;
+// in seconds, lets the rejection reason reach a client before it gets disconnected
+#[cfg(feature = "netcode")]
+pub const REJECTION_DISCONNECT_DELAY: f32 = 0.5;
+
+// servers broadcast their LAN beacons to this port
+pub const DISCOVERY_PORT: u16 = 4999;
+
+// failed reconnects in a row before the client goes back to the main menu
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+// in seconds, doubled after every failed attempt
+pub const RECONNECT_BACKOFF_BASE_SECS: f32 = 1.0;
+pub const RECONNECT_BACKOFF_MAX_SECS: f32 = 16.0;
+
 pub const CHARACTER_MODEL_PATH: &str = "models/character.glb";
+// file name in assets/maps, without the extension
+pub const DEFAULT_MAP: &str = "map_test";
+
+// number of fixed ticks of predicted state kept around for reconciliation
+pub const PREDICTION_HISTORY_SIZE: usize = 256;
+// in meters (and meters per sec for velocity)
+pub const RECONCILIATION_TOLERANCE: f32 = 0.05;
+
+// how far in the past remote players are rendered. 2 snapshots at the current send rate
+pub const DEFAULT_INTERPOLATION_DELAY_MS: u64 = 100;
+pub const SNAPSHOT_BUFFER_SIZE: usize = 32;
+
+// snapshots kept as potential delta baselines, a bit more than a second at the current send rate
+pub const SNAPSHOT_HISTORY_SIZE: usize = 32;
+
+// shots from clients with a worse ping than this are only partially compensated
+pub const LAG_COMPENSATION_MAX_REWIND_MS: u64 = 300;
+
+// snapshots per second, the lowest update rate a client can ask for
+pub const MIN_UPDATE_RATE: f64 = 1.0;
+
+// in meters, entities further away from a player are not sent to them
+pub const RELEVANCY_DISTANCE: f32 = 100.0;
+// in seconds, an entity that stops being relevant is still sent this long so it doesn't flicker
+// in and out around corners
+pub const RELEVANCY_GRACE_SECS: f32 = 1.0;
+
+// in chars, longer chat messages are dropped by the server
+pub const MAX_CHAT_MESSAGE_LEN: usize = 120;
+// chat messages a client can send in a row, the allowance refills at `CHAT_MESSAGES_PER_SEC`
+pub const CHAT_MESSAGE_BURST: f32 = 3.0;
+pub const CHAT_MESSAGES_PER_SEC: f32 = 0.5;
+// in seconds, how long a chat message stays on screen once the chat box is closed
+pub const CHAT_FADE_SECS: f32 = 8.0;
+pub const CHAT_HISTORY_SIZE: usize = 8;
+
+// demo playback speed, as a multiple of real time
+pub const MIN_DEMO_SPEED: f64 = 0.125;
+pub const MAX_DEMO_SPEED: f64 = 8.0;
+// in seconds, how far the arrow keys seek in a demo
+pub const DEMO_SEEK_STEP_SECS: f64 = 5.0;
@@ -0,0 +1,545 @@
+//! Demo recording and playback. A recording client writes every server message and snapshot it
+//! decodes to a file, with the time it arrived. `water demo` reads them back into the same
+//! systems, see `client::ServerStatePlugin`, without a server, watched with the server's free
+//! camera.
+//!
+//! The file starts with `DEMO_MAGIC` and the `DEMO_VERSION` it was written with, as a little
+//! endian u32. Then come the `DemoHeader` and the frames, each length prefixed and encoded like
+//! on the wire. Demos from other versions are refused rather than misread.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{PendingMessages, ServerStatePlugin, ServerStateSet, ServerTickRate},
+    clock::{tick_duration, ServerClock},
+    config::ClientSettings,
+    consts::{DEMO_SEEK_STEP_SECS, MAX_DEMO_SPEED, MIN_DEMO_SPEED},
+    protocol::{decode, protocol_version, MAX_MESSAGE_BYTES},
+    replication::{AppliedSnapshot, NetworkMapping, PendingSnapshots},
+    server::{
+        server_camera::{server_camera_controller, server_camera_look, spawn_camera},
+        NetworkedEntities, ServerMessages, StampedMessage,
+    },
+    water::{GameState, MapName},
+    AppState,
+};
+
+const DEMO_MAGIC: [u8; 8] = *b"WTRDEMO\0";
+/// Bumped whenever the layout of the file changes.
+pub const DEMO_VERSION: u32 = 1;
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_SNAPSHOT: u8 = 1;
+
+/// What a demo needs before its first frame.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DemoHeader {
+    /// frames are only readable by builds speaking the same protocol
+    pub protocol_version: u64,
+    pub map: String,
+    pub tick_rate: f64,
+}
+
+#[derive(Debug)]
+pub enum DemoEvent {
+    Message(StampedMessage<ServerMessages>),
+    Snapshot(NetworkedEntities),
+}
+
+#[derive(Debug)]
+pub struct DemoFrame {
+    /// seconds since the recording started
+    pub time: f64,
+    pub event: DemoEvent,
+}
+
+impl DemoFrame {
+    fn tick(&self) -> u32 {
+        match &self.event {
+            DemoEvent::Message(stamped) => stamped.tick,
+            DemoEvent::Snapshot(snapshot) => snapshot.tick,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Demo {
+    pub header: DemoHeader,
+    pub frames: Vec<DemoFrame>,
+}
+
+impl Demo {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|err| format!("can't open {}: {err}", path.display()))?;
+        Self::read(BufReader::new(file)).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    fn read(mut reader: impl Read) -> Result<Self, String> {
+        let mut magic = [0; DEMO_MAGIC.len()];
+        let mut version = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != DEMO_MAGIC {
+            return Err("not a demo".to_string());
+        }
+        reader
+            .read_exact(&mut version)
+            .map_err(|err| format!("truncated demo: {err}"))?;
+        let version = u32::from_le_bytes(version);
+        if version != DEMO_VERSION {
+            return Err(format!(
+                "demo format version {version}, this build only plays version {DEMO_VERSION}"
+            ));
+        }
+        let header: DemoHeader = read_chunk(&mut reader)
+            .and_then(|bytes| decode(&bytes).map_err(io::Error::other))
+            .map_err(|err| format!("invalid demo header: {err}"))?;
+        if header.protocol_version != protocol_version() {
+            return Err(format!(
+                "recorded with protocol version {:016x}, this build speaks {:016x}",
+                header.protocol_version,
+                protocol_version()
+            ));
+        }
+
+        let mut frames = Vec::new();
+        loop {
+            let mut time = [0; 8];
+            let mut kind = [0; 1];
+            match reader.read_exact(&mut time) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.to_string()),
+            }
+            let payload = reader
+                .read_exact(&mut kind)
+                .and_then(|()| read_chunk(&mut reader));
+            let Ok(payload) = payload else {
+                // the client went away mid write, everything before is fine
+                warn!("The demo is cut off after {} frames", frames.len());
+                break;
+            };
+            let event = match kind[0] {
+                FRAME_MESSAGE => decode(&payload).map(DemoEvent::Message),
+                FRAME_SNAPSHOT => decode(&payload).map(DemoEvent::Snapshot),
+                kind => return Err(format!("unknown frame kind {kind}")),
+            }
+            .map_err(|err| format!("malformed frame {}: {err}", frames.len()))?;
+            frames.push(DemoFrame {
+                time: f64::from_le_bytes(time),
+                event,
+            });
+        }
+        Ok(Self { header, frames })
+    }
+
+    /// Seconds from the first frame to the last.
+    pub fn duration(&self) -> f64 {
+        self.frames.last().map_or(0.0, |frame| frame.time)
+    }
+}
+
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_BYTES as usize {
+        return Err(io::Error::other(format!("{len} byte chunk")));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_chunk(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn write_header(writer: &mut impl Write, header: &DemoHeader) -> io::Result<()> {
+    writer.write_all(&DEMO_MAGIC)?;
+    writer.write_all(&DEMO_VERSION.to_le_bytes())?;
+    write_chunk(writer, &bincode::serialize(header).unwrap())
+}
+
+fn write_frame(writer: &mut impl Write, time: f64, kind: u8, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&time.to_le_bytes())?;
+    writer.write_all(&[kind])?;
+    write_chunk(writer, payload)
+}
+
+/// Writes what `client::receive_message_system` decodes to a demo file.
+#[derive(Resource)]
+pub struct DemoRecorder {
+    path: PathBuf,
+    // gone after a failed write
+    writer: Option<BufWriter<File>>,
+    start: Instant,
+}
+
+impl DemoRecorder {
+    pub fn create(path: &Path, header: &DemoHeader) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer, header)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: Some(writer),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record_message(&mut self, stamped: &StampedMessage<ServerMessages>) {
+        self.record(FRAME_MESSAGE, &bincode::serialize(stamped).unwrap());
+    }
+
+    pub fn record_snapshot(&mut self, snapshot: &NetworkedEntities) {
+        self.record(FRAME_SNAPSHOT, &bincode::serialize(snapshot).unwrap());
+    }
+
+    fn record(&mut self, kind: u8, payload: &[u8]) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let time = self.start.elapsed().as_secs_f64();
+        if let Err(err) = write_frame(writer, time, kind, payload) {
+            warn!("Stopped recording {}: {err}", self.path.display());
+            self.writer = None;
+        }
+    }
+
+    fn finish(&mut self) {
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+        match writer.flush() {
+            Ok(()) => info!("Recorded {}", self.path.display()),
+            Err(err) => warn!("Failed to finish {}: {err}", self.path.display()),
+        }
+    }
+}
+
+/// Starts recording to `ClientSettings::record` if it's set, once the server told us its tick
+/// rate.
+pub fn start_recording(
+    mut commands: Commands,
+    settings: Res<ClientSettings>,
+    tick_rate: Res<ServerTickRate>,
+    map: Res<MapName>,
+    recorder: Option<ResMut<DemoRecorder>>,
+) {
+    let Some(path) = settings.record.as_ref() else {
+        return;
+    };
+    if let Some(mut recorder) = recorder {
+        recorder.finish();
+    }
+    let header = DemoHeader {
+        protocol_version: protocol_version(),
+        map: map.0.clone(),
+        tick_rate: tick_rate.0,
+    };
+    match DemoRecorder::create(path, &header) {
+        Ok(recorder) => {
+            info!("Recording to {}", path.display());
+            commands.insert_resource(recorder);
+        }
+        Err(err) => warn!("Can't record to {}: {err}", path.display()),
+    }
+}
+
+pub fn stop_recording(mut commands: Commands, recorder: Option<ResMut<DemoRecorder>>) {
+    if let Some(mut recorder) = recorder {
+        recorder.finish();
+        commands.remove_resource::<DemoRecorder>();
+    }
+}
+
+/// Plays a demo instead of connecting to a server.
+pub struct DemoPlugin;
+
+impl Plugin for DemoPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ServerStatePlugin)
+            .init_resource::<ServerClock>()
+            .add_systems(PreUpdate, advance_demo)
+            .add_systems(FixedUpdate, play_demo.in_set(ServerStateSet::Receive))
+            .add_systems(Update, (demo_controls, show_demo_controls))
+            .add_systems(OnEnter(GameState::Game), spawn_camera)
+            .add_systems(
+                Update,
+                (server_camera_controller, server_camera_look).run_if(in_state(AppState::Main)),
+            );
+    }
+}
+
+#[derive(Resource, Debug)]
+pub struct DemoPlayback {
+    demo: Demo,
+    // index of the next frame to play
+    next: usize,
+    /// seconds into the demo
+    pub position: f64,
+    pub speed: f64,
+    pub paused: bool,
+    seek: Option<f64>,
+    // tick and time of the last frame played, the clock runs from there
+    last_played: Option<(u32, f64)>,
+}
+
+impl DemoPlayback {
+    pub fn new(demo: Demo, speed: f64) -> Self {
+        Self {
+            demo,
+            next: 0,
+            position: 0.0,
+            speed,
+            paused: false,
+            seek: None,
+            last_played: None,
+        }
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.demo.duration()
+    }
+
+    /// Jumps to `position` seconds on the next fixed tick.
+    pub fn seek(&mut self, position: f64) {
+        self.seek = Some(position.clamp(0.0, self.duration()));
+    }
+
+    /// Indices of the frames to play now, and whether the world has to be cleared first. A seek
+    /// only plays the last snapshot before the new position, the messages in between are
+    /// effects that are long over. Seeking backwards starts over from an empty world.
+    fn take_due(&mut self) -> (bool, Vec<usize>) {
+        let frames = &self.demo.frames;
+        let mut rewind = false;
+        let mut due = Vec::new();
+        if let Some(target) = self.seek.take() {
+            if target < self.position {
+                rewind = true;
+                self.next = 0;
+            }
+            let skipped = frames[self.next..]
+                .iter()
+                .take_while(|frame| frame.time <= target)
+                .count();
+            let snapshot = (self.next..self.next + skipped)
+                .rev()
+                .find(|index| matches!(frames[*index].event, DemoEvent::Snapshot(_)));
+            due.extend(snapshot);
+            self.next += skipped;
+            self.position = target;
+        }
+        while self.next < frames.len() && frames[self.next].time <= self.position {
+            due.push(self.next);
+            self.next += 1;
+        }
+        if let Some(last) = due.last() {
+            self.last_played = Some((frames[*last].tick(), frames[*last].time));
+        } else if rewind {
+            self.last_played = None;
+        }
+        (rewind, due)
+    }
+}
+
+fn advance_demo(
+    time: Res<Time>,
+    time_fixed: Res<Time<Fixed>>,
+    mut playback: ResMut<DemoPlayback>,
+    mut clock: ResMut<ServerClock>,
+) {
+    if !playback.paused {
+        playback.position += time.delta_secs_f64() * playback.speed;
+        if playback.position >= playback.duration() {
+            playback.position = playback.duration();
+            playback.paused = true;
+        }
+    }
+    // the server tick the demo is at, for interpolation
+    if let Some((tick, time)) = playback.last_played {
+        let elapsed = (playback.position - time).max(0.0);
+        clock.set(tick as f64 + elapsed / tick_duration(&time_fixed));
+    }
+}
+
+fn play_demo(
+    mut commands: Commands,
+    mut playback: ResMut<DemoPlayback>,
+    mut network_mapping: ResMut<NetworkMapping>,
+    mut applied_snapshot: ResMut<AppliedSnapshot>,
+    mut pending_snapshots: ResMut<PendingSnapshots>,
+    mut pending_messages: ResMut<PendingMessages>,
+) {
+    let (rewind, due) = playback.take_due();
+    if rewind {
+        for (_, entity) in network_mapping.0.drain() {
+            if let Some(entity) = commands.get_entity(entity) {
+                entity.despawn_recursive();
+            }
+        }
+        *applied_snapshot = AppliedSnapshot::default();
+        *pending_snapshots = PendingSnapshots::default();
+        *pending_messages = PendingMessages::default();
+    }
+    for index in due {
+        match &playback.demo.frames[index].event {
+            DemoEvent::Message(stamped) => pending_messages.0.push(stamped.clone()),
+            DemoEvent::Snapshot(snapshot) => pending_snapshots.0.push(snapshot.clone()),
+        }
+    }
+}
+
+// space pauses, the arrows seek and change the speed
+fn demo_controls(keys: Res<ButtonInput<KeyCode>>, mut playback: ResMut<DemoPlayback>) {
+    if keys.just_pressed(KeyCode::Space) {
+        toggle_pause(&mut playback);
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        let position = playback.position - DEMO_SEEK_STEP_SECS;
+        playback.seek(position);
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        let position = playback.position + DEMO_SEEK_STEP_SECS;
+        playback.seek(position);
+    }
+    if keys.just_pressed(KeyCode::ArrowUp) {
+        playback.speed = (playback.speed * 2.0).min(MAX_DEMO_SPEED);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        playback.speed = (playback.speed / 2.0).max(MIN_DEMO_SPEED);
+    }
+}
+
+fn toggle_pause(playback: &mut DemoPlayback) {
+    playback.paused = !playback.paused;
+    // from the start again once it's over
+    if !playback.paused && playback.position >= playback.duration() {
+        playback.seek(0.0);
+    }
+}
+
+fn show_demo_controls(mut contexts: EguiContexts, mut playback: ResMut<DemoPlayback>) {
+    egui::Window::new("Demo")
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -10.0))
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let label = if playback.paused { "Play" } else { "Pause" };
+                if ui.button(label).clicked() {
+                    toggle_pause(&mut playback);
+                }
+                let mut position = playback.position;
+                let duration = playback.duration();
+                let slider = egui::Slider::new(&mut position, 0.0..=duration)
+                    .show_value(false)
+                    .trailing_fill(true);
+                if ui.add(slider).changed() {
+                    playback.seek(position);
+                }
+                ui.label(format!("{:.1} / {duration:.1} s", playback.position));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Speed");
+                ui.add(
+                    egui::Slider::new(&mut playback.speed, MIN_DEMO_SPEED..=MAX_DEMO_SPEED)
+                        .logarithmic(true)
+                        .suffix("x"),
+                );
+            });
+            ui.label(
+                "Space: pause, arrows: seek and speed, WASD and mouse: camera, Escape: cursor",
+            );
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::QuantizedPosition;
+
+    use super::*;
+
+    fn header() -> DemoHeader {
+        DemoHeader {
+            protocol_version: protocol_version(),
+            map: "arena".to_string(),
+            tick_rate: 64.0,
+        }
+    }
+
+    fn record(frames: &[(f64, u32, bool)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header()).unwrap();
+        for (time, tick, snapshot) in frames {
+            let payload = if *snapshot {
+                bincode::serialize(&NetworkedEntities {
+                    tick: *tick,
+                    ..default()
+                })
+            } else {
+                bincode::serialize(&StampedMessage {
+                    tick: *tick,
+                    message: ServerMessages::RocketExploded {
+                        id: Entity::PLACEHOLDER,
+                        pos: QuantizedPosition::new(Vec3::ZERO),
+                    },
+                })
+            };
+            let kind = if *snapshot {
+                FRAME_SNAPSHOT
+            } else {
+                FRAME_MESSAGE
+            };
+            write_frame(&mut bytes, *time, kind, &payload.unwrap()).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_demos_round_trip_and_check_the_version() {
+        let mut bytes = record(&[(0.0, 10, true), (0.5, 42, false)]);
+        let demo = Demo::read(bytes.as_slice()).unwrap();
+        assert_eq!(demo.header, header());
+        assert_eq!(demo.frames.len(), 2);
+        assert_eq!(demo.frames[1].tick(), 42);
+        assert_eq!(demo.duration(), 0.5);
+
+        // a frame cut off halfway is dropped
+        let cut = Demo::read(&bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(cut.frames.len(), 1);
+
+        bytes[DEMO_MAGIC.len()] += 1;
+        let err = Demo::read(bytes.as_slice()).unwrap_err();
+        assert!(err.contains("version 2"), "{err}");
+        assert!(Demo::read(&b"not a demo"[..]).is_err());
+    }
+
+    #[test]
+    fn test_seeking_only_plays_the_last_snapshot() {
+        let bytes = record(&[
+            (0.0, 0, true),
+            (1.0, 64, false),
+            (1.0, 64, true),
+            (2.0, 128, true),
+            (3.0, 192, true),
+        ]);
+        let mut playback = DemoPlayback::new(Demo::read(bytes.as_slice()).unwrap(), 1.0);
+        assert_eq!(playback.take_due(), (false, vec![0]));
+
+        playback.seek(2.5);
+        assert_eq!(playback.take_due(), (false, vec![3]));
+        playback.position = 3.0;
+        assert_eq!(playback.take_due(), (false, vec![4]));
+
+        playback.seek(1.5);
+        assert_eq!(playback.take_due(), (true, vec![2]));
+        assert_eq!(playback.last_played, Some((64, 1.0)));
+    }
+}
@@ -0,0 +1,308 @@
+//! LAN server discovery. Servers broadcast a `LanBeacon` every second on `DISCOVERY_PORT`, the
+//! "LAN games" menu screen listens for them and pings every server it hears about. Pings are
+//! answered by the socket the beacon came from, not the game port.
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+#[cfg(feature = "netcode")]
+use bevy_renet::renet::RenetServer;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "netcode")]
+use crate::{config::ServerSettings, water::MapName};
+use crate::{
+    consts::DISCOVERY_PORT,
+    protocol::{decode, protocol_version},
+};
+
+#[cfg(feature = "netcode")]
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+// servers not heard from for this long are dropped from the list
+const LAN_SERVER_TIMEOUT: Duration = Duration::from_secs(3);
+// guards against other programs broadcasting on the same port
+const DISCOVERY_MAGIC: [u8; 4] = *b"WTRD";
+// beacons are tiny, anything bigger is garbage
+const MAX_DISCOVERY_PACKET_BYTES: usize = 512;
+
+/// What a server tells the local network about itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LanBeacon {
+    pub name: String,
+    pub map: String,
+    pub players: u16,
+    pub max_players: u16,
+    pub protocol_version: u64,
+    /// game port, the address is the one the beacon came from since the server may be bound to
+    /// an unspecified or loopback address that means nothing to the client
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum DiscoveryPacket {
+    Beacon(LanBeacon),
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+fn encode(packet: &DiscoveryPacket) -> Vec<u8> {
+    let mut bytes = DISCOVERY_MAGIC.to_vec();
+    bytes.extend(bincode::serialize(packet).unwrap());
+    bytes
+}
+
+fn decode_packet(bytes: &[u8]) -> Option<DiscoveryPacket> {
+    decode(bytes.strip_prefix(&DISCOVERY_MAGIC)?).ok()
+}
+
+/// Broadcasts beacons for a netcode server and answers pings.
+#[cfg(feature = "netcode")]
+pub struct LanBeaconPlugin {
+    /// Where the game socket is actually bound.
+    pub game_addr: SocketAddr,
+}
+
+#[cfg(feature = "netcode")]
+impl Plugin for LanBeaconPlugin {
+    fn build(&self, app: &mut App) {
+        match BeaconSocket::bind(self.game_addr) {
+            Ok(socket) => {
+                app.insert_resource(socket);
+                app.add_systems(Update, broadcast_beacon);
+            }
+            Err(err) => warn!("Not advertising the server on the LAN: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Resource)]
+struct BeaconSocket {
+    socket: UdpSocket,
+    target: SocketAddr,
+    game_port: u16,
+    timer: Timer,
+}
+
+#[cfg(feature = "netcode")]
+impl BeaconSocket {
+    /// Sent from the same ip as the game socket, that's where clients will connect to.
+    fn bind(game_addr: SocketAddr) -> io::Result<Self> {
+        // `LanDiscovery` only listens for IPv4 broadcasts
+        if game_addr.is_ipv6() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{game_addr} is IPv6, beacons are IPv4 only"),
+            ));
+        }
+        let ip = game_addr.ip();
+        let socket = UdpSocket::bind((ip, 0))?;
+        socket.set_nonblocking(true)?;
+        // a server on localhost can only be found from the same machine
+        let target = if ip.is_loopback() {
+            SocketAddr::new(ip, DISCOVERY_PORT)
+        } else {
+            socket.set_broadcast(true)?;
+            SocketAddr::new(Ipv4Addr::BROADCAST.into(), DISCOVERY_PORT)
+        };
+        Ok(Self {
+            socket,
+            target,
+            game_port: game_addr.port(),
+            timer: Timer::new(BEACON_INTERVAL, TimerMode::Repeating),
+        })
+    }
+}
+
+#[cfg(feature = "netcode")]
+fn broadcast_beacon(
+    mut beacon: ResMut<BeaconSocket>,
+    time: Res<Time>,
+    server: Res<RenetServer>,
+    settings: Res<ServerSettings>,
+    map: Res<MapName>,
+) {
+    let mut buffer = [0; MAX_DISCOVERY_PACKET_BYTES];
+    while let Ok((len, addr)) = beacon.socket.recv_from(&mut buffer) {
+        if let Some(DiscoveryPacket::Ping { nonce }) = decode_packet(&buffer[..len]) {
+            let _ = beacon
+                .socket
+                .send_to(&encode(&DiscoveryPacket::Pong { nonce }), addr);
+        }
+    }
+
+    if !beacon.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let packet = DiscoveryPacket::Beacon(LanBeacon {
+        name: settings.name.clone(),
+        map: map.0.clone(),
+        players: server.connected_clients() as u16,
+        max_players: settings.max_clients as u16,
+        protocol_version: protocol_version(),
+        port: beacon.game_port,
+    });
+    if let Err(err) = beacon.socket.send_to(&encode(&packet), beacon.target) {
+        debug!("Failed to send the LAN beacon: {err}");
+    }
+}
+
+/// A server heard on the LAN.
+#[derive(Debug, Clone)]
+pub struct LanServer {
+    pub beacon: LanBeacon,
+    pub last_seen: Instant,
+    pub ping: Option<Duration>,
+    // where pings go, the beacon socket of the server
+    beacon_addr: SocketAddr,
+}
+
+impl LanServer {
+    pub fn game_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.beacon_addr.ip(), self.beacon.port)
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.beacon.protocol_version == protocol_version()
+    }
+}
+
+/// Listens for beacons while the LAN games screen is open.
+#[derive(Resource)]
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    servers: HashMap<SocketAddr, LanServer>,
+    // pings in flight, by nonce
+    pings: HashMap<u64, (SocketAddr, Instant)>,
+    next_nonce: u64,
+}
+
+impl LanDiscovery {
+    pub fn bind() -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            servers: HashMap::new(),
+            pings: HashMap::new(),
+            next_nonce: 0,
+        })
+    }
+
+    /// Servers currently alive, sorted by name.
+    pub fn servers(&self) -> Vec<&LanServer> {
+        let mut servers: Vec<_> = self.servers.values().collect();
+        servers.sort_by(|a, b| a.beacon.name.cmp(&b.beacon.name));
+        servers
+    }
+
+    /// Reads the beacons and pongs that arrived. Returns whether the list changed.
+    pub fn update(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        let mut buffer = [0; MAX_DISCOVERY_PACKET_BYTES];
+        while let Ok((len, addr)) = self.socket.recv_from(&mut buffer) {
+            match decode_packet(&buffer[..len]) {
+                Some(DiscoveryPacket::Beacon(beacon)) => {
+                    changed |= self.receive_beacon(now, addr, beacon);
+                }
+                Some(DiscoveryPacket::Pong { nonce }) => {
+                    let Some((addr, sent_at)) = self.pings.remove(&nonce) else {
+                        continue;
+                    };
+                    if let Some(server) = self.servers.get_mut(&addr) {
+                        server.ping = Some(now - sent_at);
+                        changed = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let servers = self.servers.len();
+        self.servers
+            .retain(|_, server| now - server.last_seen < LAN_SERVER_TIMEOUT);
+        self.pings
+            .retain(|_, (_, sent_at)| now - *sent_at < LAN_SERVER_TIMEOUT);
+        changed || servers != self.servers.len()
+    }
+
+    fn receive_beacon(&mut self, now: Instant, addr: SocketAddr, beacon: LanBeacon) -> bool {
+        let changed = self
+            .servers
+            .get(&addr)
+            .is_none_or(|server| server.beacon != beacon);
+        self.servers
+            .entry(addr)
+            .and_modify(|server| {
+                server.beacon = beacon.clone();
+                server.last_seen = now;
+            })
+            .or_insert(LanServer {
+                beacon,
+                last_seen: now,
+                ping: None,
+                beacon_addr: addr,
+            });
+
+        // a ping for every beacon, the list shows the latest one
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        if self
+            .socket
+            .send_to(&encode(&DiscoveryPacket::Ping { nonce }), addr)
+            .is_ok()
+        {
+            self.pings.insert(nonce, (addr, now));
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beacon() -> LanBeacon {
+        LanBeacon {
+            name: "water".to_string(),
+            map: "arena".to_string(),
+            players: 3,
+            max_players: 16,
+            protocol_version: protocol_version(),
+            port: 5000,
+        }
+    }
+
+    #[test]
+    fn test_discovery_packets_round_trip() {
+        let packet = DiscoveryPacket::Beacon(beacon());
+        let bytes = encode(&packet);
+        assert_eq!(decode_packet(&bytes), Some(packet));
+        assert_eq!(decode_packet(&bytes[4..]), None);
+        assert_eq!(decode_packet(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_game_addr_is_where_the_beacon_came_from() {
+        let server = LanServer {
+            beacon: beacon(),
+            last_seen: Instant::now(),
+            ping: None,
+            beacon_addr: "192.168.1.20:41234".parse().unwrap(),
+        };
+        assert_eq!(server.game_addr(), "192.168.1.20:5000".parse().unwrap());
+    }
+
+    #[cfg(feature = "netcode")]
+    #[test]
+    fn test_no_beacon_on_ipv6() {
+        let err = BeaconSocket::bind("[::1]:5000".parse().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
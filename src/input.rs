@@ -73,6 +73,7 @@ pub enum Action {
     Back,
     Right,
     Shoot,
+    Railgun,
     Jump,
 }
 
@@ -84,7 +85,8 @@ pub fn build_input_map() -> InputMap<Action> {
         (Action::Back, KeyCode::KeyS),
         (Action::Right, KeyCode::KeyD),
     ])
-    .with(Action::Shoot, MouseButton::Left);
+    .with(Action::Shoot, MouseButton::Left)
+    .with(Action::Railgun, MouseButton::Right);
 
     return input_map;
 }
@@ -0,0 +1,172 @@
+pub struct InterpolationPlugin;
+
+use std::{collections::VecDeque, time::Duration};
+
+use avian3d::prelude::LinearVelocity;
+use bevy::prelude::*;
+
+use crate::{
+    client::ControlledPlayer,
+    clock::{tick_duration, ServerClock},
+    consts::{DEFAULT_INTERPOLATION_DELAY_MS, SNAPSHOT_BUFFER_SIZE},
+};
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InterpolationSettings {
+            delay: Duration::from_millis(DEFAULT_INTERPOLATION_DELAY_MS),
+        })
+        .add_systems(Update, interpolate_remote_players);
+    }
+}
+
+/// How far behind the newest server snapshot remote players are rendered.
+#[derive(Resource, Debug)]
+pub struct InterpolationSettings {
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+}
+
+/// Server snapshots of a remote entity ordered by tick, rendered `InterpolationSettings::delay`
+/// in the past.
+#[derive(Component, Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+    // newest tick that was used for rendering, anything older is useless
+    rendered_tick: Option<u32>,
+}
+
+impl SnapshotBuffer {
+    /// Inserts `snapshot` in tick order. Returns false if it was dropped because it is a
+    /// duplicate or older than what was already rendered.
+    pub fn push(&mut self, snapshot: Snapshot) -> bool {
+        if self
+            .rendered_tick
+            .is_some_and(|rendered| snapshot.tick <= rendered)
+        {
+            return false;
+        }
+
+        let index = self
+            .snapshots
+            .partition_point(|existing| existing.tick < snapshot.tick);
+        if self
+            .snapshots
+            .get(index)
+            .is_some_and(|existing| existing.tick == snapshot.tick)
+        {
+            return false;
+        }
+        self.snapshots.insert(index, snapshot);
+
+        while self.snapshots.len() > SNAPSHOT_BUFFER_SIZE {
+            self.snapshots.pop_front();
+        }
+        true
+    }
+
+    /// Interpolated state at `render_tick`. Holds the newest snapshot if we ran out of data.
+    pub fn sample(&mut self, render_tick: f64) -> Option<Snapshot> {
+        // keep exactly one snapshot at or before the render tick
+        while self.snapshots.len() >= 2 && self.snapshots[1].tick as f64 <= render_tick {
+            self.snapshots.pop_front();
+        }
+
+        let from = *self.snapshots.front()?;
+        if from.tick as f64 > render_tick {
+            // not enough delay buffered yet, wait for the render tick to catch up
+            return None;
+        }
+        self.rendered_tick = Some(from.tick);
+
+        let Some(to) = self.snapshots.get(1) else {
+            return Some(from);
+        };
+
+        let t = ((render_tick - from.tick as f64) / (to.tick - from.tick) as f64) as f32;
+        Some(Snapshot {
+            tick: from.tick,
+            translation: from.translation.lerp(to.translation, t),
+            rotation: from.rotation.slerp(to.rotation, t),
+            velocity: from.velocity.lerp(to.velocity, t),
+        })
+    }
+}
+
+fn interpolate_remote_players(
+    time_fixed: Res<Time<Fixed>>,
+    settings: Res<InterpolationSettings>,
+    clock: Res<ServerClock>,
+    mut players_q: Query<
+        (&mut SnapshotBuffer, &mut Transform, &mut LinearVelocity),
+        Without<ControlledPlayer>,
+    >,
+) {
+    let Some(server_tick) = clock.estimated_tick() else {
+        return;
+    };
+    let tick_duration = tick_duration(&time_fixed);
+    // snapshots are already one way latency old when they arrive
+    let render_tick = server_tick
+        - clock.one_way_ticks(tick_duration)
+        - settings.delay.as_secs_f64() / tick_duration;
+
+    for (mut buffer, mut player_tf, mut velocity) in players_q.iter_mut() {
+        let Some(snapshot) = buffer.sample(render_tick) else {
+            continue;
+        };
+        player_tf.translation = snapshot.translation;
+        player_tf.rotation = snapshot.rotation;
+        velocity.0 = snapshot.velocity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(tick: u32, x: f32) -> Snapshot {
+        Snapshot {
+            tick,
+            translation: Vec3::new(x, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+            velocity: Vec3::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_interpolates_between_snapshots() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(10, 0.0));
+        buffer.push(snapshot(14, 4.0));
+        let sampled = buffer.sample(11.0).unwrap();
+        assert!((sampled.translation.x - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_out_of_order_snapshots_are_sorted() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(14, 4.0));
+        buffer.push(snapshot(10, 0.0));
+        let sampled = buffer.sample(12.0).unwrap();
+        assert!((sampled.translation.x - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_stale_snapshots_are_dropped() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(10, 0.0));
+        buffer.push(snapshot(14, 4.0));
+        buffer.sample(15.0);
+        assert!(!buffer.push(snapshot(12, 2.0)));
+        assert!(!buffer.push(snapshot(14, 4.0)));
+        assert!(buffer.push(snapshot(18, 8.0)));
+    }
+}
@@ -0,0 +1,204 @@
+//! Simulated network conditions, so prediction, interpolation and the reliable channels can be
+//! tested on localhost. The transports in `transport.rs` run every packet through a
+//! `LinkConditioner` before it reaches the socket or renet.
+use std::time::Duration;
+#[cfg(feature = "netcode")]
+use std::{cmp::Ordering, collections::BinaryHeap, net::SocketAddr, time::Instant};
+
+use bevy::prelude::*;
+#[cfg(feature = "netcode")]
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// how long a reordered packet is held back, on top of its latency and jitter
+#[cfg(feature = "netcode")]
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Conditions of the simulated link, changed at runtime with the `net_*` console commands.
+/// They apply to packets in both directions, the round trip grows by twice the latency.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    /// each packet gets a random extra delay of up to this much
+    pub jitter: Duration,
+    /// chance to drop a packet, between 0 and 1
+    pub loss: f64,
+    /// chance to deliver a packet twice, between 0 and 1
+    pub duplication: f64,
+    /// chance to hold a packet back so the ones after it overtake it, between 0 and 1
+    pub reordering: f64,
+}
+
+impl LinkConditions {
+    #[cfg(feature = "netcode")]
+    pub fn is_ideal(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Debug)]
+struct DelayedPacket {
+    deliver_at: Instant,
+    // keeps packets with the same delivery time in the order they were pushed
+    sequence: u64,
+    addr: SocketAddr,
+    payload: Vec<u8>,
+}
+
+#[cfg(feature = "netcode")]
+impl PartialEq for DelayedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+#[cfg(feature = "netcode")]
+impl Eq for DelayedPacket {}
+
+#[cfg(feature = "netcode")]
+impl PartialOrd for DelayedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "netcode")]
+impl Ord for DelayedPacket {
+    // reversed, the heap pops the earliest packet first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.sequence).cmp(&(self.deliver_at, self.sequence))
+    }
+}
+
+/// Packets in flight on one direction of the simulated link.
+#[cfg(feature = "netcode")]
+#[derive(Debug)]
+pub struct LinkConditioner {
+    pub conditions: LinkConditions,
+    in_flight: BinaryHeap<DelayedPacket>,
+    sequence: u64,
+    rng: StdRng,
+}
+
+#[cfg(feature = "netcode")]
+impl Default for LinkConditioner {
+    fn default() -> Self {
+        Self::new(StdRng::from_entropy())
+    }
+}
+
+#[cfg(feature = "netcode")]
+impl LinkConditioner {
+    pub fn new(rng: StdRng) -> Self {
+        Self {
+            conditions: LinkConditions::default(),
+            in_flight: BinaryHeap::new(),
+            sequence: 0,
+            rng,
+        }
+    }
+
+    /// Nothing to simulate and nothing in flight, packets can skip the queue.
+    pub fn is_idle(&self) -> bool {
+        self.conditions.is_ideal() && self.in_flight.is_empty()
+    }
+
+    /// Sends a packet over the simulated link, it might get lost, delayed or duplicated.
+    pub fn push(&mut self, now: Instant, addr: SocketAddr, payload: &[u8]) {
+        if self.roll(self.conditions.loss) {
+            return;
+        }
+        let copies = if self.roll(self.conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = self.conditions.latency;
+            if !self.conditions.jitter.is_zero() {
+                delay += self.conditions.jitter.mul_f64(self.rng.gen());
+            }
+            if self.roll(self.conditions.reordering) {
+                delay += REORDER_DELAY;
+            }
+            self.in_flight.push(DelayedPacket {
+                deliver_at: now + delay,
+                sequence: self.sequence,
+                addr,
+                payload: payload.to_vec(),
+            });
+            self.sequence += 1;
+        }
+    }
+
+    /// Next packet that reached the other end of the link by `now`.
+    pub fn pop(&mut self, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        if self.in_flight.peek()?.deliver_at > now {
+            return None;
+        }
+        self.in_flight
+            .pop()
+            .map(|packet| (packet.addr, packet.payload))
+    }
+
+    /// Everything in flight regardless of its delay, for when the app is about to exit.
+    pub fn drain(&mut self) -> impl Iterator<Item = (SocketAddr, Vec<u8>)> + '_ {
+        std::iter::from_fn(|| {
+            self.in_flight
+                .pop()
+                .map(|packet| (packet.addr, packet.payload))
+        })
+    }
+
+    fn roll(&mut self, chance: f64) -> bool {
+        chance > 0.0 && self.rng.gen::<f64>() < chance
+    }
+}
+
+#[cfg(all(test, feature = "netcode"))]
+mod tests {
+    use super::*;
+
+    fn conditioner(conditions: LinkConditions) -> LinkConditioner {
+        let mut conditioner = LinkConditioner::new(StdRng::seed_from_u64(0));
+        conditioner.conditions = conditions;
+        conditioner
+    }
+
+    #[test]
+    fn test_packets_are_delayed_in_order() {
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let now = Instant::now();
+        let mut conditioner = conditioner(LinkConditions {
+            latency: Duration::from_millis(100),
+            ..default()
+        });
+        conditioner.push(now, addr, &[1]);
+        conditioner.push(now, addr, &[2]);
+        assert_eq!(conditioner.pop(now + Duration::from_millis(99)), None);
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(conditioner.pop(later), Some((addr, vec![1])));
+        assert_eq!(conditioner.pop(later), Some((addr, vec![2])));
+        assert_eq!(conditioner.pop(later), None);
+    }
+
+    #[test]
+    fn test_loss_and_duplication() {
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let now = Instant::now();
+        let mut lossy = conditioner(LinkConditions {
+            loss: 1.0,
+            ..default()
+        });
+        lossy.push(now, addr, &[1]);
+        assert_eq!(lossy.pop(now), None);
+
+        let mut duplicating = conditioner(LinkConditions {
+            duplication: 1.0,
+            ..default()
+        });
+        duplicating.push(now, addr, &[1]);
+        assert_eq!(duplicating.drain().count(), 2);
+    }
+}
@@ -1,33 +1,61 @@
 use avian3d::PhysicsPlugins;
 use bevy::color::palettes::css::GREEN;
 use bevy::render::RenderPlugin;
-use bevy::window::PrimaryWindow;
+use bevy::window::{ExitCondition, PrimaryWindow};
 use bevy::{prelude::*, window::WindowResolution};
 
+use bevy::app::{PluginGroupBuilder, ScheduleRunnerPlugin};
+use bevy::audio::AudioPlugin;
 use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
+use bevy::gilrs::GilrsPlugin;
 use bevy::log::{Level, LogPlugin};
 use bevy::pbr::wireframe::{WireframeConfig, WireframePlugin};
 use bevy::prelude::Window;
 use bevy::render::{render_resource::WgpuFeatures, settings::WgpuSettings};
+use bevy::winit::WinitPlugin;
 use bevy_egui::EguiContext;
 use bevy_egui::EguiPlugin;
 use client::ClientPlugin;
+use config::Launch;
+use console::StartupCommands;
+use demo::{Demo, DemoPlayback, DemoPlugin};
 use rand::rngs::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
 use server::ServerPlugin;
+use std::time::Duration;
+use water::{GameState, MapName};
 
 mod animation;
+#[cfg(feature = "netcode")]
+mod auth;
 mod bimap;
+#[cfg(feature = "netcode")]
+mod bots;
 mod camera;
 mod character;
+mod chat;
 mod client;
+mod clock;
+mod codec;
+mod config;
+mod connection;
 mod console;
 mod consts;
+mod demo;
+mod discovery;
 mod input;
+mod interpolation;
+mod link_conditioner;
 mod menu;
 mod network_visualizer;
+mod prediction;
+mod protocol;
+mod replication;
 mod server;
+mod snapshot;
+#[cfg(feature = "netcode")]
+mod transport;
 mod ui;
 mod water;
 
@@ -41,8 +69,28 @@ pub enum AppState {
 }
 
 fn main() {
+    let launch = config::parse();
+    #[cfg(feature = "netcode")]
+    if let Launch::Token(settings) = &launch {
+        if let Err(err) = auth::token_command(settings) {
+            eprintln!("Failed to write the token: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    #[cfg(feature = "netcode")]
+    if let Launch::Bots(settings) = &launch {
+        if let Err(err) = bots::run(settings) {
+            eprintln!("Failed to run the bots: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut app = App::new();
     let mut rng = rand::thread_rng();
+    let headless = matches!(&launch, Launch::Server(settings, _) if settings.headless);
+    let demo = matches!(&launch, Launch::Demo(_));
 
     app.add_plugins(FrameTimeDiagnosticsPlugin::default());
     #[cfg(debug_assertions)] // debug/dev builds only
@@ -50,58 +98,86 @@ fn main() {
         use bevy::diagnostic::LogDiagnosticsPlugin;
         app.add_plugins(LogDiagnosticsPlugin::default());
     }
-    app.add_plugins(
-        DefaultPlugins
-            .set(ImagePlugin::default_nearest())
-            .set(WindowPlugin {
-                primary_window: Some(Window {
-                    resolution: WindowResolution::new(
-                        consts::RES_WIDTH as f32,
-                        consts::RES_HEIGHT as f32,
-                    )
-                    .with_scale_factor_override(1.0),
-                    ..default()
-                }),
-                ..default()
-            })
-            .set(LogPlugin {
-                level: Level::DEBUG,
-                filter: "wgpu=error,bevy_render=info,bevy_ecs=trace,naga=info,leafwing_input_manager=info".to_string(),
-                custom_layer: |_| None,
-            })
-            .set(RenderPlugin {
-                render_creation: bevy::render::settings::RenderCreation::Automatic(WgpuSettings {
-                    features: WgpuFeatures::POLYGON_MODE_LINE,
-                    ..default()
-                }),
-                ..default()
-            }),
-    )
-    .add_plugins(WireframePlugin)
-    .insert_resource(ClearColor(Color::srgb(0., 0., 0.)))
-    .insert_resource(RngResource(StdRng::seed_from_u64(rng.gen::<u64>())));
-
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() < 2 {
-        panic!("No argument found, pass either client or server");
+    match &launch {
+        Launch::Server(settings, _) if settings.headless => {
+            app.add_plugins(headless_plugins(settings.tick_rate));
+        }
+        _ => {
+            app.add_plugins(
+                DefaultPlugins
+                    .set(ImagePlugin::default_nearest())
+                    .set(WindowPlugin {
+                        primary_window: Some(Window {
+                            resolution: WindowResolution::new(
+                                consts::RES_WIDTH as f32,
+                                consts::RES_HEIGHT as f32,
+                            )
+                            .with_scale_factor_override(1.0),
+                            ..default()
+                        }),
+                        ..default()
+                    })
+                    .set(log_plugin())
+                    .set(RenderPlugin {
+                        render_creation: bevy::render::settings::RenderCreation::Automatic(
+                            WgpuSettings {
+                                features: WgpuFeatures::POLYGON_MODE_LINE,
+                                ..default()
+                            },
+                        ),
+                        ..default()
+                    }),
+            )
+            .add_plugins(WireframePlugin)
+            .insert_resource(ClearColor(Color::srgb(0., 0., 0.)));
+        }
     }
+    app.insert_resource(RngResource(StdRng::seed_from_u64(rng.gen::<u64>())));
 
-    let exec_type = &args[1];
-    let is_host = match exec_type.as_str() {
-        "client" => false,
-        "server" => true,
-        _ => panic!("Invalid argument, must be \"client\" or \"server\"."),
-    };
-
-    debug!("is_host: {:?}", is_host);
+    match launch {
+        Launch::Server(settings, commands) => {
+            debug!("Adding ServerPlugin to app");
+            app.insert_resource(Time::<Fixed>::from_hz(settings.tick_rate))
+                .insert_resource(MapName(settings.map.clone()))
+                .insert_resource(StartupCommands(commands))
+                .insert_resource(settings)
+                .add_plugins(ServerPlugin);
+        }
+        Launch::Client(settings, commands) => {
+            debug!("Adding ClientPlugin to app");
+            // the fixed timestep comes from the server once connected
+            app.insert_resource(StartupCommands(commands))
+                .insert_resource(settings)
+                .add_plugins(ClientPlugin);
+        }
+        Launch::Demo(settings) => {
+            debug!("Adding DemoPlugin to app");
+            let demo = match Demo::load(&settings.file) {
+                Ok(demo) => demo,
+                Err(err) => {
+                    eprintln!("Failed to load the demo: {err}");
+                    std::process::exit(1);
+                }
+            };
+            app.insert_resource(Time::<Fixed>::from_hz(demo.header.tick_rate))
+                .insert_resource(MapName(demo.header.map.clone()))
+                .insert_resource(DemoPlayback::new(demo, settings.speed))
+                .add_plugins(DemoPlugin);
+        }
+        #[cfg(feature = "netcode")]
+        Launch::Token(_) | Launch::Bots(_) => unreachable!(),
+    }
 
-    if is_host {
-        debug!("Adding ServerPlugin to app");
-        app.add_plugins(ServerPlugin);
-    } else {
-        debug!("Adding ClientPlugin to app");
-        app.add_plugins(ClientPlugin);
+    if headless {
+        // no menu to go through, straight into the game
+        app.add_plugins(water::WaterPlugin)
+            .add_plugins(PhysicsPlugins::default())
+            .add_plugins(character::CharacterControllerPlugin)
+            .add_plugins(input::InputPlugin)
+            .add_plugins(console::ConsolePlugin)
+            .insert_state(GameState::Game)
+            .run();
+        return;
     }
 
     app.insert_resource(WireframeConfig {
@@ -120,8 +196,48 @@ fn main() {
         .add_plugins(console::ConsolePlugin)
         .add_plugins(animation::AnimationPlugin)
         .add_plugins(menu::MenuPlugin)
-        .add_plugins(ui::UiPlugin)
-        .run();
+        .add_plugins(ui::UiPlugin);
+    if demo {
+        // nothing to pick in the menu, the demo decides the map
+        app.insert_state(GameState::Game);
+    }
+    app.run();
+}
+
+fn log_plugin() -> LogPlugin {
+    LogPlugin {
+        level: Level::DEBUG,
+        filter: "wgpu=error,bevy_render=info,bevy_ecs=trace,naga=info,leafwing_input_manager=info"
+            .to_string(),
+        custom_layer: |_| None,
+    }
+}
+
+/// Dedicated server without a window or GPU. Assets, scenes and glTF meshes still load so the
+/// map colliders get built, but the renderer has no backend and nothing is drawn.
+fn headless_plugins(tick_rate: f64) -> PluginGroupBuilder {
+    DefaultPlugins
+        .build()
+        .disable::<WinitPlugin>()
+        .disable::<AudioPlugin>()
+        .disable::<GilrsPlugin>()
+        .set(WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            close_when_requested: false,
+        })
+        .set(log_plugin())
+        .set(RenderPlugin {
+            render_creation: WgpuSettings {
+                backends: None,
+                ..default()
+            }
+            .into(),
+            ..default()
+        })
+        .add(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / tick_rate,
+        )))
 }
 
 fn inspector_ui(world: &mut World) {
@@ -1,5 +1,10 @@
 pub struct MenuPlugin;
 
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::connection::JoinServer;
+use crate::discovery::LanDiscovery;
 use crate::water::GameState;
 
 use bevy::app::AppExit;
@@ -32,6 +37,17 @@ impl Plugin for MenuPlugin {
             // Systems to handle the main menu screen
             .add_systems(OnEnter(MenuState::Main), main_menu_setup)
             .add_systems(OnExit(MenuState::Main), despawn_screen::<OnMainMenuScreen>)
+            // Systems to handle the LAN games screen
+            .add_event::<JoinServer>()
+            .add_systems(OnEnter(MenuState::LanGames), lan_games_menu_setup)
+            .add_systems(
+                Update,
+                update_lan_games.run_if(in_state(MenuState::LanGames)),
+            )
+            .add_systems(
+                OnExit(MenuState::LanGames),
+                (despawn_screen::<OnLanGamesMenuScreen>, stop_lan_discovery),
+            )
             // Systems to handle the settings menu screen
             .add_systems(OnEnter(MenuState::Settings), settings_menu_setup)
             .add_systems(
@@ -72,6 +88,7 @@ impl Plugin for MenuPlugin {
 #[derive(Clone, Copy, Default, Eq, PartialEq, Debug, Hash, States)]
 enum MenuState {
     Main,
+    LanGames,
     Settings,
     SettingsDisplay,
     SettingsSound,
@@ -83,6 +100,14 @@ enum MenuState {
 #[derive(Component)]
 struct OnMainMenuScreen;
 
+// Tag component used to tag entities added on the LAN games menu screen
+#[derive(Component)]
+struct OnLanGamesMenuScreen;
+
+// Tag component for the node holding one button per server found on the LAN
+#[derive(Component)]
+struct LanGamesList;
+
 // Tag component used to tag entities added on the settings menu screen
 #[derive(Component)]
 struct OnSettingsMenuScreen;
@@ -108,6 +133,8 @@ struct SelectedOption;
 #[derive(Component)]
 enum MenuButtonAction {
     Play,
+    LanGames,
+    JoinLanGame(SocketAddr),
     Settings,
     SettingsDisplay,
     SettingsSound,
@@ -200,8 +227,9 @@ fn main_menu_setup(mut commands: Commands) {
                         TextColor(TEXT_COLOR),
                     ));
 
-                    // Display three buttons for each action available from the main menu:
+                    // Display four buttons for each action available from the main menu:
                     // - new game
+                    // - LAN games
                     // - settings
                     // - quit
                     parent
@@ -220,6 +248,22 @@ fn main_menu_setup(mut commands: Commands) {
                                 },
                             ));
                         });
+                    parent
+                        .spawn((
+                            Button,
+                            button_node.clone(),
+                            BackgroundColor(NORMAL_BUTTON),
+                            MenuButtonAction::LanGames,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn((
+                                Text::new("LAN Games"),
+                                TextFont {
+                                    font_size: 60.0,
+                                    ..Default::default()
+                                },
+                            ));
+                        });
                     parent
                         .spawn((
                             Button,
@@ -256,6 +300,143 @@ fn main_menu_setup(mut commands: Commands) {
         });
 }
 
+fn lan_games_menu_setup(mut commands: Commands) {
+    let button_node = Node {
+        width: Val::Px(200.0),
+        height: Val::Px(40.0),
+        margin: UiRect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+
+    // the screen still opens if the port is taken, it just says so
+    let status = match LanDiscovery::bind() {
+        Ok(discovery) => {
+            commands.insert_resource(discovery);
+            "Looking for games on the local network...".to_string()
+        }
+        Err(err) => format!("Can't listen for LAN games: {err}"),
+    };
+
+    commands
+        .spawn((
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            OnLanGamesMenuScreen,
+            BackgroundColor(BLACK.into()),
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(Node {
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn((
+                        Text::new("LAN Games"),
+                        TextFont {
+                            font_size: 60.0,
+                            ..Default::default()
+                        },
+                        TextColor(TEXT_COLOR),
+                    ));
+                    parent
+                        .spawn((
+                            Node {
+                                flex_direction: FlexDirection::Column,
+                                align_items: AlignItems::Center,
+                                margin: UiRect::all(Val::Px(20.0)),
+                                ..default()
+                            },
+                            LanGamesList,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn((Text::new(status), TextColor(TEXT_COLOR)));
+                        });
+                    parent
+                        .spawn((
+                            Button,
+                            button_node,
+                            BackgroundColor(NORMAL_BUTTON),
+                            MenuButtonAction::BackToMainMenu,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(Text::new("Back"));
+                        });
+                });
+        });
+}
+
+// Rebuilds the list of servers whenever a beacon or ping changes it
+fn update_lan_games(
+    mut commands: Commands,
+    discovery: Option<ResMut<LanDiscovery>>,
+    list_query: Single<Entity, With<LanGamesList>>,
+) {
+    let Some(mut discovery) = discovery else {
+        return;
+    };
+    if !discovery.update(Instant::now()) {
+        return;
+    }
+
+    let list = list_query.into_inner();
+    commands.entity(list).despawn_descendants();
+    commands.entity(list).with_children(|parent| {
+        let servers = discovery.servers();
+        if servers.is_empty() {
+            parent.spawn((
+                Text::new("Looking for games on the local network..."),
+                TextColor(TEXT_COLOR),
+            ));
+        }
+        for server in servers {
+            let ping = server
+                .ping
+                .map_or("?".to_string(), |ping| ping.as_millis().to_string());
+            let mut label = format!(
+                "{} - {} - {}/{} players - {ping} ms",
+                server.beacon.name,
+                server.beacon.map,
+                server.beacon.players,
+                server.beacon.max_players
+            );
+            let mut entity = parent.spawn((
+                Button,
+                Node {
+                    width: Val::Px(800.0),
+                    height: Val::Px(50.0),
+                    margin: UiRect::all(Val::Px(5.0)),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                BackgroundColor(NORMAL_BUTTON),
+            ));
+            // listed so the player knows why they can't join it
+            if server.is_compatible() {
+                entity.insert(MenuButtonAction::JoinLanGame(server.game_addr()));
+            } else {
+                label.push_str(" - different version");
+            }
+            entity.with_children(|parent| {
+                parent.spawn(Text::new(label));
+            });
+        }
+    });
+}
+
+fn stop_lan_discovery(mut commands: Commands) {
+    commands.remove_resource::<LanDiscovery>();
+}
+
 fn settings_menu_setup(mut commands: Commands) {
     let button_node = Node {
         width: Val::Px(200.0),
@@ -472,6 +653,7 @@ fn menu_action(
     mut app_exit_events: EventWriter<AppExit>,
     mut menu_state: ResMut<NextState<MenuState>>,
     mut game_state: ResMut<NextState<GameState>>,
+    mut join_server: EventWriter<JoinServer>,
 ) {
     for (interaction, menu_button_action) in &interaction_query {
         if *interaction == Interaction::Pressed {
@@ -483,6 +665,12 @@ fn menu_action(
                     game_state.set(GameState::Game);
                     menu_state.set(MenuState::Disabled);
                 }
+                MenuButtonAction::LanGames => menu_state.set(MenuState::LanGames),
+                MenuButtonAction::JoinLanGame(server_addr) => {
+                    join_server.send(JoinServer(*server_addr));
+                    game_state.set(GameState::Game);
+                    menu_state.set(MenuState::Disabled);
+                }
                 MenuButtonAction::Settings => menu_state.set(MenuState::Settings),
                 MenuButtonAction::SettingsDisplay => {
                     menu_state.set(MenuState::SettingsDisplay);
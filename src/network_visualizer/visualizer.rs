@@ -11,6 +11,11 @@ use egui::{
 
 use bevy_renet::renet::{ClientId, NetworkInfo, RenetServer};
 
+use crate::server::{
+    bandwidth::{SnapshotBandwidth, SnapshotUsage},
+    validation::{ValidationViolations, ViolationCounts},
+};
+
 use super::circular_buffer::CircularBuffer;
 
 /// Egui visualizer for the renet client. Draws graphs with metrics:
@@ -24,6 +29,8 @@ pub struct RenetClientVisualizer<const N: usize> {
     sent_bandwidth_kbps: CircularBuffer<N, f32>,
     received_bandwidth_kbps: CircularBuffer<N, f32>,
     packet_loss: CircularBuffer<N, f32>,
+    // ticks per second, and the update rate we asked for
+    rates: Option<(f64, Option<f64>)>,
     style: RenetVisualizerStyle,
 }
 
@@ -36,7 +43,11 @@ pub struct RenetClientVisualizer<const N: usize> {
 pub struct RenetServerVisualizer<const N: usize> {
     show_all_clients: bool,
     selected_client: Option<ClientId>,
+    // ticks and max snapshots per second
+    rates: Option<(f64, f64)>,
     clients: HashMap<ClientId, RenetClientVisualizer<N>>,
+    violations: HashMap<ClientId, ViolationCounts>,
+    snapshot_usage: HashMap<ClientId, SnapshotUsage>,
     style: RenetVisualizerStyle,
 }
 
@@ -91,10 +102,17 @@ impl<const N: usize> RenetClientVisualizer<N> {
             sent_bandwidth_kbps: CircularBuffer::default(),
             received_bandwidth_kbps: CircularBuffer::default(),
             packet_loss: CircularBuffer::default(),
+            rates: None,
             style,
         }
     }
 
+    /// Set the simulation rate and the update rate requested from the server, shown above the
+    /// graphs.
+    pub fn set_rates(&mut self, tick_rate: f64, update_rate: Option<f64>) {
+        self.rates = Some((tick_rate, update_rate));
+    }
+
     /// Add the network information from the client. Should be called every time the client
     /// updates.
     ///
@@ -123,6 +141,16 @@ impl<const N: usize> RenetClientVisualizer<N> {
             .resizable(false)
             .collapsible(true)
             .show(ctx, |ui| {
+                if let Some((tick_rate, update_rate)) = self.rates {
+                    let update_rate = update_rate
+                        .map_or("server default".to_string(), |rate| format!("{rate} Hz"));
+                    ui.label(
+                        RichText::new(format!(
+                            "Tick rate: {tick_rate} Hz, update rate: {update_rate}"
+                        ))
+                        .color(self.style.text_color),
+                    );
+                }
                 ui.horizontal(|ui| {
                     self.draw_all(ui);
                 });
@@ -191,11 +219,20 @@ impl<const N: usize> RenetServerVisualizer<N> {
         Self {
             show_all_clients: false,
             selected_client: None,
+            rates: None,
             clients: HashMap::new(),
+            violations: HashMap::new(),
+            snapshot_usage: HashMap::new(),
             style,
         }
     }
 
+    /// Set the simulation rate and the highest snapshot rate clients can get, shown at the top
+    /// of the window.
+    pub fn set_rates(&mut self, tick_rate: f64, send_rate: f64) {
+        self.rates = Some((tick_rate, send_rate));
+    }
+
     /// Add a new client to keep track off. Should be called whenever a new client
     /// connected event is received.
     ///
@@ -241,6 +278,8 @@ impl<const N: usize> RenetServerVisualizer<N> {
     /// ```
     pub fn remove_client(&mut self, client_id: ClientId) {
         self.clients.remove(&client_id);
+        self.violations.remove(&client_id);
+        self.snapshot_usage.remove(&client_id);
     }
 
     fn add_network_info(&mut self, client_id: ClientId, network_info: NetworkInfo) {
@@ -270,10 +309,50 @@ impl<const N: usize> RenetServerVisualizer<N> {
         }
     }
 
+    /// Copy the input validation counters, shown under each client's graphs.
+    pub fn update_violations(&mut self, violations: &ValidationViolations) {
+        self.violations.clear();
+        self.violations
+            .extend(violations.0.iter().map(|(id, counts)| (*id, *counts)));
+    }
+
+    /// Copy what went into each client's last snapshot, shown under its graphs.
+    pub fn update_snapshot_usage(&mut self, bandwidth: &SnapshotBandwidth) {
+        self.snapshot_usage.clear();
+        self.snapshot_usage
+            .extend(bandwidth.0.iter().map(|(id, client)| (*id, client.usage)));
+    }
+
+    fn draw_snapshot_usage(&self, client_id: ClientId, ui: &mut egui::Ui) {
+        let usage = self
+            .snapshot_usage
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default();
+        let text = RichText::new(format!("Last snapshot: {usage}"));
+        if usage.deferred > 0 {
+            ui.label(text.color(Color32::YELLOW));
+        } else {
+            ui.label(text.color(self.style.text_color));
+        }
+    }
+
+    fn draw_violations(&self, client_id: ClientId, ui: &mut egui::Ui) {
+        let counts = self.violations.get(&client_id).copied().unwrap_or_default();
+        let text = RichText::new(format!("Input violations: {counts}"));
+        if counts.total() > 0 {
+            ui.label(text.color(Color32::RED));
+        } else {
+            ui.label(text.color(self.style.text_color));
+        }
+    }
+
     /// Draw all metrics without a window or layout for the specified client.
     pub fn draw_client_metrics(&self, client_id: ClientId, ui: &mut egui::Ui) {
         if let Some(client) = self.clients.get(&client_id) {
             client.draw_all(ui);
+            self.draw_snapshot_usage(client_id, ui);
+            self.draw_violations(client_id, ui);
         }
     }
 
@@ -284,6 +363,14 @@ impl<const N: usize> RenetServerVisualizer<N> {
             .resizable(false)
             .collapsible(true)
             .show(ctx, |ui| {
+                if let Some((tick_rate, send_rate)) = self.rates {
+                    ui.label(
+                        RichText::new(format!(
+                            "Tick rate: {tick_rate} Hz, send rate: up to {send_rate} Hz"
+                        ))
+                        .color(self.style.text_color),
+                    );
+                }
                 ui.horizontal(|ui| {
                     ui.checkbox(&mut self.show_all_clients, "Show all clients");
                     ui.add_enabled_ui(!self.show_all_clients, |ui| {
@@ -312,6 +399,8 @@ impl<const N: usize> RenetServerVisualizer<N> {
                                 ui.horizontal(|ui| {
                                     client.draw_all(ui);
                                 });
+                                self.draw_snapshot_usage(*client_id, ui);
+                                self.draw_violations(*client_id, ui);
                             });
                         }
                     } else if let Some(selected_client) = self.selected_client {
@@ -319,6 +408,8 @@ impl<const N: usize> RenetServerVisualizer<N> {
                             ui.horizontal(|ui| {
                                 client.draw_all(ui);
                             });
+                            self.draw_snapshot_usage(selected_client, ui);
+                            self.draw_violations(selected_client, ui);
                         }
                     }
                 });
@@ -0,0 +1,340 @@
+pub struct PredictionPlugin;
+
+use std::collections::VecDeque;
+
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::{
+    character::{
+        accelerate, damp, Grounded, JumpImpulse, MovementAcceleration, MovementDampingFactor,
+    },
+    client::ControlledPlayer,
+    consts::{PREDICTION_HISTORY_SIZE, RECONCILIATION_TOLERANCE},
+    input::{Action, MovementIntent},
+};
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputSequence>()
+            .init_resource::<PredictionHistory>()
+            .add_event::<AuthoritativeState>()
+            .add_systems(FixedPreUpdate, advance_input_sequence)
+            .add_systems(FixedUpdate, reconcile_controlled_player)
+            .add_systems(
+                FixedPostUpdate,
+                record_predicted_state.after(PhysicsSet::Sync),
+            );
+    }
+}
+
+/// Sequence number of the fixed tick the controlled player is currently simulating.
+/// Sent to the server with every `ClientAction` so it can tell us which input it last applied.
+#[derive(Resource, Default, Debug)]
+pub struct InputSequence(pub u32);
+
+/// Whether input sequence `a` comes after `b`, allowing for the sequence wrapping around.
+pub fn sequence_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The inputs used to simulate one fixed tick locally.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PredictedInput {
+    pub intent: Vec3,
+    pub jump: bool,
+    pub grounded: bool,
+    // rocket knockback the server applied on this tick
+    pub impulse: Vec3,
+}
+
+/// What we predicted the controlled player looked like after simulating `sequence`.
+#[derive(Debug, Clone, Copy)]
+pub struct PredictedState {
+    pub sequence: u32,
+    pub input: PredictedInput,
+    pub translation: Vec3,
+    pub velocity: Vec3,
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct PredictionHistory(pub VecDeque<PredictedState>);
+
+impl PredictionHistory {
+    /// Adds a knockback to the input of `sequence`, so replaying that tick doesn't lose it.
+    pub fn record_impulse(&mut self, sequence: u32, impulse: Vec3) {
+        if let Some(predicted) = self
+            .0
+            .iter_mut()
+            .find(|predicted| predicted.sequence == sequence)
+        {
+            predicted.input.impulse += impulse;
+        }
+    }
+}
+
+/// State of our own player as seen by the server, after it applied `sequence`.
+#[derive(Event, Debug, Clone)]
+pub struct AuthoritativeState {
+    pub sequence: u32,
+    pub translation: Vec3,
+    pub velocity: Vec3,
+}
+
+/// Constants needed to re-simulate the controlled player outside of the physics step.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayParams {
+    pub acceleration: f32,
+    pub damping: f32,
+    pub jump_impulse: f32,
+    pub gravity: Vec3,
+    pub delta_time: f32,
+}
+
+fn advance_input_sequence(mut sequence: ResMut<InputSequence>) {
+    sequence.0 = sequence.0.wrapping_add(1);
+}
+
+/// What `record_predicted_state` needs from the controlled player.
+type PredictedPlayer<'a> = (
+    &'a Transform,
+    &'a LinearVelocity,
+    &'a MovementIntent,
+    &'a ActionState<Action>,
+    Has<Grounded>,
+);
+
+fn record_predicted_state(
+    sequence: Res<InputSequence>,
+    mut history: ResMut<PredictionHistory>,
+    player_q: Query<PredictedPlayer, With<ControlledPlayer>>,
+) {
+    let Ok((player_tf, velocity, move_intent, action_state, grounded)) = player_q.get_single()
+    else {
+        return;
+    };
+
+    history.0.push_back(PredictedState {
+        sequence: sequence.0,
+        input: PredictedInput {
+            intent: move_intent.0,
+            jump: action_state.pressed(&Action::Jump),
+            grounded,
+            impulse: Vec3::ZERO,
+        },
+        translation: player_tf.translation,
+        velocity: velocity.0,
+    });
+
+    while history.0.len() > PREDICTION_HISTORY_SIZE {
+        history.0.pop_front();
+    }
+}
+
+/// The controlled player's state and the constants to replay its inputs with.
+type ReconciledPlayer<'a> = (
+    &'a mut Transform,
+    &'a mut LinearVelocity,
+    &'a MovementAcceleration,
+    &'a MovementDampingFactor,
+    &'a JumpImpulse,
+    Option<&'a GravityScale>,
+);
+
+fn reconcile_controlled_player(
+    time_fixed: Res<Time<Fixed>>,
+    gravity: Res<Gravity>,
+    mut authoritative_states: EventReader<AuthoritativeState>,
+    mut history: ResMut<PredictionHistory>,
+    mut player_q: Query<ReconciledPlayer, With<ControlledPlayer>>,
+) {
+    // only the most recent state matters, older ones are already acknowledged by it
+    let Some(state) = authoritative_states.read().last() else {
+        return;
+    };
+    let Ok((mut player_tf, mut velocity, acceleration, damping, jump_impulse, gravity_scale)) =
+        player_q.get_single_mut()
+    else {
+        return;
+    };
+
+    let params = ReplayParams {
+        acceleration: acceleration.0,
+        damping: damping.0,
+        jump_impulse: jump_impulse.0,
+        gravity: gravity.0 * gravity_scale.map_or(1.0, |scale| scale.0),
+        delta_time: time_fixed.delta_secs(),
+    };
+
+    if let Some((translation, linear_velocity)) = reconcile(&mut history.0, state, &params) {
+        debug!(
+            "reconciled controlled player, correction: {:?}",
+            translation - player_tf.translation
+        );
+        player_tf.translation = translation;
+        velocity.0 = linear_velocity;
+    }
+}
+
+/// Drops every acknowledged entry from `history` and, if the server disagrees with what we
+/// predicted for `state.sequence`, replays the remaining inputs on top of the server state.
+///
+/// Returns the corrected translation and velocity, or `None` if the prediction was good enough.
+pub fn reconcile(
+    history: &mut VecDeque<PredictedState>,
+    state: &AuthoritativeState,
+    params: &ReplayParams,
+) -> Option<(Vec3, Vec3)> {
+    while history
+        .front()
+        .is_some_and(|predicted| sequence_newer(state.sequence, predicted.sequence))
+    {
+        history.pop_front();
+    }
+
+    // either too old or from before we started predicting
+    let acknowledged = history.pop_front()?;
+    if acknowledged.sequence != state.sequence {
+        history.push_front(acknowledged);
+        return None;
+    }
+
+    if acknowledged.translation.distance(state.translation) <= RECONCILIATION_TOLERANCE
+        && acknowledged.velocity.distance(state.velocity) <= RECONCILIATION_TOLERANCE
+    {
+        return None;
+    }
+
+    let mut translation = state.translation;
+    let mut velocity = state.velocity;
+    for predicted in history.iter_mut() {
+        (translation, velocity) = replay_step(translation, velocity, &predicted.input, params);
+        predicted.translation = translation;
+        predicted.velocity = velocity;
+    }
+
+    Some((translation, velocity))
+}
+
+/// Re-simulates a single tick the same way `movement`, `apply_movement_damping` and
+/// `movement_2` do. Collisions are not replayed, the ground is only taken into account through
+/// the recorded grounded flag.
+pub fn replay_step(
+    translation: Vec3,
+    velocity: Vec3,
+    input: &PredictedInput,
+    params: &ReplayParams,
+) -> (Vec3, Vec3) {
+    let mut velocity = velocity + input.impulse;
+    if input.grounded {
+        if input.jump {
+            velocity.y = params.jump_impulse;
+        } else {
+            velocity.y = velocity.y.max(0.0);
+        }
+        velocity = damp(velocity, params.damping);
+    } else {
+        velocity += params.gravity * params.delta_time;
+    }
+    velocity = accelerate(
+        velocity,
+        input.intent,
+        params.acceleration,
+        params.delta_time,
+    );
+
+    (translation + velocity * params.delta_time, velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ReplayParams {
+        ReplayParams {
+            acceleration: 50.0,
+            damping: 0.9,
+            jump_impulse: 7.0,
+            gravity: Vec3::new(0.0, -19.62, 0.0),
+            delta_time: 1.0 / 64.0,
+        }
+    }
+
+    fn predict(count: u32, start: Vec3) -> VecDeque<PredictedState> {
+        let input = PredictedInput {
+            intent: Vec3::NEG_Z,
+            jump: false,
+            grounded: true,
+            impulse: Vec3::ZERO,
+        };
+        let mut translation = start;
+        let mut velocity = Vec3::ZERO;
+        (0..count)
+            .map(|sequence| {
+                (translation, velocity) = replay_step(translation, velocity, &input, &params());
+                PredictedState {
+                    sequence,
+                    input,
+                    translation,
+                    velocity,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matching_state_is_not_corrected() {
+        let mut history = predict(10, Vec3::ZERO);
+        let acked = history[4];
+        let state = AuthoritativeState {
+            sequence: acked.sequence,
+            translation: acked.translation,
+            velocity: acked.velocity,
+        };
+        assert!(reconcile(&mut history, &state, &params()).is_none());
+        assert_eq!(history.len(), 5);
+        assert_eq!(history.front().unwrap().sequence, 5);
+    }
+
+    #[test]
+    fn test_replays_unacknowledged_inputs() {
+        let mut history = predict(10, Vec3::ZERO);
+        let expected = predict(10, Vec3::X);
+        let acked = expected[4];
+        let state = AuthoritativeState {
+            sequence: acked.sequence,
+            translation: acked.translation,
+            velocity: acked.velocity,
+        };
+        let (translation, velocity) = reconcile(&mut history, &state, &params()).unwrap();
+        assert!(translation.distance(expected[9].translation) < 1e-4);
+        assert!(velocity.distance(expected[9].velocity) < 1e-4);
+        assert!(history.back().unwrap().translation.distance(translation) < 1e-4);
+    }
+
+    #[test]
+    fn test_replays_recorded_knockback() {
+        let mut history = PredictionHistory(predict(10, Vec3::ZERO));
+        let acked = history.0[4];
+        history.record_impulse(6, Vec3::Y * 5.0);
+        let state = AuthoritativeState {
+            sequence: acked.sequence,
+            translation: acked.translation + Vec3::X,
+            velocity: acked.velocity,
+        };
+        let (translation, _) = reconcile(&mut history.0, &state, &params()).unwrap();
+        let expected = predict(10, Vec3::X)[9].translation;
+        // replayed on top of the server state instead of lost
+        assert!(translation.y > expected.y);
+    }
+
+    #[test]
+    fn test_sequence_wraps() {
+        assert!(sequence_newer(1, 0));
+        assert!(!sequence_newer(0, 1));
+        assert!(!sequence_newer(5, 5));
+        assert!(sequence_newer(2, u32::MAX - 1));
+        assert!(!sequence_newer(u32::MAX - 1, 2));
+    }
+}
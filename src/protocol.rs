@@ -0,0 +1,327 @@
+//! Versioning of the wire protocol. The token issuer puts the client's `Handshake` in the
+//! connect token `user_data`, the server checks it before spawning anything for that client.
+use std::{sync::OnceLock, time::Duration};
+
+use avian3d::prelude::LinearVelocity;
+use bevy::prelude::*;
+#[cfg(feature = "netcode")]
+use bevy_renet::netcode::NETCODE_USER_DATA_BYTES;
+use bincode::Options;
+use leafwing_input_manager::action_diff::ActionDiff;
+#[cfg(feature = "netcode")]
+use serde::Deserialize;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    character::Health,
+    chat::{ChatScope, ClientChat},
+    client::{
+        ClientAction, ClientChannel, ClientLookDirection, ClientMouseMovement, ClientSetting,
+        SnapshotAck,
+    },
+    codec::QuantizedPosition,
+    input::Action,
+    replication::{ComponentStates, ReplicationPlugin, ReplicationRegistry},
+    server::{LastInputSequence, Player, ServerChannel, ServerMessages, StampedMessage},
+    snapshot::{EntityDelta, SnapshotDelta},
+    water::Rocket,
+};
+
+/// Netcode protocol id, the same for every version of the game so that mismatched clients
+/// still connect and can be told why they are rejected.
+#[cfg(feature = "netcode")]
+pub const GAME_PROTOCOL_ID: u64 = 0x7761_7465_7200_0001;
+
+/// Hash of everything that goes over the wire, see `wire_samples`. Any change to the layout of
+/// a message, a replicated component or the channels changes it, and clients and servers only
+/// talk to the same version.
+pub fn protocol_version() -> u64 {
+    static VERSION: OnceLock<u64> = OnceLock::new();
+    *VERSION.get_or_init(|| fnv1a(&wire_samples()))
+}
+
+/// A serialized sample of every message, every variant and every replicated component, plus
+/// the channel configs. The samples use distinct values so that reordered fields show up too.
+fn wire_samples() -> Vec<u8> {
+    fn push(samples: &mut Vec<u8>, value: &impl Serialize) {
+        bincode::serialize_into(samples, value).unwrap();
+    }
+    let mut samples = Vec::new();
+    let pos = QuantizedPosition::new(Vec3::new(1.0, 2.0, 3.0));
+
+    let messages = [
+        ServerMessages::RocketExploded {
+            id: Entity::from_raw(1),
+            pos,
+        },
+        ServerMessages::RailgunShot {
+            id: 2,
+            start: pos,
+            end: QuantizedPosition::new(Vec3::new(4.0, 5.0, 6.0)),
+            target: Some(Entity::from_raw(3)),
+        },
+        ServerMessages::Chat {
+            id: 4,
+            name: "name".to_string(),
+            scope: ChatScope::Team,
+            text: "text".to_string(),
+        },
+        ServerMessages::ServerInfo { tick_rate: 28.0 },
+    ];
+    for message in messages {
+        // a new variant stops this from compiling until it has a sample above
+        match message {
+            ServerMessages::RocketExploded { .. }
+            | ServerMessages::RailgunShot { .. }
+            | ServerMessages::Chat { .. }
+            | ServerMessages::ServerInfo { .. } => {}
+        }
+        push(&mut samples, &StampedMessage { tick: 5, message });
+    }
+
+    push(
+        &mut samples,
+        &SnapshotDelta {
+            tick: 15,
+            baseline: Some(16),
+            changed: vec![EntityDelta {
+                entity: Entity::from_raw(17),
+                changed: component_samples().into_iter().collect(),
+                removed: vec![18],
+            }],
+            removed: vec![Entity::from_raw(19)],
+        },
+    );
+
+    let actions = [
+        Action::Forward,
+        Action::Left,
+        Action::Back,
+        Action::Right,
+        Action::Shoot,
+        Action::Railgun,
+        Action::Jump,
+    ];
+    for action in actions {
+        // same as the messages, a new action needs a sample
+        match action {
+            Action::Forward
+            | Action::Left
+            | Action::Back
+            | Action::Right
+            | Action::Shoot
+            | Action::Railgun
+            | Action::Jump => {}
+        }
+        let action_diffs = vec![ActionDiff::Pressed { action, value: 1.0 }];
+        push(
+            &mut samples,
+            &ClientAction {
+                sequence: 26,
+                action_diffs,
+            },
+        );
+    }
+    push(
+        &mut samples,
+        &ClientAction {
+            sequence: 27,
+            action_diffs: vec![ActionDiff::Released {
+                action: Action::Jump,
+            }],
+        },
+    );
+    push(
+        &mut samples,
+        &ClientMouseMovement {
+            rotation: Quat::from_rotation_y(2.0),
+        },
+    );
+    push(
+        &mut samples,
+        &ClientLookDirection {
+            dir: Vec3::new(20.0, 21.0, 22.0),
+            sequence: 23,
+        },
+    );
+    push(&mut samples, &SnapshotAck { tick: 24 });
+    push(&mut samples, &ClientSetting::UpdateRate(25.0));
+    push(
+        &mut samples,
+        &ClientSetting::InterpolationDelay(Duration::from_millis(26)),
+    );
+    for scope in [ChatScope::All, ChatScope::Team] {
+        let text = "text".to_string();
+        push(&mut samples, &ClientChat { scope, text });
+    }
+
+    let channels = format!(
+        "{:?}{:?}",
+        ClientChannel::channels_config(),
+        ServerChannel::channels_config()
+    );
+    samples.extend_from_slice(channels.as_bytes());
+    samples
+}
+
+/// Every replicated component captured through the registry, so their ids are covered too.
+fn component_samples() -> ComponentStates {
+    let mut app = App::new();
+    app.add_plugins(ReplicationPlugin);
+    let entity = app
+        .world_mut()
+        .spawn((
+            Player { id: 6 },
+            Transform::from_xyz(7.0, 8.0, 9.0).with_rotation(Quat::from_rotation_y(1.0)),
+            LinearVelocity(Vec3::new(10.0, 11.0, 12.0)),
+            Health(13),
+            LastInputSequence(14),
+            Rocket,
+        ))
+        .id();
+    app.world()
+        .resource::<ReplicationRegistry>()
+        .capture(&app.world().entity(entity))
+}
+
+/// Identifies the binary, only used to tell the player what to update to.
+#[cfg(feature = "netcode")]
+pub const BUILD_ID: &str = match option_env!("WATER_BUILD_ID") {
+    Some(build_id) => build_id,
+    None => env!("CARGO_PKG_VERSION"),
+};
+
+// upper bound on what decoding a single message may allocate, a forged length prefix can't
+// make us reserve gigabytes
+pub const MAX_MESSAGE_BYTES: u64 = 256 * 1024;
+
+// guards against random or empty user data being read as a handshake
+#[cfg(feature = "netcode")]
+const HANDSHAKE_MAGIC: [u8; 4] = *b"WTR1";
+// in chars, keeps the handshake well within the user data
+#[cfg(feature = "netcode")]
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Decodes a message written with `bincode::serialize`. Malformed, truncated or oversized
+/// messages are errors instead of panics.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_BYTES)
+        .deserialize(bytes)
+}
+
+#[cfg(feature = "netcode")]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u64,
+    pub build_id: String,
+    pub display_name: String,
+}
+
+#[cfg(feature = "netcode")]
+impl Handshake {
+    pub fn current(display_name: &str) -> Self {
+        Self {
+            protocol_version: protocol_version(),
+            build_id: BUILD_ID.to_string(),
+            display_name: display_name.chars().take(MAX_DISPLAY_NAME_LEN).collect(),
+        }
+    }
+
+    pub fn to_user_data(&self) -> [u8; NETCODE_USER_DATA_BYTES] {
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        let encoded = bincode::serialize(self).unwrap();
+        let len = HANDSHAKE_MAGIC.len() + encoded.len();
+        assert!(len <= NETCODE_USER_DATA_BYTES, "handshake too large");
+        user_data[..HANDSHAKE_MAGIC.len()].copy_from_slice(&HANDSHAKE_MAGIC);
+        user_data[HANDSHAKE_MAGIC.len()..len].copy_from_slice(&encoded);
+        user_data
+    }
+
+    pub fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Self> {
+        let payload = user_data.strip_prefix(&HANDSHAKE_MAGIC)?;
+        bincode::deserialize(payload).ok()
+    }
+
+    /// Why a client that sent `handshake` can't join this server, if it can't.
+    pub fn rejection_reason(handshake: Option<&Handshake>) -> Option<String> {
+        let Some(handshake) = handshake else {
+            return Some(format!(
+                "Your client is too old for this server (server build {BUILD_ID}), please update."
+            ));
+        };
+        if handshake.protocol_version == protocol_version() {
+            return None;
+        }
+        Some(format!(
+            "Version mismatch: your build {} (protocol {:016x}) can't join server build {} (protocol {:016x}).",
+            handshake.build_id,
+            handshake.protocol_version,
+            BUILD_ID,
+            protocol_version()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "netcode")]
+    #[test]
+    fn test_handshake_round_trip() {
+        let current = Handshake::current(&"🌊".repeat(100));
+        let handshake = Handshake::from_user_data(&current.to_user_data()).unwrap();
+        assert_eq!(handshake, current);
+        assert_eq!(Handshake::rejection_reason(Some(&handshake)), None);
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_messages() {
+        let encoded = bincode::serialize(&(7u32, vec![1u8, 2, 3])).unwrap();
+        assert_eq!(
+            decode::<(u32, Vec<u8>)>(&encoded).unwrap(),
+            (7, vec![1, 2, 3])
+        );
+        assert!(decode::<(u32, Vec<u8>)>(&encoded[..encoded.len() - 1]).is_err());
+
+        // a vec claiming u64::MAX elements
+        let mut forged = 7u32.to_le_bytes().to_vec();
+        forged.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(decode::<(u32, Vec<u8>)>(&forged).is_err());
+    }
+
+    #[cfg(feature = "netcode")]
+    #[test]
+    fn test_mismatch_is_rejected() {
+        // clients from before the handshake send empty user data
+        assert_eq!(
+            Handshake::from_user_data(&[0; NETCODE_USER_DATA_BYTES]),
+            None
+        );
+        assert!(Handshake::rejection_reason(None).is_some());
+
+        let handshake = Handshake {
+            protocol_version: protocol_version() ^ 1,
+            build_id: "old".to_string(),
+            display_name: "player".to_string(),
+        };
+        let reason = Handshake::rejection_reason(Some(&handshake)).unwrap();
+        assert!(reason.contains("old"));
+    }
+
+    #[test]
+    fn test_every_replicated_component_has_a_sample() {
+        let mut app = App::new();
+        app.add_plugins(ReplicationPlugin);
+        let registered = app.world().resource::<ReplicationRegistry>().len();
+        assert_eq!(component_samples().len(), registered);
+    }
+}
@@ -0,0 +1,400 @@
+//! Generic replication of server entities. Every entity with `Replicated` is part of the
+//! snapshots, along with each registered component it has. Registering a component is all it
+//! takes to replicate it: the server captures it, `snapshot.rs` delta encodes it, and the client
+//! spawns, updates and despawns its copies through `NetworkMapping`.
+//!
+//! Components are identified on the wire by their registration order, which is why both apps
+//! register them in `ReplicationPlugin`, and why `protocol::protocol_version` samples them.
+use std::collections::BTreeMap;
+
+use avian3d::prelude::LinearVelocity;
+use bevy::{prelude::*, utils::HashMap};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    character::Health,
+    codec::{quantize_health, QuantizedPosition, QuantizedRotation, QuantizedVelocity},
+    protocol::decode,
+    server::{LastInputSequence, NetworkedEntities, Player},
+    water::Rocket,
+};
+
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        // the order is the wire format, only ever append
+        app.init_resource::<ReplicationRegistry>()
+            .replicate::<Player>()
+            .replicate::<Transform>()
+            .replicate::<LinearVelocity>()
+            .replicate::<Health>()
+            .replicate::<LastInputSequence>()
+            .replicate::<Rocket>();
+    }
+}
+
+/// Server entities that are sent to clients.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Replicated;
+
+/// Index of a component in the `ReplicationRegistry`.
+pub type ComponentId = u8;
+
+/// Serialized state of every replicated component of one entity.
+pub type ComponentStates = BTreeMap<ComponentId, Vec<u8>>;
+
+/// A component that can be replicated, and what it looks like on the wire.
+pub trait Replicate: Component + Sized {
+    type State: Serialize + DeserializeOwned;
+
+    /// Server side, what to send.
+    fn capture(&self) -> Self::State;
+
+    /// Client side, called with the state from every applied snapshot. Components that are
+    /// predicted or interpolated hand it over instead of overwriting themselves.
+    fn write(state: Self::State, entity: &mut EntityWorldMut, tick: u32);
+}
+
+pub trait AppReplicationExt {
+    fn replicate<C: Replicate>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for App {
+    fn replicate<C: Replicate>(&mut self) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<ReplicationRegistry>()
+            .register::<C>();
+        self
+    }
+}
+
+struct ComponentFns {
+    name: &'static str,
+    capture: fn(&EntityRef) -> Option<Vec<u8>>,
+    write: fn(&[u8], &mut EntityWorldMut, u32) -> bincode::Result<()>,
+    remove: fn(&mut EntityWorldMut),
+}
+
+fn capture<C: Replicate>(entity: &EntityRef) -> Option<Vec<u8>> {
+    let component = entity.get::<C>()?;
+    Some(bincode::serialize(&component.capture()).unwrap())
+}
+
+fn write<C: Replicate>(
+    bytes: &[u8],
+    entity: &mut EntityWorldMut,
+    tick: u32,
+) -> bincode::Result<()> {
+    C::write(decode(bytes)?, entity, tick);
+    Ok(())
+}
+
+fn remove<C: Replicate>(entity: &mut EntityWorldMut) {
+    entity.remove::<C>();
+}
+
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry {
+    components: Vec<ComponentFns>,
+}
+
+impl ReplicationRegistry {
+    fn register<C: Replicate>(&mut self) {
+        assert!(
+            self.components.len() < ComponentId::MAX as usize,
+            "too many replicated components"
+        );
+        self.components.push(ComponentFns {
+            name: std::any::type_name::<C>(),
+            capture: capture::<C>,
+            write: write::<C>,
+            remove: remove::<C>,
+        });
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    /// Server side, the registered components `entity` has.
+    pub fn capture(&self, entity: &EntityRef) -> ComponentStates {
+        self.components
+            .iter()
+            .enumerate()
+            .filter_map(|(id, fns)| Some((id as ComponentId, (fns.capture)(entity)?)))
+            .collect()
+    }
+}
+
+/// Server entity to the client entity replicating it.
+#[derive(Resource, Debug, Default)]
+pub struct NetworkMapping(pub HashMap<Entity, Entity>);
+
+/// Snapshots decoded this frame, oldest first, waiting for `apply_snapshots`.
+#[derive(Resource, Debug, Default)]
+pub struct PendingSnapshots(pub Vec<NetworkedEntities>);
+
+/// Last snapshot written to the world, the next one is compared against it.
+#[derive(Resource, Debug, Default)]
+pub struct AppliedSnapshot(pub Option<NetworkedEntities>);
+
+/// Client side, brings the world in line with the snapshots received this frame. Late
+/// snapshots are dropped, the world only moves forward.
+pub fn apply_snapshots(world: &mut World) {
+    let snapshots = std::mem::take(&mut world.resource_mut::<PendingSnapshots>().0);
+    world.resource_scope(|world, registry: Mut<ReplicationRegistry>| {
+        world.resource_scope(|world, mut mapping: Mut<NetworkMapping>| {
+            world.resource_scope(|world, mut applied: Mut<AppliedSnapshot>| {
+                for snapshot in snapshots {
+                    if applied
+                        .0
+                        .as_ref()
+                        .is_some_and(|last| last.tick >= snapshot.tick)
+                    {
+                        continue;
+                    }
+                    apply_snapshot(
+                        world,
+                        &registry,
+                        &mut mapping,
+                        applied.0.as_ref(),
+                        &snapshot,
+                    );
+                    applied.0 = Some(snapshot);
+                }
+            });
+        });
+    });
+}
+
+fn apply_snapshot(
+    world: &mut World,
+    registry: &ReplicationRegistry,
+    mapping: &mut NetworkMapping,
+    previous: Option<&NetworkedEntities>,
+    snapshot: &NetworkedEntities,
+) {
+    if let Some(previous) = previous {
+        for server_entity in previous.entities.keys() {
+            if snapshot.entities.contains_key(server_entity) {
+                continue;
+            }
+            if let Some(entity) = mapping.0.remove(server_entity) {
+                if let Ok(entity) = world.get_entity_mut(entity) {
+                    entity.despawn_recursive();
+                }
+            }
+        }
+    }
+
+    for (server_entity, states) in snapshot.entities.iter() {
+        let entity = *mapping
+            .0
+            .entry(*server_entity)
+            .or_insert_with(|| world.spawn(Replicated).id());
+        let Ok(mut entity) = world.get_entity_mut(entity) else {
+            // despawned locally, e.g. a rocket that exploded, it stays that way until the server
+            // despawns it too
+            continue;
+        };
+
+        let removed = previous
+            .and_then(|previous| previous.entities.get(server_entity))
+            .into_iter()
+            .flat_map(|previous| previous.keys())
+            .filter(|id| !states.contains_key(id));
+        for fns in removed.filter_map(|id| registry.components.get(*id as usize)) {
+            (fns.remove)(&mut entity);
+        }
+        for (id, bytes) in states.iter() {
+            let Some(fns) = registry.components.get(*id as usize) else {
+                warn!("unknown replicated component {id}");
+                continue;
+            };
+            if let Err(err) = (fns.write)(bytes, &mut entity, snapshot.tick) {
+                warn!("malformed {} for {server_entity:?}: {err}", fns.name);
+            }
+        }
+    }
+}
+
+/// Where a replicated entity is according to the server. Predicted and interpolated entities
+/// read it instead of getting their `Transform` overwritten, see `client::route_server_motion`.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct ServerMotion {
+    pub tick: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+    pub input_sequence: u32,
+}
+
+fn server_motion<'w>(entity: &'w mut EntityWorldMut, tick: u32) -> Mut<'w, ServerMotion> {
+    if !entity.contains::<ServerMotion>() {
+        entity.insert(ServerMotion::default());
+    }
+    let mut motion = entity.get_mut::<ServerMotion>().unwrap();
+    motion.tick = tick;
+    motion
+}
+
+impl Replicate for Player {
+    type State = u64;
+
+    fn capture(&self) -> u64 {
+        self.id
+    }
+
+    fn write(id: u64, entity: &mut EntityWorldMut, _tick: u32) {
+        // only inserted once, `client::spawn_replicated_players` reacts to it being added
+        if !entity.contains::<Player>() {
+            entity.insert(Player { id });
+        }
+    }
+}
+
+impl Replicate for Transform {
+    type State = (QuantizedPosition, QuantizedRotation);
+
+    fn capture(&self) -> Self::State {
+        (
+            QuantizedPosition::new(self.translation),
+            QuantizedRotation::new(self.rotation),
+        )
+    }
+
+    fn write((translation, rotation): Self::State, entity: &mut EntityWorldMut, tick: u32) {
+        let (translation, rotation) = (translation.get(), rotation.get());
+        if !entity.contains::<Transform>() {
+            entity.insert(Transform::from_translation(translation).with_rotation(rotation));
+        }
+        let mut motion = server_motion(entity, tick);
+        motion.translation = translation;
+        motion.rotation = rotation;
+    }
+}
+
+impl Replicate for LinearVelocity {
+    type State = QuantizedVelocity;
+
+    fn capture(&self) -> QuantizedVelocity {
+        QuantizedVelocity::new(self.0)
+    }
+
+    fn write(velocity: QuantizedVelocity, entity: &mut EntityWorldMut, tick: u32) {
+        server_motion(entity, tick).velocity = velocity.get();
+    }
+}
+
+impl Replicate for Health {
+    type State = u8;
+
+    fn capture(&self) -> u8 {
+        quantize_health(self.0)
+    }
+
+    fn write(health: u8, entity: &mut EntityWorldMut, _tick: u32) {
+        // the health ui only updates on change
+        if entity.get::<Health>().map(|current| current.0) != Some(health as usize) {
+            entity.insert(Health(health as usize));
+        }
+    }
+}
+
+impl Replicate for LastInputSequence {
+    type State = u32;
+
+    fn capture(&self) -> u32 {
+        self.0
+    }
+
+    fn write(sequence: u32, entity: &mut EntityWorldMut, tick: u32) {
+        server_motion(entity, tick).input_sequence = sequence;
+    }
+}
+
+impl Replicate for Rocket {
+    type State = ();
+
+    fn capture(&self) {}
+
+    fn write(_: (), entity: &mut EntityWorldMut, _tick: u32) {
+        // `client::spawn_replicated_rockets` adds the visuals
+        if !entity.contains::<Rocket>() {
+            entity.insert(Rocket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(ReplicationPlugin)
+            .init_resource::<NetworkMapping>()
+            .init_resource::<PendingSnapshots>()
+            .init_resource::<AppliedSnapshot>();
+        app
+    }
+
+    #[test]
+    fn test_entities_are_spawned_updated_and_despawned() {
+        let mut server = app();
+        let server_entity = server
+            .world_mut()
+            .spawn((Replicated, Player { id: 7 }, Health(100)))
+            .id();
+        let capture = |server: &mut App, tick| {
+            let registry = server.world().resource::<ReplicationRegistry>();
+            let entity = server.world().entity(server_entity);
+            NetworkedEntities {
+                tick,
+                entities: [(server_entity, registry.capture(&entity))].into(),
+            }
+        };
+
+        let mut client = app();
+        let first = capture(&mut server, 1);
+        client
+            .world_mut()
+            .resource_mut::<PendingSnapshots>()
+            .0
+            .push(first);
+        client.world_mut().run_system_once(apply_snapshots).unwrap();
+        let entity = client.world().resource::<NetworkMapping>().0[&server_entity];
+        assert_eq!(client.world().get::<Player>(entity).unwrap().id, 7);
+        assert_eq!(client.world().get::<Health>(entity).unwrap().0, 100);
+
+        server
+            .world_mut()
+            .entity_mut(server_entity)
+            .remove::<Health>();
+        let second = capture(&mut server, 2);
+        client
+            .world_mut()
+            .resource_mut::<PendingSnapshots>()
+            .0
+            .push(second);
+        client.world_mut().run_system_once(apply_snapshots).unwrap();
+        assert!(client.world().get::<Health>(entity).is_none());
+
+        let empty = NetworkedEntities {
+            tick: 3,
+            ..default()
+        };
+        client
+            .world_mut()
+            .resource_mut::<PendingSnapshots>()
+            .0
+            .push(empty);
+        client.world_mut().run_system_once(apply_snapshots).unwrap();
+        assert!(client.world().get_entity(entity).is_err());
+        assert!(client.world().resource::<NetworkMapping>().0.is_empty());
+    }
+}
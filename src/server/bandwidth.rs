@@ -0,0 +1,201 @@
+//! Per client snapshot rate and bandwidth budget. Clients get snapshots at the rate they asked
+//! for, on fixed ticks, capped by `ServerSettings::send_rate`. Each entity with something new
+//! to send builds up priority, faster the more relevant it is, and every snapshot is filled with
+//! the highest priority entities until the budget runs out. The others keep what the client
+//! already has and catch up in a later snapshot.
+use std::{collections::BTreeMap, fmt};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+
+use crate::{consts::MIN_UPDATE_RATE, snapshot};
+
+use super::server::NetworkedEntities;
+
+/// Rates, priorities and usage of every client, see `ClientBandwidth::prioritize`.
+#[derive(Resource, Debug, Default)]
+pub struct SnapshotBandwidth(pub HashMap<ClientId, ClientBandwidth>);
+
+#[derive(Debug, Default)]
+pub struct ClientBandwidth {
+    // snapshots per second the client asked for, the server's send rate if it didn't
+    requested_rate: Option<f64>,
+    // snapshots per second it gets
+    rate: f64,
+    // tick the last snapshot was sent on
+    last_sent: Option<u32>,
+    // priority of the entities that are out of date on the client
+    priorities: HashMap<Entity, f32>,
+    pub usage: SnapshotUsage,
+}
+
+/// What went into the last snapshot of one client.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SnapshotUsage {
+    pub rate: f64,
+    pub bytes: usize,
+    pub budget: usize,
+    // entities that had changes but didn't fit
+    pub deferred: usize,
+}
+
+impl fmt::Display for SnapshotUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} Hz, {}/{} bytes, {} deferred",
+            self.rate, self.bytes, self.budget, self.deferred
+        )
+    }
+}
+
+impl ClientBandwidth {
+    /// Sets the rate the client asked for, capped in `due`.
+    pub fn request_rate(&mut self, rate: f64) {
+        if rate.is_finite() {
+            self.requested_rate = Some(rate);
+        }
+    }
+
+    /// Whether the client is due a snapshot on `tick`, and if so the seconds since the last one.
+    /// Clients get at least `MIN_UPDATE_RATE` and at most `max_rate` snapshots per second.
+    pub fn due(&mut self, tick: u32, tick_rate: f64, max_rate: f64) -> Option<f32> {
+        self.rate = self
+            .requested_rate
+            .map_or(max_rate, |rate| rate.clamp(MIN_UPDATE_RATE, max_rate));
+        self.usage.rate = self.rate;
+        let interval = (tick_rate / self.rate).round().max(1.0) as u32;
+        let elapsed = match self.last_sent {
+            Some(last_sent) if tick.wrapping_sub(last_sent) < interval => return None,
+            Some(last_sent) => tick.wrapping_sub(last_sent),
+            None => interval,
+        };
+        self.last_sent = Some(tick);
+        Some((elapsed as f64 / tick_rate) as f32)
+    }
+
+    /// The snapshot to send instead of `current`. Deferred entities are left as they are in
+    /// `baseline`, so the delta against it doesn't mention them. `weight` is how fast an
+    /// entity's priority grows per second.
+    pub fn prioritize(
+        &mut self,
+        baseline: Option<&NetworkedEntities>,
+        current: &NetworkedEntities,
+        elapsed: f32,
+        budget: usize,
+        weight: impl Fn(Entity) -> f32,
+    ) -> NetworkedEntities {
+        let mut entities = BTreeMap::new();
+        let mut candidates = Vec::new();
+        for (entity, states) in current.entities.iter() {
+            let previous = baseline.and_then(|baseline| baseline.entities.get(entity));
+            match snapshot::delta_size(*entity, previous, states) {
+                Some(size) => {
+                    let priority = self.priorities.entry(*entity).or_default();
+                    *priority += elapsed * weight(*entity);
+                    candidates.push((*entity, size, *priority));
+                }
+                None => {
+                    // already up to date
+                    self.priorities.remove(entity);
+                    entities.insert(*entity, states.clone());
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut usage = SnapshotUsage {
+            rate: self.rate,
+            budget,
+            ..default()
+        };
+        for (entity, size, _) in candidates {
+            // the most important entity always goes, even if it's bigger than the whole budget
+            if usage.bytes == 0 || usage.bytes + size <= budget {
+                usage.bytes += size;
+                self.priorities.remove(&entity);
+                entities.insert(entity, current.entities[&entity].clone());
+                continue;
+            }
+            usage.deferred += 1;
+            if let Some(previous) = baseline.and_then(|baseline| baseline.entities.get(&entity)) {
+                entities.insert(entity, previous.clone());
+            }
+        }
+        self.priorities
+            .retain(|entity, _| current.entities.contains_key(entity));
+        self.usage = usage;
+
+        NetworkedEntities {
+            tick: current.tick,
+            entities,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replication::ComponentStates;
+
+    use super::*;
+
+    fn snapshot(tick: u32, x: u8) -> NetworkedEntities {
+        let state = |id| -> ComponentStates { [(0, vec![x; 16]), (1, vec![id])].into() };
+        NetworkedEntities {
+            tick,
+            entities: (0..4)
+                .map(|id| (Entity::from_raw(id), state(id as u8)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_deferred_entities_catch_up() {
+        let mut bandwidth = ClientBandwidth::default();
+        let baseline = snapshot(1, 0);
+        let current = snapshot(2, 1);
+        let size = snapshot::delta_size(
+            Entity::from_raw(0),
+            baseline.entities.get(&Entity::from_raw(0)),
+            &current.entities[&Entity::from_raw(0)],
+        )
+        .unwrap();
+        // entity 3 is the most relevant, the budget fits two entities
+        let weight = |entity: Entity| 1.0 + entity.index() as f32;
+
+        let sent = bandwidth.prioritize(Some(&baseline), &current, 1.0, size * 2, weight);
+        assert_eq!(bandwidth.usage.deferred, 2);
+        assert_eq!(
+            sent.entities[&Entity::from_raw(3)],
+            current.entities[&Entity::from_raw(3)]
+        );
+        assert_eq!(
+            sent.entities[&Entity::from_raw(0)],
+            baseline.entities[&Entity::from_raw(0)]
+        );
+
+        // the deferred ones built up priority while the others start from zero
+        let sent = bandwidth.prioritize(Some(&sent), &current, 1.0, size * 2, weight);
+        assert_eq!(bandwidth.usage.deferred, 0);
+        assert_eq!(sent.entities, current.entities);
+    }
+
+    #[test]
+    fn test_snapshots_are_sent_at_the_requested_rate() {
+        let mut bandwidth = ClientBandwidth::default();
+        let sent = |ticks: std::ops::Range<u32>, bandwidth: &mut ClientBandwidth| {
+            ticks
+                .filter(|tick| bandwidth.due(*tick, 64.0, 32.0).is_some())
+                .count()
+        };
+        assert_eq!(sent(0..64, &mut bandwidth), 32);
+
+        bandwidth.request_rate(16.0);
+        assert_eq!(sent(64..128, &mut bandwidth), 16);
+        // capped by the server
+        bandwidth.request_rate(1000.0);
+        assert_eq!(sent(128..192, &mut bandwidth), 32);
+        bandwidth.request_rate(0.0);
+        assert_eq!(sent(192..320, &mut bandwidth), 2);
+    }
+}
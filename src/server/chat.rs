@@ -0,0 +1,136 @@
+//! Server side of the text chat. Messages on `ClientChannel::Chat` are sanitized, rate limited
+//! and relayed as `ServerMessages::Chat`, to everyone or to the sender's team. There are no team
+//! modes yet, players are split in two teams as they join so team chat has someone to talk to.
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+
+use crate::{
+    chat::{sanitize_chat, ChatScope, ClientChat},
+    consts::{CHAT_MESSAGES_PER_SEC, CHAT_MESSAGE_BURST},
+};
+
+use super::{validation::ViolationCounts, ServerMessages};
+
+const CHAT_TEAMS: u8 = 2;
+
+/// Name, team and chat allowance of every connected client.
+#[derive(Resource, Debug, Default)]
+pub struct ChatRoster(pub HashMap<ClientId, ChatMember>);
+
+#[derive(Debug)]
+pub struct ChatMember {
+    pub name: String,
+    pub team: u8,
+    // messages that can be sent right now
+    allowance: f32,
+    // server time of the last refill, in seconds
+    last_refill: f32,
+}
+
+impl ChatRoster {
+    /// Adds a client to the team with the fewest players.
+    pub fn join(&mut self, client_id: ClientId, name: String, now: f32) {
+        let team = (0..CHAT_TEAMS)
+            .min_by_key(|team| {
+                self.0
+                    .values()
+                    .filter(|member| member.team == *team)
+                    .count()
+            })
+            .unwrap_or_default();
+        self.0.insert(
+            client_id,
+            ChatMember {
+                name,
+                team,
+                allowance: CHAT_MESSAGE_BURST,
+                last_refill: now,
+            },
+        );
+    }
+
+    /// The message to relay for `chat`, `None` if it's dropped.
+    pub fn accept(
+        &mut self,
+        client_id: ClientId,
+        chat: ClientChat,
+        now: f32,
+        violations: &mut ViolationCounts,
+    ) -> Option<ServerMessages> {
+        let member = self.0.get_mut(&client_id)?;
+        member.allowance = (member.allowance + (now - member.last_refill) * CHAT_MESSAGES_PER_SEC)
+            .min(CHAT_MESSAGE_BURST);
+        member.last_refill = now;
+        if member.allowance < 1.0 {
+            violations.chat += 1;
+            return None;
+        }
+        // the client sanitizes too, anything it would have refused is a violation
+        let Some(text) = sanitize_chat(&chat.text) else {
+            violations.chat += 1;
+            return None;
+        };
+        member.allowance -= 1.0;
+        Some(ServerMessages::Chat {
+            id: client_id,
+            name: member.name.clone(),
+            scope: chat.scope,
+            text,
+        })
+    }
+
+    /// Who gets a message `sender` sent to `scope`, including the sender.
+    pub fn recipients(&self, sender: ClientId, scope: ChatScope) -> Vec<ClientId> {
+        let Some(team) = self.0.get(&sender).map(|member| member.team) else {
+            return Vec::new();
+        };
+        self.0
+            .iter()
+            .filter(|(_, member)| scope == ChatScope::All || member.team == team)
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(scope: ChatScope, text: &str) -> ClientChat {
+        ClientChat {
+            scope,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_chat_is_rate_limited_and_scoped() {
+        let mut roster = ChatRoster::default();
+        let mut violations = ViolationCounts::default();
+        for client_id in 0..3 {
+            roster.join(client_id, format!("player{client_id}"), 0.0);
+        }
+
+        for _ in 0..CHAT_MESSAGE_BURST as usize {
+            assert!(roster
+                .accept(0, chat(ChatScope::All, "hi"), 0.0, &mut violations)
+                .is_some());
+        }
+        assert!(roster
+            .accept(0, chat(ChatScope::All, "hi"), 0.0, &mut violations)
+            .is_none());
+        assert_eq!(violations.chat, 1);
+        let refill = 1.0 / CHAT_MESSAGES_PER_SEC;
+        assert!(roster
+            .accept(0, chat(ChatScope::All, "hi"), refill, &mut violations)
+            .is_some());
+        assert!(roster
+            .accept(1, chat(ChatScope::All, ""), refill, &mut violations)
+            .is_none());
+
+        let mut team = roster.recipients(0, ChatScope::Team);
+        team.sort();
+        assert_eq!(team, vec![0, 2]);
+        assert_eq!(roster.recipients(1, ChatScope::All).len(), 3);
+    }
+}
@@ -4,7 +4,6 @@ use super::server::*;
 use crate::camera::*;
 use crate::character::*;
 use crate::consts::*;
-use bevy_renet::renet::RenetServer;
 use std::time::Duration;
 
 #[derive(Component)]
@@ -15,18 +14,12 @@ pub struct DeathTimer {
 
 pub fn check_player_death(
     player_q: Query<(Entity, &Player, &Health, &Transform), With<PlayerMarker>>,
-    mut server: ResMut<RenetServer>,
     mut commands: Commands,
 ) {
     for (player_ent, player_id, health, player_tf) in player_q.iter() {
         if health.0 == 0 || player_tf.translation.y <= -20.0 {
+            // clients despawn their copy when it's missing from the next snapshot
             commands.entity(player_ent).despawn_recursive();
-            let message = bincode::serialize(&ServerMessages::PlayerDeath {
-                server_ent: player_ent,
-                id: player_id.id,
-            })
-            .unwrap();
-            server.broadcast_message(ServerChannel::ServerMessages, message);
 
             commands.spawn(DeathTimer {
                 timer: Timer::new(Duration::from_secs_f32(PLAYER_DEATH_TIMER), TimerMode::Once),
@@ -41,7 +34,6 @@ pub fn respawn_player(
     mut server_lobby: ResMut<ServerLobby>,
     time_fixed: Res<Time<Fixed>>,
     mut commands: Commands,
-    mut server: ResMut<RenetServer>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -52,7 +44,6 @@ pub fn respawn_player(
         if death_timer.timer.just_finished() {
             commands.entity(ent).despawn();
 
-            let transform = Transform::from_xyz(0.0, 1.5, 0.0);
             let player_entity = build_player_ent(
                 &mut commands,
                 &asset_server,
@@ -63,14 +54,6 @@ pub fn respawn_player(
             );
 
             server_lobby.players.insert(death_timer.id, player_entity);
-            let translation: [f32; 3] = transform.translation.into();
-            let message = bincode::serialize(&ServerMessages::PlayerCreate {
-                id: death_timer.id,
-                entity: player_entity,
-                translation,
-            })
-            .unwrap();
-            server.broadcast_message(ServerChannel::ServerMessages, message);
         }
     }
 }
@@ -0,0 +1,320 @@
+//! Server side rewind of player hitboxes, so hitscan shots are resolved against what the
+//! shooter saw instead of where the targets are now.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use avian3d::prelude::*;
+use bevy::{color::palettes::css::RED, ecs::system::SystemParam, prelude::*};
+use bevy_renet::renet::ClientId;
+
+use crate::{
+    camera::PlayerMarker,
+    character::Health,
+    clock::tick_duration,
+    codec::QuantizedPosition,
+    console::GameSettings,
+    consts::{
+        DEFAULT_INTERPOLATION_DELAY_MS, LAG_COMPENSATION_MAX_REWIND_MS, RAILGUN_DAMAGE,
+        RAILGUN_RANGE,
+    },
+    input::LookDirection,
+};
+
+use super::relevancy::RelevantMessages;
+use super::server::{handle_server_player_action, Player, ServerMessages, ServerTick};
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RailgunFired>()
+            .init_resource::<RewoundHitboxes>()
+            .init_resource::<InterpolationDelays>()
+            .add_systems(
+                FixedUpdate,
+                resolve_railgun_shots.after(handle_server_player_action),
+            )
+            .add_systems(FixedPostUpdate, record_pose_history.after(PhysicsSet::Sync))
+            .add_systems(Update, debug_rewound_hitboxes);
+    }
+}
+
+// the world camera of `build_player_ent` sits this high above the player origin
+pub const EYE_OFFSET: Vec3 = Vec3::new(0.0, 0.5, 0.0);
+
+#[derive(Event, Clone)]
+pub struct RailgunFired {
+    pub shooter: Entity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pose {
+    pub tick: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+}
+
+/// Where a player's collider was on the ticks a shot can be rewound to.
+#[derive(Component, Debug, Default)]
+pub struct PoseHistory(VecDeque<Pose>);
+
+impl PoseHistory {
+    /// Adds the newest pose and forgets the ones more than `max_age` ticks older than it.
+    pub fn record(&mut self, pose: Pose, max_age: u32) {
+        while self
+            .0
+            .front()
+            .is_some_and(|oldest| pose.tick.wrapping_sub(oldest.tick) > max_age)
+        {
+            self.0.pop_front();
+        }
+        self.0.push_back(pose);
+    }
+
+    /// Pose at `tick`, interpolated between the recorded ticks around it. Ticks outside of
+    /// the history get the oldest or newest pose.
+    pub fn at(&self, tick: f64) -> Option<(Vec3, Quat)> {
+        let index = self.0.partition_point(|pose| (pose.tick as f64) <= tick);
+        let (from, to) = match (index.checked_sub(1), self.0.get(index)) {
+            (Some(before), Some(after)) => (self.0[before], *after),
+            (Some(before), None) => return Some(pose_of(&self.0[before])),
+            (None, Some(after)) => return Some(pose_of(after)),
+            (None, None) => return None,
+        };
+        let t = ((tick - from.tick as f64) / (to.tick - from.tick) as f64) as f32;
+        Some((
+            from.translation.lerp(to.translation, t),
+            from.rotation.slerp(to.rotation, t),
+        ))
+    }
+}
+
+fn pose_of(pose: &Pose) -> (Vec3, Quat) {
+    (pose.translation, pose.rotation)
+}
+
+/// How many ticks to rewind for a shot from a client with the given round trip time, in
+/// seconds. The client renders other players half a RTT plus its interpolation delay behind
+/// the server, and the shot takes another half RTT to reach us.
+pub fn rewind_ticks(rtt: f64, interpolation_delay: f64, tick_duration: f64) -> f64 {
+    let max_rewind = LAG_COMPENSATION_MAX_REWIND_MS as f64 / 1000.0;
+    (rtt + interpolation_delay).min(max_rewind) / tick_duration
+}
+
+/// Ticks of poses needed to cover the longest rewind at the given tick duration.
+pub fn pose_history_ticks(tick_duration: f64) -> u32 {
+    let max_rewind = LAG_COMPENSATION_MAX_REWIND_MS as f64 / 1000.0;
+    // one more for the pose after the rewound tick, to interpolate to
+    (max_rewind / tick_duration).ceil() as u32 + 1
+}
+
+/// How far behind the server each client renders the other players, as it told us on the
+/// settings channel. Clients that didn't are assumed to use the default delay.
+#[derive(Resource, Debug, Default)]
+pub struct InterpolationDelays(pub HashMap<ClientId, Duration>);
+
+/// What a shot is rewound by besides the shooter's round trip time.
+#[derive(SystemParam)]
+struct RewindSettings<'w> {
+    time_fixed: Res<'w, Time<Fixed>>,
+    interpolation_delays: Res<'w, InterpolationDelays>,
+}
+
+impl RewindSettings<'_> {
+    /// Tick to rewind the targets to for a shot `client_id` fired on `tick`.
+    fn target_tick(&self, tick: u32, client_id: ClientId, rtt: f64) -> f64 {
+        let interpolation_delay = self
+            .interpolation_delays
+            .0
+            .get(&client_id)
+            .copied()
+            .unwrap_or(Duration::from_millis(DEFAULT_INTERPOLATION_DELAY_MS));
+        tick as f64
+            - rewind_ticks(
+                rtt,
+                interpolation_delay.as_secs_f64(),
+                tick_duration(&self.time_fixed),
+            )
+    }
+}
+
+/// Last resolved shot, drawn when `GameSettings::show_debug_hitboxes` is on.
+#[derive(Resource, Debug, Default)]
+pub struct RewoundHitboxes {
+    pub hitboxes: Vec<ColliderAabb>,
+    pub ray: Option<(Vec3, Vec3)>,
+}
+
+fn record_pose_history(
+    tick: Res<ServerTick>,
+    time_fixed: Res<Time<Fixed>>,
+    mut commands: Commands,
+    mut players_q: Query<(Entity, &Transform, Option<&mut PoseHistory>), With<PlayerMarker>>,
+) {
+    let max_age = pose_history_ticks(tick_duration(&time_fixed));
+    for (entity, player_tf, history) in players_q.iter_mut() {
+        let pose = Pose {
+            tick: tick.0,
+            translation: player_tf.translation,
+            rotation: player_tf.rotation,
+        };
+        match history {
+            Some(mut history) => history.record(pose, max_age),
+            None => {
+                let mut history = PoseHistory::default();
+                history.record(pose, max_age);
+                commands.entity(entity).insert(history);
+            }
+        }
+    }
+}
+
+fn resolve_railgun_shots(
+    mut railgun_fired: EventReader<RailgunFired>,
+    rewind: RewindSettings,
+    spatial_query: SpatialQuery,
+    shooters_q: Query<(&Player, &Transform, &LookDirection)>,
+    mut targets_q: Query<(Entity, &Collider, &PoseHistory, &mut Health), With<PlayerMarker>>,
+    mut rewound: ResMut<RewoundHitboxes>,
+    mut messages: RelevantMessages,
+) {
+    for ev in railgun_fired.read() {
+        let Ok((shooter, shooter_tf, look_direction)) = shooters_q.get(ev.shooter) else {
+            continue;
+        };
+        let Ok(direction) = Dir3::new(look_direction.0) else {
+            continue;
+        };
+        let origin = shooter_tf.translation + EYE_OFFSET;
+
+        let rtt = messages
+            .server
+            .network_info(shooter.id)
+            .map(|info| info.rtt)
+            .unwrap_or_default();
+        let target_tick = rewind.target_tick(messages.tick.0, shooter.id, rtt);
+
+        // players are rewound below, only the level can block the shot here
+        let players = SpatialQueryFilter::from_excluded_entities(
+            targets_q.iter().map(|(entity, _, _, _)| entity),
+        );
+        let max_distance = spatial_query
+            .cast_ray(origin, direction, RAILGUN_RANGE, true, &players)
+            .map_or(RAILGUN_RANGE, |hit| hit.distance);
+
+        rewound.hitboxes.clear();
+        let mut closest: Option<(Entity, f32)> = None;
+        for (entity, collider, history, _) in targets_q.iter() {
+            if entity == ev.shooter {
+                continue;
+            }
+            let Some((translation, rotation)) = history.at(target_tick) else {
+                continue;
+            };
+            rewound.hitboxes.push(collider.aabb(translation, rotation));
+            let Some((distance, _)) = collider.cast_ray(
+                translation,
+                rotation,
+                origin,
+                direction.into(),
+                max_distance,
+                true,
+            ) else {
+                continue;
+            };
+            if closest.is_none_or(|(_, closest_distance)| distance < closest_distance) {
+                closest = Some((entity, distance));
+            }
+        }
+
+        let distance = closest.map_or(max_distance, |(_, distance)| distance);
+        let end = origin + direction * distance;
+        rewound.ray = Some((origin, end));
+
+        let target = closest.map(|(entity, _)| entity);
+        if let Some(target) = target {
+            if let Ok((_, _, _, mut health)) = targets_q.get_mut(target) {
+                health.0 = health.0.saturating_sub(RAILGUN_DAMAGE);
+                debug!("railgun hit {:?}, health {}", target, health.0);
+            }
+        }
+
+        let message = ServerMessages::RailgunShot {
+            id: shooter.id,
+            start: QuantizedPosition::new(origin),
+            end: QuantizedPosition::new(end),
+            target,
+        };
+        // whoever sees the shooter, the victim or either end of the beam
+        let entities: Vec<Entity> = [ev.shooter].into_iter().chain(target).collect();
+        messages.send(message, &entities, &[origin, end]);
+    }
+}
+
+fn debug_rewound_hitboxes(
+    mut gizmos: Gizmos,
+    rewound: Res<RewoundHitboxes>,
+    settings: Res<GameSettings>,
+) {
+    if !settings.show_debug_hitboxes {
+        return;
+    }
+    for aabb in rewound.hitboxes.iter() {
+        gizmos.cuboid(
+            Transform::from_translation(aabb.center()).with_scale(aabb.size()),
+            RED,
+        );
+    }
+    if let Some((start, end)) = rewound.ray {
+        gizmos.line(start, end, RED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(tick: u32, x: f32) -> Pose {
+        Pose {
+            tick,
+            translation: Vec3::new(x, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+        }
+    }
+
+    #[test]
+    fn test_pose_is_interpolated() {
+        let mut history = PoseHistory::default();
+        history.record(pose(10, 0.0), 8);
+        history.record(pose(11, 1.0), 8);
+        let (translation, _) = history.at(10.25).unwrap();
+        assert!((translation.x - 0.25).abs() < 1e-5);
+        assert_eq!(history.at(4.0).unwrap().0.x, 0.0);
+        assert_eq!(history.at(20.0).unwrap().0.x, 1.0);
+    }
+
+    #[test]
+    fn test_rewind_is_clamped() {
+        let tick = 1.0 / 64.0;
+        assert!((rewind_ticks(0.05, 0.1, tick) - 9.6).abs() < 1e-9);
+        let max_ticks = LAG_COMPENSATION_MAX_REWIND_MS as f64 / 1000.0 / tick;
+        assert_eq!(rewind_ticks(2.0, 0.1, tick), max_ticks);
+    }
+
+    #[test]
+    fn test_pose_history_covers_max_rewind() {
+        for tick_rate in [30.0, 64.0, 128.0] {
+            let tick = 1.0 / tick_rate;
+            let max_age = pose_history_ticks(tick);
+            let mut history = PoseHistory::default();
+            for i in 0..1000 {
+                history.record(pose(i, i as f32), max_age);
+            }
+            let oldest = 999.0 - rewind_ticks(10.0, 0.1, tick);
+            assert!(history.0.front().unwrap().tick as f64 <= oldest.floor());
+            assert_eq!(history.0.len(), max_age as usize + 1);
+        }
+    }
+}
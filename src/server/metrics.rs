@@ -0,0 +1,462 @@
+//! Server metrics, for monitoring servers without the visualizer window. Every second a sample
+//! of the tick time, the player count, the `ServerMessages` sent by variant and the network stats
+//! of every client is taken. It is written to rotating files in `--metrics-dir` and served as
+//! Prometheus text on `--metrics-port`, each only when configured.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{ClientId, RenetServer};
+use serde::Serialize;
+
+use crate::config::{MetricsFormat, ServerSettings};
+
+use super::{ServerLobby, ServerMessages, ServerTick};
+
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+// the current file is rotated once it gets this big
+const METRICS_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// rotated files kept next to the current one, older ones are overwritten
+const METRICS_FILES_KEPT: usize = 5;
+// only the request line matters, anything past this is ignored
+const MAX_REQUEST_BYTES: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct MetricsPlugin;
+
+impl Plugin for MetricsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerMessageCounts>()
+            .init_resource::<TickTime>()
+            .add_systems(FixedFirst, start_tick)
+            .add_systems(FixedLast, end_tick);
+
+        let settings = app.world().resource::<ServerSettings>();
+        let file = settings.metrics_dir.as_ref().and_then(|dir| {
+            MetricsFile::open(dir, settings.metrics_format)
+                .inspect_err(|err| warn!("Not writing metrics to {}: {err}", dir.display()))
+                .ok()
+        });
+        let listener = settings.metrics_port.and_then(|port| {
+            bind_metrics_listener(port)
+                .inspect_err(|err| warn!("Not serving metrics on port {port}: {err}"))
+                .ok()
+        });
+        if file.is_none() && listener.is_none() {
+            return;
+        }
+        app.insert_resource(MetricsExporter {
+            timer: Timer::new(METRICS_INTERVAL, TimerMode::Repeating),
+            file,
+            listener,
+            latest: None,
+        })
+        .add_systems(Update, export_metrics);
+    }
+}
+
+/// `ServerMessages` sent since the server started, by variant, counted once per recipient.
+#[derive(Resource, Debug, Default)]
+pub struct ServerMessageCounts(pub BTreeMap<&'static str, u64>);
+
+impl ServerMessageCounts {
+    pub fn record(&mut self, message: &ServerMessages, recipients: usize) {
+        *self.0.entry(message.kind()).or_default() += recipients as u64;
+    }
+}
+
+/// Wall time spent in fixed ticks since the last sample.
+#[derive(Resource, Debug, Default)]
+struct TickTime {
+    start: Option<Instant>,
+    total: Duration,
+    max: Duration,
+    ticks: u32,
+}
+
+fn start_tick(mut tick_time: ResMut<TickTime>) {
+    tick_time.start = Some(Instant::now());
+}
+
+fn end_tick(mut tick_time: ResMut<TickTime>) {
+    let Some(start) = tick_time.start.take() else {
+        return;
+    };
+    let elapsed = start.elapsed();
+    tick_time.total += elapsed;
+    tick_time.max = tick_time.max.max(elapsed);
+    tick_time.ticks += 1;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSample {
+    /// seconds since the unix epoch
+    pub time: f64,
+    pub tick: u32,
+    /// mean and max over the ticks since the last sample
+    pub tick_time_ms: f64,
+    pub max_tick_time_ms: f64,
+    pub players: usize,
+    pub messages: BTreeMap<&'static str, u64>,
+    pub clients: Vec<ClientMetrics>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientMetrics {
+    pub id: ClientId,
+    pub rtt_ms: f64,
+    pub packet_loss: f64,
+    pub sent_bytes_per_second: f64,
+    pub received_bytes_per_second: f64,
+}
+
+impl MetricsSample {
+    /// The Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, f64)>| {
+            let _ = writeln!(
+                out,
+                "# HELP water_{name} {help}\n# TYPE water_{name} {kind}"
+            );
+            for (labels, value) in values {
+                let _ = writeln!(out, "water_{name}{labels} {value}");
+            }
+        };
+        let server = |value: f64| vec![(String::new(), value)];
+        let clients = |value: fn(&ClientMetrics) -> f64| -> Vec<(String, f64)> {
+            self.clients
+                .iter()
+                .map(|client| (format!("{{client=\"{}\"}}", client.id), value(client)))
+                .collect()
+        };
+
+        metric(
+            "tick",
+            "counter",
+            "Current server tick",
+            server(self.tick as f64),
+        );
+        metric(
+            "tick_time_seconds",
+            "gauge",
+            "Mean wall time of a tick over the last second",
+            server(self.tick_time_ms / 1000.0),
+        );
+        metric(
+            "tick_time_max_seconds",
+            "gauge",
+            "Longest tick over the last second",
+            server(self.max_tick_time_ms / 1000.0),
+        );
+        metric(
+            "players",
+            "gauge",
+            "Players in the game",
+            server(self.players as f64),
+        );
+        metric(
+            "server_messages_total",
+            "counter",
+            "Server messages sent, by variant",
+            self.messages
+                .iter()
+                .map(|(kind, count)| (format!("{{kind=\"{kind}\"}}"), *count as f64))
+                .collect(),
+        );
+        metric(
+            "client_rtt_seconds",
+            "gauge",
+            "Round trip time",
+            clients(|client| client.rtt_ms / 1000.0),
+        );
+        metric(
+            "client_packet_loss_ratio",
+            "gauge",
+            "Packets lost",
+            clients(|client| client.packet_loss),
+        );
+        metric(
+            "client_sent_bytes_per_second",
+            "gauge",
+            "Bytes sent to the client",
+            clients(|client| client.sent_bytes_per_second),
+        );
+        metric(
+            "client_received_bytes_per_second",
+            "gauge",
+            "Bytes received from the client",
+            clients(|client| client.received_bytes_per_second),
+        );
+        out
+    }
+
+    /// `time,metric,label,value` rows, the label is the client id or the message variant.
+    fn csv(&self) -> String {
+        let mut rows = vec![
+            ("tick", String::new(), self.tick as f64),
+            ("tick_time_ms", String::new(), self.tick_time_ms),
+            ("max_tick_time_ms", String::new(), self.max_tick_time_ms),
+            ("players", String::new(), self.players as f64),
+        ];
+        for (kind, count) in self.messages.iter() {
+            rows.push(("messages", kind.to_string(), *count as f64));
+        }
+        for client in self.clients.iter() {
+            let id = client.id.to_string();
+            rows.push(("rtt_ms", id.clone(), client.rtt_ms));
+            rows.push(("packet_loss", id.clone(), client.packet_loss));
+            rows.push((
+                "sent_bytes_per_second",
+                id.clone(),
+                client.sent_bytes_per_second,
+            ));
+            rows.push((
+                "received_bytes_per_second",
+                id,
+                client.received_bytes_per_second,
+            ));
+        }
+        rows.into_iter()
+            .map(|(metric, label, value)| format!("{:.3},{metric},{label},{value}\n", self.time))
+            .collect()
+    }
+
+    fn json(&self) -> String {
+        serde_json::to_string(self).unwrap() + "\n"
+    }
+}
+
+/// The current metrics file, `metrics.csv` or `metrics.json`. Rotated files get a number before
+/// the extension, `metrics.1.csv` being the most recent.
+struct MetricsFile {
+    dir: PathBuf,
+    format: MetricsFormat,
+    file: File,
+    len: u64,
+}
+
+impl MetricsFile {
+    fn open(dir: &Path, format: MetricsFormat) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut metrics_file = Self {
+            dir: dir.to_path_buf(),
+            format,
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(Self::path(dir, format, None))?,
+            len: 0,
+        };
+        metrics_file.len = metrics_file.file.metadata()?.len();
+        if metrics_file.len == 0 {
+            metrics_file.write_header()?;
+        }
+        Ok(metrics_file)
+    }
+
+    fn path(dir: &Path, format: MetricsFormat, index: Option<usize>) -> PathBuf {
+        let extension = match format {
+            MetricsFormat::Csv => "csv",
+            MetricsFormat::Json => "json",
+        };
+        match index {
+            Some(index) => dir.join(format!("metrics.{index}.{extension}")),
+            None => dir.join(format!("metrics.{extension}")),
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.format == MetricsFormat::Csv {
+            self.append("time,metric,label,value\n")?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, text: &str) -> io::Result<()> {
+        self.file.write_all(text.as_bytes())?;
+        self.len += text.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, sample: &MetricsSample) -> io::Result<()> {
+        let text = match self.format {
+            MetricsFormat::Csv => sample.csv(),
+            MetricsFormat::Json => sample.json(),
+        };
+        if self.len + text.len() as u64 > METRICS_FILE_MAX_BYTES {
+            self.rotate()?;
+        }
+        self.append(&text)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..METRICS_FILES_KEPT).rev() {
+            let from = Self::path(&self.dir, self.format, Some(index));
+            if from.exists() {
+                fs::rename(from, Self::path(&self.dir, self.format, Some(index + 1)))?;
+            }
+        }
+        let current = Self::path(&self.dir, self.format, None);
+        fs::rename(&current, Self::path(&self.dir, self.format, Some(1)))?;
+        self.file = File::create(current)?;
+        self.len = 0;
+        self.write_header()
+    }
+}
+
+fn bind_metrics_listener(port: u16) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    Ok(listener)
+}
+
+#[derive(Resource)]
+struct MetricsExporter {
+    timer: Timer,
+    file: Option<MetricsFile>,
+    listener: Option<TcpListener>,
+    // what scrapes get until the next sample
+    latest: Option<MetricsSample>,
+}
+
+fn export_metrics(
+    mut exporter: ResMut<MetricsExporter>,
+    time: Res<Time>,
+    server: Res<RenetServer>,
+    lobby: Res<ServerLobby>,
+    tick: Res<ServerTick>,
+    counts: Res<ServerMessageCounts>,
+    mut tick_time: ResMut<TickTime>,
+) {
+    if exporter.timer.tick(time.delta()).just_finished() {
+        let sample = MetricsSample {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            tick: tick.0,
+            tick_time_ms: (tick_time.total.as_secs_f64() * 1000.0)
+                / f64::from(tick_time.ticks.max(1)),
+            max_tick_time_ms: tick_time.max.as_secs_f64() * 1000.0,
+            players: lobby.players.len(),
+            messages: counts.0.clone(),
+            clients: server
+                .clients_id()
+                .into_iter()
+                .filter_map(|id| {
+                    let info = server.network_info(id).ok()?;
+                    Some(ClientMetrics {
+                        id,
+                        rtt_ms: info.rtt * 1000.0,
+                        packet_loss: info.packet_loss,
+                        sent_bytes_per_second: info.bytes_sent_per_second,
+                        received_bytes_per_second: info.bytes_received_per_second,
+                    })
+                })
+                .collect(),
+        };
+        // a tick could be running, e.g. when this runs from inside the fixed loop
+        let start = tick_time.start;
+        *tick_time = TickTime { start, ..default() };
+        if let Some(file) = exporter.file.as_mut() {
+            if let Err(err) = file.write(&sample) {
+                warn!("Failed to write metrics, not writing any more: {err}");
+                exporter.file = None;
+            }
+        }
+        exporter.latest = Some(sample);
+    }
+
+    let Some(listener) = exporter.listener.as_ref() else {
+        return;
+    };
+    while let Ok((stream, _)) = listener.accept() {
+        let body = exporter
+            .latest
+            .as_ref()
+            .map(MetricsSample::prometheus)
+            .unwrap_or_default();
+        if let Err(err) = answer_scrape(stream, &body) {
+            debug!("Failed to answer a metrics request: {err}");
+        }
+    }
+}
+
+fn answer_scrape(mut stream: TcpStream, body: &str) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = [0; MAX_REQUEST_BYTES];
+    let len = stream.read(&mut request)?;
+    let request_line = String::from_utf8_lossy(&request[..len]);
+    let response = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ),
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> MetricsSample {
+        MetricsSample {
+            time: 1.0,
+            tick: 64,
+            tick_time_ms: 2.0,
+            max_tick_time_ms: 4.0,
+            players: 1,
+            messages: [("RailgunShot", 3)].into(),
+            clients: vec![ClientMetrics {
+                id: 7,
+                rtt_ms: 50.0,
+                packet_loss: 0.01,
+                sent_bytes_per_second: 1000.0,
+                received_bytes_per_second: 200.0,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let text = sample().prometheus();
+        assert!(
+            text.contains("# TYPE water_tick_time_seconds gauge\nwater_tick_time_seconds 0.002\n")
+        );
+        assert!(text.contains("water_server_messages_total{kind=\"RailgunShot\"} 3\n"));
+        assert!(text.contains("water_client_rtt_seconds{client=\"7\"} 0.05\n"));
+    }
+
+    #[test]
+    fn test_files_are_rotated() {
+        let dir = std::env::temp_dir().join(format!("water-metrics-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut file = MetricsFile::open(&dir, MetricsFormat::Csv).unwrap();
+        let sample = sample();
+        let per_sample = sample.csv().len() as u64;
+        for _ in 0..=METRICS_FILE_MAX_BYTES / per_sample {
+            file.write(&sample).unwrap();
+        }
+
+        let current = fs::read_to_string(MetricsFile::path(&dir, MetricsFormat::Csv, None));
+        let rotated = fs::read_to_string(MetricsFile::path(&dir, MetricsFormat::Csv, Some(1)));
+        assert!(current.unwrap().starts_with("time,metric,label,value\n"));
+        assert!(rotated.unwrap().len() as u64 <= METRICS_FILE_MAX_BYTES);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
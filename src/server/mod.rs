@@ -1,5 +1,11 @@
+pub mod bandwidth;
+pub mod chat;
 pub mod death;
+pub mod lag_compensation;
+pub mod metrics;
+pub mod relevancy;
 pub mod server;
 pub mod server_camera;
+pub mod validation;
 
 pub use server::*;
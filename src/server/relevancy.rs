@@ -0,0 +1,210 @@
+//! Interest management. Every client is only sent the replicated entities near enough to its
+//! player and not hidden behind the map, and the messages about them, what it doesn't get can't
+//! be read out of the packets.
+//! Entities that stop being relevant drop out of the client's snapshots after a grace period,
+//! the snapshot delta tells the client to despawn them like any other removed entity.
+use std::time::Duration;
+
+use avian3d::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use bevy_renet::renet::{ClientId, RenetServer};
+
+use crate::{
+    consts::{RELEVANCY_DISTANCE, RELEVANCY_GRACE_SECS},
+    replication::Replicated,
+};
+
+use super::lag_compensation::EYE_OFFSET;
+use super::metrics::ServerMessageCounts;
+use super::server::{NetworkedEntities, ServerChannel, ServerLobby, ServerMessages, ServerTick};
+
+// a client's own player is always the most important thing to keep up to date
+const OWN_PLAYER_WEIGHT: f32 = 4.0;
+
+pub struct RelevancyPlugin;
+
+impl Plugin for RelevancyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Relevancy>()
+            .add_systems(FixedPostUpdate, update_relevancy.after(PhysicsSet::Sync));
+    }
+}
+
+/// What each client is allowed to know about.
+#[derive(Resource, Debug, Default)]
+pub struct Relevancy(pub HashMap<ClientId, RelevantSet>);
+
+#[derive(Debug, Default)]
+pub struct RelevantSet {
+    entities: HashMap<Entity, Relevance>,
+    // where the player last looked from, kept while it is dead
+    eye: Option<Vec3>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Relevance {
+    last_relevant: Duration,
+    weight: f32,
+}
+
+impl RelevantSet {
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.entities.contains_key(&entity)
+    }
+
+    /// How much the client cares about `entity`, used to prioritize snapshots. Entities in their
+    /// grace period keep the weight they had when they were last relevant.
+    pub fn weight(&self, entity: Entity) -> f32 {
+        self.entities
+            .get(&entity)
+            .map_or(0.0, |relevance| relevance.weight)
+    }
+
+    /// Marks `relevant` as seen at `now`, with their weight, and forgets the entities past their
+    /// grace period.
+    pub fn update(&mut self, now: Duration, relevant: impl IntoIterator<Item = (Entity, f32)>) {
+        for (entity, weight) in relevant {
+            let relevance = Relevance {
+                last_relevant: now,
+                weight,
+            };
+            self.entities.insert(entity, relevance);
+        }
+        let grace = Duration::from_secs_f32(RELEVANCY_GRACE_SECS);
+        self.entities
+            .retain(|_, relevance| now.saturating_sub(relevance.last_relevant) <= grace);
+    }
+
+    /// The part of `snapshot` this client gets.
+    pub fn filter(&self, snapshot: &NetworkedEntities) -> NetworkedEntities {
+        NetworkedEntities {
+            tick: snapshot.tick,
+            entities: snapshot
+                .entities
+                .iter()
+                .filter(|(entity, _)| self.contains(**entity))
+                .map(|(entity, states)| (*entity, states.clone()))
+                .collect(),
+        }
+    }
+}
+
+pub fn update_relevancy(
+    time_fixed: Res<Time<Fixed>>,
+    lobby: Res<ServerLobby>,
+    spatial_query: SpatialQuery,
+    replicated_q: Query<(Entity, &Transform), With<Replicated>>,
+    mut relevancy: ResMut<Relevancy>,
+) {
+    relevancy
+        .0
+        .retain(|client_id, _| lobby.players.contains_key(client_id));
+    // only the map blocks the view, not other players or rockets
+    let map_only =
+        SpatialQueryFilter::from_excluded_entities(replicated_q.iter().map(|(entity, _)| entity));
+    let now = time_fixed.elapsed();
+
+    for (client_id, player_entity) in lobby.players.iter() {
+        let set = relevancy.0.entry(*client_id).or_default();
+        if let Ok((_, player_tf)) = replicated_q.get(*player_entity) {
+            set.eye = Some(player_tf.translation + EYE_OFFSET);
+        }
+        let Some(eye) = set.eye else {
+            continue;
+        };
+
+        let relevant = replicated_q.iter().filter_map(|(entity, transform)| {
+            if entity == *player_entity {
+                return Some((entity, OWN_PLAYER_WEIGHT));
+            }
+            is_visible(&spatial_query, &map_only, eye, transform.translation).then(|| {
+                // closer is more important
+                let closeness = 1.0 - eye.distance(transform.translation) / RELEVANCY_DISTANCE;
+                (entity, 1.0 + closeness)
+            })
+        });
+        set.update(now, relevant);
+    }
+}
+
+/// Sends `ServerMessages` about something happening in the world only to the clients that could
+/// know about it, like the snapshots.
+#[derive(SystemParam)]
+pub struct RelevantMessages<'w, 's> {
+    pub server: ResMut<'w, RenetServer>,
+    pub tick: Res<'w, ServerTick>,
+    relevancy: Res<'w, Relevancy>,
+    spatial_query: SpatialQuery<'w, 's>,
+    replicated_q: Query<'w, 's, Entity, With<Replicated>>,
+    message_counts: ResMut<'w, ServerMessageCounts>,
+}
+
+impl RelevantMessages<'_, '_> {
+    /// Sends `message` to the clients one of `entities` is relevant to, or that can see one of
+    /// `points`.
+    pub fn send(&mut self, message: ServerMessages, entities: &[Entity], points: &[Vec3]) {
+        let map_only = SpatialQueryFilter::from_excluded_entities(self.replicated_q.iter());
+        let recipients: Vec<ClientId> = self
+            .relevancy
+            .0
+            .iter()
+            .filter(|(_, set)| {
+                entities.iter().any(|entity| set.contains(*entity))
+                    || set.eye.is_some_and(|eye| {
+                        points
+                            .iter()
+                            .any(|point| is_visible(&self.spatial_query, &map_only, eye, *point))
+                    })
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        self.message_counts.record(&message, recipients.len());
+        let message = self.tick.stamp(message);
+        for client_id in recipients {
+            self.server
+                .send_message(client_id, ServerChannel::ServerMessages, message.clone());
+        }
+    }
+}
+
+/// Close enough and in line of sight of either the origin or the head height of `target`.
+fn is_visible(
+    spatial_query: &SpatialQuery,
+    filter: &SpatialQueryFilter,
+    eye: Vec3,
+    target: Vec3,
+) -> bool {
+    if eye.distance(target) > RELEVANCY_DISTANCE {
+        return false;
+    }
+    [target, target + EYE_OFFSET].into_iter().any(|point| {
+        let Ok((direction, distance)) = Dir3::new_and_length(point - eye) else {
+            return true;
+        };
+        spatial_query
+            .cast_ray(eye, direction, distance, true, filter)
+            .is_none()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entities_stay_relevant_for_the_grace_period() {
+        let (near, far) = (Entity::from_raw(1), Entity::from_raw(2));
+        let grace = Duration::from_secs_f32(RELEVANCY_GRACE_SECS);
+        let mut set = RelevantSet::default();
+        set.update(Duration::ZERO, [(near, 2.0), (far, 1.0)]);
+
+        set.update(grace, [(near, 2.0)]);
+        assert!(set.contains(near));
+        assert!(set.contains(far));
+        assert_eq!(set.weight(far), 1.0);
+
+        set.update(grace * 2, [(near, 2.0)]);
+        assert!(set.contains(near));
+        assert!(!set.contains(far));
+    }
+}
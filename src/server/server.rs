@@ -1,19 +1,20 @@
 use avian3d::{
     math::{Scalar, Vector3},
     parry::utils::hashmap::HashMap,
-    prelude::{Collider, LinearVelocity, RigidBody},
+    prelude::{Collider, LinearVelocity, PhysicsSet, RigidBody},
 };
 use bevy_egui::EguiContexts;
 use leafwing_input_manager::prelude::ActionState;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, VecDeque},
     net::UdpSocket,
     time::{Duration, SystemTime},
 };
 
-use bevy::{prelude::*, time::common_conditions::on_timer};
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_renet::{
-    netcode::{NetcodeServerPlugin, NetcodeServerTransport, ServerAuthentication, ServerConfig},
+    netcode::{ServerAuthentication, ServerConfig},
     renet::{
         ChannelConfig, ClientId, ConnectionConfig, DefaultChannel, RenetServer, SendType,
         ServerEvent,
@@ -22,44 +23,82 @@ use bevy_renet::{
 };
 
 use crate::{
-    camera::{CameraSensitivity, PlayerMarker},
+    camera::PlayerMarker,
     character::*,
-    client::{ClientAction, ClientChannel, ClientLookDirection, ClientMouseMovement},
-    consts::{PLAYER_DEATH_TIMER, ROCKET_SPEED},
+    chat::{ChatScope, ClientChat},
+    client::{
+        ClientAction, ClientChannel, ClientLookDirection, ClientMouseMovement, ClientSetting,
+        SnapshotAck,
+    },
+    clock::tick_duration,
+    codec::QuantizedPosition,
+    config::ServerSettings,
+    consts::{
+        MAX_BUFFERED_INPUTS, MAX_LOOK_MESSAGES_PER_SECOND, MAX_MALFORMED_MESSAGES, MAX_TURN_SPEED,
+        ROCKET_SPEED,
+    },
     input::{Action, LookDirection, MovementIntent},
-    water::GameState,
-    water::Rocket,
+    prediction::sequence_newer,
+    protocol::decode,
+    replication::{ComponentStates, Replicated, ReplicationPlugin, ReplicationRegistry},
+    snapshot::{self, ClientBaseline},
+    water::{rocket_visuals, GameState},
     AppState,
 };
 use leafwing_input_manager::prelude::*;
 
 use crate::network_visualizer::visualizer::RenetServerVisualizer;
 
+use super::bandwidth::SnapshotBandwidth;
+use super::chat::ChatRoster;
 use super::death::*;
+use super::lag_compensation::{InterpolationDelays, LagCompensationPlugin, RailgunFired};
+use super::metrics::{MetricsPlugin, ServerMessageCounts};
+use super::relevancy::{update_relevancy, Relevancy, RelevancyPlugin};
 use super::server_camera::*;
+use super::validation::{validate_look_direction, validate_rotation, ValidationViolations};
 
 pub struct ServerPlugin;
 
 impl Plugin for ServerPlugin {
     fn build(&self, mut app: &mut App) {
         app.add_plugins(RenetServerPlugin);
+        app.add_plugins(ReplicationPlugin);
 
         app.insert_resource(ServerLobby::default());
+        app.init_resource::<ServerTick>();
+        app.init_resource::<SnapshotBaselines>();
+        app.init_resource::<SnapshotBandwidth>();
+        app.init_resource::<ChatRoster>();
+        app.init_resource::<PendingRejections>();
+        app.init_resource::<MessageStrikes>();
+        app.init_resource::<ValidationViolations>();
+        app.add_systems(FixedFirst, advance_server_tick);
 
         app.add_plugins(InputManagerPlugin::<Action>::server());
+        app.add_plugins(LagCompensationPlugin);
+        app.add_plugins(RelevancyPlugin);
+        app.add_plugins(MetricsPlugin);
 
-        app.add_systems(OnEnter(GameState::Game), spawn_camera);
         // todo: the server starts at startup, but it should start when choosing the option to host
         #[cfg(feature = "netcode")]
         add_netcode_network(&mut app);
 
         #[cfg(feature = "steam")]
         add_steam_network(&mut app);
-        app.add_systems(
-            Update,
-            (server_camera_controller, server_camera_look).run_if(in_state(AppState::Main)),
-        );
-        app.insert_resource(RenetServerVisualizer::<200>::default());
+        let settings = app.world().resource::<ServerSettings>();
+        let mut visualizer = RenetServerVisualizer::<200>::default();
+        visualizer.set_rates(settings.tick_rate, settings.send_rate);
+        app.insert_resource(visualizer);
+        // nothing to look at without a window
+        if !app.world().resource::<ServerSettings>().headless {
+            app.add_systems(OnEnter(GameState::Game), spawn_camera);
+            app.add_systems(
+                Update,
+                (server_camera_controller, server_camera_look).run_if(in_state(AppState::Main)),
+            );
+            app.add_systems(Update, update_visualizer_system);
+        }
 
         app.add_systems(
             FixedUpdate,
@@ -67,8 +106,11 @@ impl Plugin for ServerPlugin {
                 check_player_death,
                 respawn_player,
                 handle_events_system,
+                send_server_info.after(handle_events_system),
+                receive_client_messages.after(handle_events_system),
+                relay_chat.after(handle_events_system),
                 handle_server_player_action,
-                server_mouse,
+                server_mouse.after(receive_client_messages),
                 tick_shoot_cooldown, //server_network_sync,
             ), //.after(handle_events_system)
                //.chain(),
@@ -76,40 +118,77 @@ impl Plugin for ServerPlugin {
         //app.add_systems(FixedUpdate, server_mouse.after(handle_events_system));
 
         //https://www.reddit.com/r/gamedev/comments/4eigzo/generally_how_often_do_most_realtime_multiplayer/
+        // on fixed ticks, once the tick is simulated and relevancy is up to date, each client at
+        // its own rate, see `ClientBandwidth::due`
         app.add_systems(
-            Update,
-            server_network_sync.run_if(on_timer(Duration::from_millis(50))),
+            FixedPostUpdate,
+            server_network_sync
+                .after(PhysicsSet::Sync)
+                .after(update_relevancy),
         );
 
-        app.add_systems(Update, update_visualizer_system);
+        app.add_systems(Update, disconnect_rejected_clients);
 
-        app.add_systems(PreUpdate, update_client_input_state);
-        app.add_systems(FixedUpdate, read_client_input_state.before(movement_2));
+        app.add_systems(
+            FixedUpdate,
+            (update_client_input_state, read_client_input_state)
+                .chain()
+                .after(receive_client_messages)
+                .before(movement_2),
+        );
 
         app.add_event::<ServerPlayerAction>();
+        app.add_event::<FromClient<ClientAction<Action>>>();
+        app.add_event::<FromClient<ClientMouseMovement>>();
     }
 }
 
 #[cfg(feature = "netcode")]
 fn add_netcode_network(app: &mut App) {
-    app.add_plugins(NetcodeServerPlugin);
+    use crate::auth::{issuer_addr, load_or_create_private_key, spawn_token_issuer};
+    use crate::discovery::LanBeaconPlugin;
+    use crate::protocol::GAME_PROTOCOL_ID;
+    use crate::transport::{ConditionedServerPlugin, ConditionedServerTransport};
+
+    app.add_plugins(ConditionedServerPlugin);
 
+    let settings = app.world().resource::<ServerSettings>().clone();
     let server = RenetServer::new(connection_config());
-    let server_addr = "127.0.0.1:5000".parse().unwrap();
+    let server_addr = settings.addr;
     let socket = UdpSocket::bind(server_addr).unwrap();
+    let game_addr = socket.local_addr().unwrap();
+    let private_key = match load_or_create_private_key(&settings.private_key) {
+        Ok(private_key) => private_key,
+        Err(err) => {
+            eprintln!(
+                "Failed to load the private key {}: {err}",
+                settings.private_key.display()
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = spawn_token_issuer(game_addr, settings.public_addr, private_key) {
+        eprintln!(
+            "Failed to start the token issuer on {}: {err}",
+            issuer_addr(game_addr)
+        );
+        std::process::exit(1);
+    }
     let server_config = ServerConfig {
         current_time: SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap(),
-        max_clients: 64,
-        protocol_id: 0,
-        public_addresses: vec![server_addr],
-        authentication: ServerAuthentication::Unsecure,
+        max_clients: settings.max_clients,
+        protocol_id: GAME_PROTOCOL_ID,
+        public_addresses: vec![settings.public_addr],
+        authentication: ServerAuthentication::Secure { private_key },
     };
 
-    let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
+    let transport = ConditionedServerTransport::new(server_config, socket).unwrap();
     app.insert_resource(server);
     app.insert_resource(transport);
+    app.add_plugins(LanBeaconPlugin { game_addr });
+    app.add_systems(FixedUpdate, check_handshakes.before(handle_events_system));
 }
 
 #[cfg(feature = "steam")]
@@ -158,9 +237,12 @@ fn receive_message_system(mut server: ResMut<RenetServer>) {
         }
     }
 }
+#[derive(Debug, Clone, Copy)]
 pub enum ServerChannel {
     ServerMessages,
     NetworkedEntities,
+    // plain UTF-8 reason, never change its id or format so any version can read it
+    Rejection,
 }
 
 impl From<ServerChannel> for u8 {
@@ -168,6 +250,7 @@ impl From<ServerChannel> for u8 {
         match channel_id {
             ServerChannel::ServerMessages => 0,
             ServerChannel::NetworkedEntities => 1,
+            ServerChannel::Rejection => 2,
         }
     }
 }
@@ -187,37 +270,69 @@ impl ServerChannel {
                 max_memory_usage_bytes: 10 * 1024 * 1024,
                 send_type: SendType::Unreliable,
             },
+            ChannelConfig {
+                channel_id: Self::Rejection.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
         ]
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Component)]
+#[derive(Debug, Clone, Serialize, Deserialize, Component)]
 pub enum ServerMessages {
-    PlayerCreate {
-        entity: Entity,
-        id: ClientId,
-        translation: [f32; 3],
+    // the rocket is `id` until its entity is gone from the snapshots
+    RocketExploded {
+        id: Entity,
+        pos: QuantizedPosition,
     },
-    PlayerRemove {
+    RailgunShot {
         id: ClientId,
+        start: QuantizedPosition,
+        end: QuantizedPosition,
+        // server entity of the player that was hit
+        target: Option<Entity>,
     },
-    BulletCreate {
-        translation: [f32; 3],
-        dir: [f32; 3], // normalized
+    Chat {
+        id: ClientId,
+        name: String,
+        scope: ChatScope,
+        text: String,
     },
-    PlayerDeath {
-        server_ent: Entity,
-        id: u64,
+    // sent once on connecting, clients run their fixed ticks at the server's rate
+    ServerInfo {
+        tick_rate: f64,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+impl ServerMessages {
+    /// Name of the variant, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::RocketExploded { .. } => "RocketExploded",
+            Self::RailgunShot { .. } => "RailgunShot",
+            Self::Chat { .. } => "Chat",
+            Self::ServerInfo { .. } => "ServerInfo",
+        }
+    }
+}
+
+/// Wraps every message on `ServerChannel::ServerMessages` with the tick it was sent on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StampedMessage<T> {
+    pub tick: u32,
+    pub message: T,
+}
+
+/// Full state of every `Replicated` entity at one server tick. Sent over the wire as a
+/// `SnapshotDelta` against a snapshot the client acknowledged.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct NetworkedEntities {
-    pub entities: Vec<Entity>,
-    pub translations: Vec<[f32; 3]>,
-    pub rotations: Vec<[f32; 4]>,
-    pub velocities: Vec<[f32; 3]>,
-    pub health: Vec<usize>,
+    // server tick the snapshot was taken on
+    pub tick: u32,
+    pub entities: BTreeMap<Entity, ComponentStates>,
 }
 
 #[derive(Debug, Component)]
@@ -228,6 +343,63 @@ pub struct Player {
 #[derive(Debug, Component)]
 pub struct WeaponCooldown(pub Timer);
 
+#[derive(Debug, Component)]
+pub struct RailgunCooldown(pub Timer);
+
+/// Input sequence of the last `ClientAction` applied to this player, the snapshots tell its
+/// client which predicted tick to compare against.
+#[derive(Debug, Component, Default)]
+pub struct LastInputSequence(pub u32);
+
+/// Inputs received from the client controlling this player, one is simulated per tick so every
+/// acked sequence is a tick the client predicted on its own.
+#[derive(Debug, Component, Default)]
+pub struct PendingInputs(pub VecDeque<ClientAction<Action>>);
+
+/// Input sequence of the last look direction applied to this player.
+#[derive(Debug, Component, Default)]
+pub struct LastLookSequence(pub u32);
+
+/// Number of fixed ticks simulated since the server started.
+#[derive(Debug, Default, Resource)]
+pub struct ServerTick(pub u32);
+
+impl ServerTick {
+    /// Serializes `message` for `ServerChannel::ServerMessages`, stamped with the current tick.
+    pub fn stamp(&self, message: ServerMessages) -> Vec<u8> {
+        bincode::serialize(&StampedMessage {
+            tick: self.0,
+            message,
+        })
+        .unwrap()
+    }
+}
+
+/// Clients that failed the handshake, disconnected once the timer gave the rejection reason
+/// time to be delivered.
+#[derive(Debug, Default, Resource)]
+pub struct PendingRejections(pub HashMap<ClientId, Timer>);
+
+fn disconnect_rejected_clients(
+    mut server: ResMut<RenetServer>,
+    mut rejections: ResMut<PendingRejections>,
+    time: Res<Time>,
+) {
+    for (client_id, timer) in rejections.0.iter_mut() {
+        if timer.tick(time.delta()).just_finished() {
+            server.disconnect(*client_id);
+        }
+    }
+}
+
+fn advance_server_tick(mut tick: ResMut<ServerTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+/// Per client baselines used to delta encode snapshots.
+#[derive(Debug, Default, Resource)]
+pub struct SnapshotBaselines(pub HashMap<ClientId, ClientBaseline>);
+
 #[derive(Debug, Default, Resource)]
 pub struct ServerLobby {
     pub players: HashMap<ClientId, Entity>,
@@ -235,13 +407,16 @@ pub struct ServerLobby {
 
 fn handle_events_system(
     mut server_events: EventReader<ServerEvent>,
-    mut server: ResMut<RenetServer>,
-    mut players: Query<(Entity, &Player, &Transform, &mut LookDirection)>,
     mut lobby: ResMut<ServerLobby>,
     mut commands: Commands,
-    mut movement_event_writer: EventWriter<ClientAction<Action>>,
-    mut mouse_event_writer: EventWriter<ClientMouseMovement>,
     mut visualizer: ResMut<RenetServerVisualizer<200>>,
+    mut baselines: ResMut<SnapshotBaselines>,
+    mut rejections: ResMut<PendingRejections>,
+    mut strikes: ResMut<MessageStrikes>,
+    mut violations: ResMut<ValidationViolations>,
+    mut chat_roster: ResMut<ChatRoster>,
+    mut interpolation_delays: ResMut<InterpolationDelays>,
+    time: Res<Time>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -250,20 +425,22 @@ fn handle_events_system(
         match event {
             ServerEvent::ClientConnected { client_id } => {
                 debug!("Client {client_id} connected");
+                // turned away by `check_handshakes`
+                if rejections.0.contains_key(client_id) {
+                    continue;
+                }
                 visualizer.add_client(*client_id);
-
-                // Initialize other players for this new client
-                for (entity, player, transform, _) in players.iter() {
-                    let translation: [f32; 3] = transform.translation.into();
-                    let message = bincode::serialize(&ServerMessages::PlayerCreate {
-                        id: player.id,
-                        entity,
-                        translation,
-                    })
-                    .unwrap();
-                    server.send_message(*client_id, ServerChannel::ServerMessages, message);
+                baselines.0.insert(*client_id, ClientBaseline::default());
+                // steam clients don't send a handshake
+                if !chat_roster.0.contains_key(client_id) {
+                    chat_roster.join(
+                        *client_id,
+                        format!("Player {client_id}"),
+                        time.elapsed_secs(),
+                    );
                 }
 
+                // everything else the client needs comes with its first, full snapshot
                 let player_entity = build_player_ent(
                     &mut commands,
                     &asset_server,
@@ -274,72 +451,360 @@ fn handle_events_system(
                 );
 
                 lobby.players.insert(*client_id, player_entity);
-                let translation: [f32; 3] = Vec3::new(0.0, 1.5, 0.0).into();
-                let message = bincode::serialize(&ServerMessages::PlayerCreate {
-                    id: *client_id,
-                    entity: player_entity,
-                    translation,
-                })
-                .unwrap();
-                server.broadcast_message(ServerChannel::ServerMessages, message);
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 debug!("Client {client_id} disconnected: {reason}");
+                strikes.0.remove(client_id);
+                violations.0.remove(client_id);
+                chat_roster.0.remove(client_id);
+                interpolation_delays.0.remove(client_id);
+                if rejections.0.remove(client_id).is_some() {
+                    continue;
+                }
                 visualizer.remove_client(*client_id);
+                baselines.0.remove(client_id);
                 if let Some(player_entity) = lobby.players.remove(client_id) {
                     if let Some(commands) = commands.get_entity(player_entity) {
                         commands.try_despawn_recursive();
                     }
                 }
+            }
+        }
+    }
+}
+
+/// Checks the handshake netcode clients put in their connect token before `handle_events_system`
+/// spawns anything for them. Steam connections carry no user data, they aren't checked.
+#[cfg(feature = "netcode")]
+fn check_handshakes(
+    mut server_events: EventReader<ServerEvent>,
+    mut server: ResMut<RenetServer>,
+    transport: Res<crate::transport::ConditionedServerTransport>,
+    mut rejections: ResMut<PendingRejections>,
+    mut chat_roster: ResMut<ChatRoster>,
+    time: Res<Time>,
+) {
+    use crate::{consts::REJECTION_DISCONNECT_DELAY, protocol::Handshake};
+
+    for event in server_events.read() {
+        let ServerEvent::ClientConnected { client_id } = event else {
+            continue;
+        };
+        let handshake = transport
+            .user_data(*client_id)
+            .and_then(|user_data| Handshake::from_user_data(&user_data));
+        if let Some(handshake) = handshake.as_ref() {
+            debug!("Client {client_id} is {}", handshake.display_name);
+            chat_roster.join(
+                *client_id,
+                handshake.display_name.clone(),
+                time.elapsed_secs(),
+            );
+        }
+        if let Some(reason) = Handshake::rejection_reason(handshake.as_ref()) {
+            info!("Rejecting client {client_id}: {reason}");
+            server.send_message(*client_id, ServerChannel::Rejection, reason);
+            rejections.0.insert(
+                *client_id,
+                Timer::from_seconds(REJECTION_DISCONNECT_DELAY, TimerMode::Once),
+            );
+        }
+    }
+}
+
+/// Tells the clients that just connected what they need before their first tick.
+fn send_server_info(
+    mut server_events: EventReader<ServerEvent>,
+    mut server: ResMut<RenetServer>,
+    rejections: Res<PendingRejections>,
+    settings: Res<ServerSettings>,
+    tick: Res<ServerTick>,
+    mut message_counts: ResMut<ServerMessageCounts>,
+) {
+    for event in server_events.read() {
+        let ServerEvent::ClientConnected { client_id } = event else {
+            continue;
+        };
+        if rejections.0.contains_key(client_id) {
+            continue;
+        }
+        let message = ServerMessages::ServerInfo {
+            tick_rate: settings.tick_rate,
+        };
+        message_counts.record(&message, 1);
+        server.send_message(
+            *client_id,
+            ServerChannel::ServerMessages,
+            tick.stamp(message),
+        );
+    }
+}
 
-                let message =
-                    bincode::serialize(&ServerMessages::PlayerRemove { id: *client_id }).unwrap();
-                server.broadcast_message(ServerChannel::ServerMessages, message);
+/// Malformed messages received from each client, past `MAX_MALFORMED_MESSAGES` the client
+/// gets disconnected.
+#[derive(Debug, Default, Resource)]
+pub struct MessageStrikes(pub HashMap<ClientId, u32>);
+
+impl MessageStrikes {
+    /// Decodes a message from `client_id`, counting a strike if it is malformed.
+    fn decode<T: DeserializeOwned>(
+        &mut self,
+        client_id: ClientId,
+        channel: ClientChannel,
+        message: &[u8],
+    ) -> Option<T> {
+        match decode(message) {
+            Ok(decoded) => Some(decoded),
+            Err(err) => {
+                let strikes = self.0.entry(client_id).or_default();
+                *strikes += 1;
+                warn!("Malformed {channel:?} message from client {client_id} (strike {strikes}): {err}");
+                None
             }
         }
     }
 
+    fn exceeded(&self, client_id: ClientId) -> bool {
+        self.0
+            .get(&client_id)
+            .is_some_and(|strikes| *strikes > MAX_MALFORMED_MESSAGES)
+    }
+}
+
+/// A message from a client, attributed to the connection it arrived on. Clients don't get to
+/// say who they are in the payload.
+#[derive(Event, Debug, Clone)]
+pub struct FromClient<T> {
+    pub client_id: ClientId,
+    pub message: T,
+}
+
+/// Where the input the clients send ends up, as events for the systems that apply it, and the
+/// tick it is read on.
+#[derive(SystemParam)]
+struct ClientInputs<'w> {
+    movement: EventWriter<'w, FromClient<ClientAction<Action>>>,
+    mouse: EventWriter<'w, FromClient<ClientMouseMovement>>,
+    time_fixed: Res<'w, Time<Fixed>>,
+}
+
+/// Mouse or look direction messages a client can send on a tick of `tick_duration` seconds.
+fn look_message_budget(tick_duration: f64) -> usize {
+    (MAX_LOOK_MESSAGES_PER_SECOND * tick_duration)
+        .ceil()
+        .max(1.0) as usize
+}
+
+/// What the clients' snapshot acks and settings update.
+#[derive(SystemParam)]
+struct SnapshotRequests<'w> {
+    baselines: ResMut<'w, SnapshotBaselines>,
+    bandwidth: ResMut<'w, SnapshotBandwidth>,
+    interpolation_delays: ResMut<'w, InterpolationDelays>,
+}
+
+fn receive_client_messages(
+    mut server: ResMut<RenetServer>,
+    mut players: Query<(&mut LookDirection, &mut LastLookSequence)>,
+    lobby: Res<ServerLobby>,
+    mut inputs: ClientInputs,
+    mut snapshot_requests: SnapshotRequests,
+    mut strikes: ResMut<MessageStrikes>,
+    mut violations: ResMut<ValidationViolations>,
+) {
     for client_id in server.clients_id() {
         while let Some(message) = server.receive_message(client_id, ClientChannel::Input) {
-            let client_move: ClientAction<Action> = bincode::deserialize(&message).unwrap();
+            let Some(client_move): Option<ClientAction<Action>> =
+                strikes.decode(client_id, ClientChannel::Input, &message)
+            else {
+                continue;
+            };
             debug!("received ClientMovement {:?}", client_move);
-            if let Some(player_entity) = lobby.players.get(&client_move.client_id) {
-                movement_event_writer.send(client_move);
+            if lobby.players.contains_key(&client_id) {
+                inputs.movement.send(FromClient {
+                    client_id,
+                    message: client_move,
+                });
             }
         }
+        // rotations and look directions are absolute, only the newest one of the tick matters.
+        // Clients flooding us past what they can send in a tick get a violation each tick
+        let budget = look_message_budget(tick_duration(&inputs.time_fixed));
+        let mut received = 0;
+        let mut newest_mouse = None;
         while let Some(message) = server.receive_message(client_id, ClientChannel::MouseInput) {
-            let client_mouse: ClientMouseMovement = bincode::deserialize(&message).unwrap();
+            received += 1;
+            let Some(client_mouse): Option<ClientMouseMovement> =
+                strikes.decode(client_id, ClientChannel::MouseInput, &message)
+            else {
+                continue;
+            };
             debug!("received ClientMouseMovement {:?}", client_mouse);
-            if let Some(player_entity) = lobby.players.get(&client_mouse.client_id) {
-                mouse_event_writer.send(client_mouse);
-            }
+            newest_mouse = Some(client_mouse);
+        }
+        if received > budget {
+            violations.client(client_id).rate_limited += 1;
+        }
+        if let Some(client_mouse) = newest_mouse.filter(|_| lobby.players.contains_key(&client_id))
+        {
+            inputs.mouse.send(FromClient {
+                client_id,
+                message: client_mouse,
+            });
         }
+
+        let mut received = 0;
+        let mut newest_look: Option<ClientLookDirection> = None;
         while let Some(message) = server.receive_message(client_id, ClientChannel::ClientData) {
-            let client_data: ClientLookDirection = bincode::deserialize(&message).unwrap();
+            received += 1;
+            let Some(client_data): Option<ClientLookDirection> =
+                strikes.decode(client_id, ClientChannel::ClientData, &message)
+            else {
+                continue;
+            };
             //debug!("received ClientLookDirection {:?}", client_data);
-            if let Some(player_entity) = lobby.players.get(&client_data.client_id) {
-                let Ok((_, _, _, mut look_dir)) = players.get_mut(*player_entity) else {
-                    continue;
-                };
-                look_dir.0 = client_data.dir;
+            let Some(dir) = validate_look_direction(client_data.dir, violations.client(client_id))
+            else {
+                continue;
+            };
+            // unreliable channel, a reordered packet can come after a newer one
+            if newest_look
+                .as_ref()
+                .is_none_or(|newest| sequence_newer(client_data.sequence, newest.sequence))
+            {
+                newest_look = Some(ClientLookDirection {
+                    dir,
+                    sequence: client_data.sequence,
+                });
+            }
+        }
+        if received > budget {
+            violations.client(client_id).rate_limited += 1;
+        }
+        let player = lobby
+            .players
+            .get(&client_id)
+            .and_then(|player_entity| players.get_mut(*player_entity).ok());
+        if let (Some(newest_look), Some((mut look_dir, mut last_sequence))) = (newest_look, player)
+        {
+            // the newest of this tick can still be older than one from a previous tick
+            if sequence_newer(newest_look.sequence, last_sequence.0) {
+                look_dir.0 = newest_look.dir;
+                last_sequence.0 = newest_look.sequence;
+            }
+        }
+        while let Some(message) = server.receive_message(client_id, ClientChannel::SnapshotAck) {
+            let Some(ack): Option<SnapshotAck> =
+                strikes.decode(client_id, ClientChannel::SnapshotAck, &message)
+            else {
+                continue;
+            };
+            if let Some(baseline) = snapshot_requests.baselines.0.get_mut(&client_id) {
+                baseline.acknowledge(ack.tick);
+            }
+        }
+        while let Some(message) = server.receive_message(client_id, ClientChannel::Settings) {
+            let Some(setting): Option<ClientSetting> =
+                strikes.decode(client_id, ClientChannel::Settings, &message)
+            else {
+                continue;
+            };
+            match setting {
+                ClientSetting::UpdateRate(rate) => snapshot_requests
+                    .bandwidth
+                    .0
+                    .entry(client_id)
+                    .or_default()
+                    .request_rate(rate),
+                // shots are rewound at most `LAG_COMPENSATION_MAX_REWIND_MS`, whatever it says
+                ClientSetting::InterpolationDelay(delay) => {
+                    snapshot_requests
+                        .interpolation_delays
+                        .0
+                        .insert(client_id, delay);
+                }
+            }
+        }
+
+        if strikes.exceeded(client_id) {
+            warn!("Disconnecting client {client_id}, too many malformed messages");
+            server.disconnect(client_id);
+        }
+    }
+}
+
+fn relay_chat(
+    mut server: ResMut<RenetServer>,
+    mut chat_roster: ResMut<ChatRoster>,
+    mut strikes: ResMut<MessageStrikes>,
+    mut violations: ResMut<ValidationViolations>,
+    tick: Res<ServerTick>,
+    time: Res<Time>,
+    mut message_counts: ResMut<ServerMessageCounts>,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, ClientChannel::Chat) {
+            let Some(chat): Option<ClientChat> =
+                strikes.decode(client_id, ClientChannel::Chat, &message)
+            else {
+                continue;
+            };
+            let scope = chat.scope;
+            let Some(message) = chat_roster.accept(
+                client_id,
+                chat,
+                time.elapsed_secs(),
+                violations.client(client_id),
+            ) else {
+                continue;
+            };
+            let recipients = chat_roster.recipients(client_id, scope);
+            message_counts.record(&message, recipients.len());
+            let message = tick.stamp(message);
+            for recipient in recipients {
+                server.send_message(recipient, ServerChannel::ServerMessages, message.clone());
             }
         }
     }
 }
 
+/// What `update_client_input_state` feeds the inputs into.
+type InputTargets<'a> = (
+    &'a mut ActionState<Action>,
+    &'a mut PendingInputs,
+    &'a mut LastInputSequence,
+);
+
+// one buffered input per tick, the acked sequence is the one this tick simulates
 fn update_client_input_state(
-    mut movement_event_reader: EventReader<ClientAction<Action>>,
+    mut movement_event_reader: EventReader<FromClient<ClientAction<Action>>>,
     //mut controllers: Query<(Entity, &mut ActionState<Action>), With<PlayerMarker>>,
-    mut action_state_query: Query<&mut ActionState<Action>, With<PlayerMarker>>,
+    mut action_state_query: Query<InputTargets, With<PlayerMarker>>,
     server_lobby: Res<ServerLobby>,
 ) {
     for ev in movement_event_reader.read() {
         debug!("processing client movement event");
 
         if let Some(player_ent) = server_lobby.players.get(&ev.client_id) {
-            if let Ok(mut action_state) = action_state_query.get_mut(*player_ent) {
-                action_state.apply_diff(&ev.action_diff);
+            if let Ok((_, mut pending, _)) = action_state_query.get_mut(*player_ent) {
+                // reliable and ordered, every message is newer than the last
+                pending.0.push_back(ev.message.clone());
+            }
+        }
+    }
+
+    for (mut action_state, mut pending, mut last_sequence) in action_state_query.iter_mut() {
+        // a client that got ahead after a stall catches up instead of staying that far behind
+        let buffered = pending.0.len();
+        let consumed = buffered
+            .saturating_sub(MAX_BUFFERED_INPUTS)
+            .max(1)
+            .min(buffered);
+        for input in pending.0.drain(..consumed) {
+            for action_diff in &input.action_diffs {
+                action_state.apply_diff(action_diff);
             }
+            last_sequence.0 = input.sequence;
         }
     }
 }
@@ -362,6 +827,12 @@ fn read_client_input_state(
                 ent,
             });
         }
+        if action_state.pressed(&Action::Railgun) {
+            player_action.send(ServerPlayerAction {
+                action: PlayerAction::Railgun,
+                ent,
+            });
+        }
         if action_state.pressed(&Action::Jump) {
             player_action.send(ServerPlayerAction {
                 action: PlayerAction::Jump,
@@ -399,7 +870,7 @@ pub struct ServerPlayerAction {
     ent: Entity,
 }
 
-fn handle_server_player_action(
+pub fn handle_server_player_action(
     time_fixed: Res<Time<Fixed>>,
     mut movement_event_reader: EventReader<ServerPlayerAction>,
     mut controllers: Query<(
@@ -408,10 +879,11 @@ fn handle_server_player_action(
         Has<Grounded>,
         &Transform,
         &mut WeaponCooldown,
+        &mut RailgunCooldown,
         &LookDirection,
     )>,
+    mut railgun_fired: EventWriter<RailgunFired>,
     mut commands: Commands,
-    mut server: ResMut<RenetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
@@ -424,6 +896,7 @@ fn handle_server_player_action(
             is_grounded,
             player_tf,
             mut weapon_timer,
+            mut railgun_timer,
             look_direction,
         )) = controllers.get_mut(event.ent)
         else {
@@ -443,61 +916,66 @@ fn handle_server_player_action(
                     let spawn_location =
                         player_tf.translation + (look_direction.0 * 2.0) + Vec3::new(0.0, 0.4, 0.0);
                     let rocket_speed = look_direction.0 * ROCKET_SPEED;
+                    // clients only ever see the replicated copy, see `RocketExploded`
                     commands.spawn((
-                        Name::new("Bullet"),
-                        Rocket,
+                        rocket_visuals(&mut meshes, &mut materials),
+                        Replicated,
                         LinearVelocity(rocket_speed),
                         RigidBody::Kinematic,
                         Collider::cuboid(0.2, 0.2, 0.2),
-                        Mesh3d(meshes.add(Cuboid::from_length(0.2))),
-                        MeshMaterial3d(materials.add(Color::srgb_u8(154, 109, 100))),
                         Transform::from_translation(spawn_location),
                     ));
-                    let message = bincode::serialize(&ServerMessages::BulletCreate {
-                        translation: spawn_location.into(),
-                        dir: look_direction.0.to_array(),
-                    })
-                    .unwrap();
-                    server.broadcast_message(ServerChannel::ServerMessages, message);
+                }
+            }
+            PlayerAction::Railgun => {
+                if railgun_timer.0.finished() {
+                    railgun_timer.0.reset();
+                    // resolved against rewound hitboxes, see `lag_compensation`
+                    railgun_fired.send(RailgunFired { shooter: event.ent });
                 }
             }
         }
     }
 }
 
-fn tick_shoot_cooldown(mut timer_q: Query<&mut WeaponCooldown>, time: Res<Time>) {
-    for mut timer in timer_q.iter_mut() {
+fn tick_shoot_cooldown(
+    mut timer_q: Query<(&mut WeaponCooldown, &mut RailgunCooldown)>,
+    time: Res<Time>,
+) {
+    for (mut timer, mut railgun_timer) in timer_q.iter_mut() {
         timer.0.tick(time.delta());
+        railgun_timer.0.tick(time.delta());
     }
 }
 
 fn server_mouse(
-    mut mouse_event: EventReader<ClientMouseMovement>,
-    mut player_q: Query<(Entity, &mut Transform, &CameraSensitivity)>,
+    mut mouse_event: EventReader<FromClient<ClientMouseMovement>>,
+    mut player_q: Query<&mut Transform, With<PlayerMarker>>,
     server_lobby: Res<ServerLobby>,
+    time_fixed: Res<Time<Fixed>>,
+    mut violations: ResMut<ValidationViolations>,
 ) {
+    // rotations are absolute, only the newest one of the tick matters
+    let mut rotations = HashMap::default();
     for event in mouse_event.read() {
-        for (player_ent, mut player_tf, player_camera_sens) in player_q.iter_mut() {
-            if server_lobby
-                .players
-                .get(&event.client_id)
-                .is_some_and(|inner| *inner == player_ent)
-            {
-                debug!("Mouse movement for {:?} ", player_ent);
-                /*
-
-                let delta_yaw = -event.mouse_delta.x;
-                let delta_pitch = -event.mouse_delta.y;
-
-                let (yaw, pitch, roll) = player_tf.rotation.to_euler(EulerRot::YXZ);
-                let yaw = yaw + delta_yaw;
-
-                const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01;
-                let pitch = (pitch + delta_pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
-                 */
-
-                player_tf.rotation = event.rotation;
-            }
+        rotations.insert(event.client_id, event.message.rotation);
+    }
+    let max_turn = MAX_TURN_SPEED * time_fixed.delta_secs();
+    for (client_id, rotation) in rotations {
+        let Some(player_ent) = server_lobby.players.get(&client_id) else {
+            continue;
+        };
+        let Ok(mut player_tf) = player_q.get_mut(*player_ent) else {
+            continue;
+        };
+        debug!("Mouse movement for {:?} ", player_ent);
+        if let Some(rotation) = validate_rotation(
+            player_tf.rotation,
+            rotation,
+            max_turn,
+            violations.client(client_id),
+        ) {
+            player_tf.rotation = rotation;
         }
     }
 }
@@ -506,30 +984,60 @@ fn update_visualizer_system(
     mut egui_contexts: EguiContexts,
     mut visualizer: ResMut<RenetServerVisualizer<200>>,
     server: Res<RenetServer>,
+    violations: Res<ValidationViolations>,
+    bandwidth: Res<SnapshotBandwidth>,
 ) {
     visualizer.update(&server);
+    visualizer.update_violations(&violations);
+    visualizer.update_snapshot_usage(&bandwidth);
     visualizer.show_window(egui_contexts.ctx_mut());
 }
 
 fn server_network_sync(
     mut server: ResMut<RenetServer>,
-    query: Query<(Entity, &Transform, &LinearVelocity, &Health), With<PlayerMarker>>,
+    tick: Res<ServerTick>,
+    mut baselines: ResMut<SnapshotBaselines>,
+    mut bandwidth: ResMut<SnapshotBandwidth>,
+    relevancy: Res<Relevancy>,
+    settings: Res<ServerSettings>,
+    registry: Res<ReplicationRegistry>,
+    query: Query<EntityRef, With<Replicated>>,
 ) {
-    let mut networked_entities = NetworkedEntities::default();
-    for (entity, transform, velocity, health) in query.iter() {
-        networked_entities.entities.push(entity);
-        networked_entities
-            .translations
-            .push(transform.translation.into());
-        networked_entities
-            .rotations
-            .push(transform.rotation.to_array());
-        networked_entities.velocities.push(velocity.to_array());
-        networked_entities.health.push(health.0);
-    }
+    let networked_entities = NetworkedEntities {
+        tick: tick.0,
+        entities: query
+            .iter()
+            .map(|entity| (entity.id(), registry.capture(&entity)))
+            .collect(),
+    };
 
-    let sync_message = bincode::serialize(&networked_entities).unwrap();
-    server.broadcast_message(ServerChannel::NetworkedEntities, sync_message);
+    bandwidth
+        .0
+        .retain(|client_id, _| baselines.0.contains_key(client_id));
+    // every client gets its own delta when it's due one, depending on what it can see, what it
+    // acknowledged and what fits in its budget
+    for (client_id, baseline) in baselines.0.iter_mut() {
+        // not placed in the world yet
+        let Some(relevant) = relevancy.0.get(client_id) else {
+            continue;
+        };
+        let client_bandwidth = bandwidth.0.entry(*client_id).or_default();
+        let Some(elapsed) = client_bandwidth.due(tick.0, settings.tick_rate, settings.send_rate)
+        else {
+            continue;
+        };
+        let networked_entities = client_bandwidth.prioritize(
+            baseline.baseline(),
+            &relevant.filter(&networked_entities),
+            elapsed,
+            settings.snapshot_budget,
+            |entity| relevant.weight(entity),
+        );
+        let delta = snapshot::diff(baseline.baseline(), &networked_entities);
+        let sync_message = bincode::serialize(&delta).unwrap();
+        server.send_message(*client_id, ServerChannel::NetworkedEntities, sync_message);
+        baseline.record_sent(networked_entities);
+    }
 }
 
 pub fn connection_config() -> ConnectionConfig {
@@ -539,3 +1047,175 @@ pub fn connection_config() -> ConnectionConfig {
         server_channels_config: ServerChannel::channels_config(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy_renet::renet::RenetClient;
+
+    use super::*;
+
+    // what clients sent before the payload ids were dropped
+    #[derive(Serialize)]
+    struct LegacyLookDirection {
+        dir: Vec3,
+        client_id: u64,
+        sequence: u32,
+    }
+
+    #[test]
+    fn test_spoofed_client_id_is_ignored() {
+        let (attacker_id, victim_id) = (1, 2);
+        let mut server = RenetServer::new(connection_config());
+        server.add_connection(attacker_id);
+        server.add_connection(victim_id);
+        // `new_local_client` would send on the server's channels, this is a real client
+        let mut attacker = RenetClient::new(connection_config());
+        attacker.set_connected();
+
+        let mut app = App::new();
+        let attacker_ent = app
+            .world_mut()
+            .spawn((LookDirection::default(), LastLookSequence::default()))
+            .id();
+        let victim_ent = app
+            .world_mut()
+            .spawn((LookDirection::default(), LastLookSequence::default()))
+            .id();
+        let mut lobby = ServerLobby::default();
+        lobby.players.insert(attacker_id, attacker_ent);
+        lobby.players.insert(victim_id, victim_ent);
+
+        let spoofed = LegacyLookDirection {
+            dir: Vec3::X,
+            client_id: victim_id,
+            sequence: 1,
+        };
+        attacker.send_message(
+            ClientChannel::ClientData,
+            bincode::serialize(&spoofed).unwrap(),
+        );
+        let own = ClientLookDirection {
+            dir: Vec3::NEG_Z,
+            sequence: 2,
+        };
+        attacker.send_message(ClientChannel::ClientData, bincode::serialize(&own).unwrap());
+        for packet in attacker.get_packets_to_send() {
+            server.process_packet_from(&packet, attacker_id).unwrap();
+        }
+
+        app.insert_resource(server)
+            .insert_resource(lobby)
+            .init_resource::<SnapshotBaselines>()
+            .init_resource::<SnapshotBandwidth>()
+            .init_resource::<InterpolationDelays>()
+            .init_resource::<Time<Fixed>>()
+            .init_resource::<MessageStrikes>()
+            .init_resource::<ValidationViolations>()
+            .add_event::<FromClient<ClientAction<Action>>>()
+            .add_event::<FromClient<ClientMouseMovement>>()
+            .add_systems(Update, receive_client_messages);
+        app.update();
+
+        let world = app.world();
+        assert_eq!(
+            world.get::<LookDirection>(victim_ent).unwrap().0,
+            Vec3::ZERO
+        );
+        assert_eq!(world.get::<LastLookSequence>(victim_ent).unwrap().0, 0);
+        assert_eq!(
+            world.get::<LookDirection>(attacker_ent).unwrap().0,
+            Vec3::NEG_Z
+        );
+        // the legacy layout doesn't decode anymore
+        assert_eq!(world.resource::<MessageStrikes>().0[&attacker_id], 1);
+    }
+
+    #[test]
+    fn test_look_flood_keeps_the_newest_direction() {
+        let client_id = 1;
+        let mut server = RenetServer::new(connection_config());
+        server.add_connection(client_id);
+        let mut client = RenetClient::new(connection_config());
+        client.set_connected();
+
+        let mut app = App::new();
+        let player_ent = app
+            .world_mut()
+            .spawn((LookDirection::default(), LastLookSequence::default()))
+            .id();
+        let mut lobby = ServerLobby::default();
+        lobby.players.insert(client_id, player_ent);
+
+        // twice what fits in a 64hz tick, the newest one reordered to the front
+        let budget = look_message_budget(1.0 / 64.0);
+        let newest = budget as u32 * 2;
+        for sequence in std::iter::once(newest).chain(1..newest) {
+            let look = ClientLookDirection {
+                dir: Vec3::new(sequence as f32, 0.0, -1.0).normalize(),
+                sequence,
+            };
+            client.send_message(
+                ClientChannel::ClientData,
+                bincode::serialize(&look).unwrap(),
+            );
+        }
+        for packet in client.get_packets_to_send() {
+            server.process_packet_from(&packet, client_id).unwrap();
+        }
+
+        app.insert_resource(server)
+            .insert_resource(lobby)
+            .insert_resource(Time::<Fixed>::from_hz(64.0))
+            .init_resource::<SnapshotBaselines>()
+            .init_resource::<SnapshotBandwidth>()
+            .init_resource::<InterpolationDelays>()
+            .init_resource::<MessageStrikes>()
+            .init_resource::<ValidationViolations>()
+            .add_event::<FromClient<ClientAction<Action>>>()
+            .add_event::<FromClient<ClientMouseMovement>>()
+            .add_systems(Update, receive_client_messages);
+        app.update();
+
+        let world = app.world();
+        assert_eq!(world.get::<LastLookSequence>(player_ent).unwrap().0, newest);
+        let violations = &world.resource::<ValidationViolations>().0[&client_id];
+        assert_eq!(violations.rate_limited, 1);
+    }
+
+    #[test]
+    fn test_one_input_is_simulated_per_tick() {
+        let client_id = 1;
+        let mut app = App::new();
+        let player = app
+            .world_mut()
+            .spawn((
+                PlayerMarker,
+                ActionState::<Action>::default(),
+                PendingInputs::default(),
+                LastInputSequence::default(),
+            ))
+            .id();
+        let mut lobby = ServerLobby::default();
+        lobby.players.insert(client_id, player);
+        app.insert_resource(lobby)
+            .add_event::<FromClient<ClientAction<Action>>>()
+            .add_systems(Update, update_client_input_state);
+
+        // two ticks worth of input arriving together
+        for sequence in [1, 2] {
+            app.world_mut().send_event(FromClient {
+                client_id,
+                message: ClientAction::<Action> {
+                    sequence,
+                    action_diffs: vec![],
+                },
+            });
+        }
+        app.update();
+        assert_eq!(app.world().get::<LastInputSequence>(player).unwrap().0, 1);
+        app.update();
+        assert_eq!(app.world().get::<LastInputSequence>(player).unwrap().0, 2);
+        app.update();
+        assert_eq!(app.world().get::<LastInputSequence>(player).unwrap().0, 2);
+    }
+}
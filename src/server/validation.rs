@@ -0,0 +1,160 @@
+//! Sanity checks on the look and rotation input clients send. Clients only get to aim, the
+//! server makes sure what they send is a direction a real mouse could have produced.
+use std::{
+    f32::consts::{PI, TAU},
+    fmt,
+};
+
+use avian3d::parry::utils::hashmap::HashMap;
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+
+use crate::consts::PITCH_LIMIT;
+
+// vectors and quaternions shorter than this don't point anywhere
+const MIN_LENGTH_SQUARED: f32 = 1e-6;
+// slack for the rounding of a pitch that was clamped on the client
+const PITCH_TOLERANCE: f32 = 1e-3;
+
+/// Inputs from each client that had to be dropped or corrected, shown in the server visualizer.
+#[derive(Debug, Default, Resource)]
+pub struct ValidationViolations(pub HashMap<ClientId, ViolationCounts>);
+
+impl ValidationViolations {
+    pub fn client(&mut self, client_id: ClientId) -> &mut ViolationCounts {
+        self.0.entry(client_id).or_default()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ViolationCounts {
+    /// NaN, infinite or zero length rotations and look directions, dropped
+    pub malformed: u32,
+    /// rotations turning faster than `MAX_TURN_SPEED`, clamped
+    pub turn_speed: u32,
+    /// look directions past `PITCH_LIMIT`, clamped
+    pub pitch: u32,
+    /// ticks on which more look messages came in than a client can send, only the newest is used
+    pub rate_limited: u32,
+    /// chat messages that are empty, too long or too frequent, dropped
+    pub chat: u32,
+}
+
+impl ViolationCounts {
+    pub fn total(&self) -> u32 {
+        self.malformed + self.turn_speed + self.pitch + self.rate_limited + self.chat
+    }
+}
+
+impl fmt::Display for ViolationCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed {}, turn speed {}, pitch {}, rate limited {}, chat {}",
+            self.malformed, self.turn_speed, self.pitch, self.rate_limited, self.chat
+        )
+    }
+}
+
+/// Body rotation for a player currently rotated by `current` that asked for `requested`. Only
+/// the yaw is kept, and it turns at most `max_turn` radians.
+pub fn validate_rotation(
+    current: Quat,
+    requested: Quat,
+    max_turn: f32,
+    violations: &mut ViolationCounts,
+) -> Option<Quat> {
+    if !requested.is_finite() || requested.length_squared() < MIN_LENGTH_SQUARED {
+        violations.malformed += 1;
+        return None;
+    }
+    let (current_yaw, _, _) = current.to_euler(EulerRot::YXZ);
+    let (requested_yaw, _, _) = requested.normalize().to_euler(EulerRot::YXZ);
+
+    // shortest way around
+    let delta = (requested_yaw - current_yaw + PI).rem_euclid(TAU) - PI;
+    if delta.abs() > max_turn {
+        violations.turn_speed += 1;
+    }
+    let yaw = current_yaw + delta.clamp(-max_turn, max_turn);
+    Some(Quat::from_rotation_y(yaw))
+}
+
+/// Unit look direction with the pitch clamped to `PITCH_LIMIT`.
+pub fn validate_look_direction(dir: Vec3, violations: &mut ViolationCounts) -> Option<Vec3> {
+    if !dir.is_finite() || dir.length_squared() < MIN_LENGTH_SQUARED {
+        violations.malformed += 1;
+        return None;
+    }
+    let dir = dir.normalize();
+    let pitch = dir.y.clamp(-1.0, 1.0).asin();
+    if pitch.abs() <= PITCH_LIMIT + PITCH_TOLERANCE {
+        return Some(dir);
+    }
+
+    violations.pitch += 1;
+    // straight up or down, there is no yaw left to keep
+    let Some(horizontal) = Vec2::new(dir.x, dir.z).try_normalize() else {
+        violations.malformed += 1;
+        return None;
+    };
+    let pitch = pitch.clamp(-PITCH_LIMIT, PITCH_LIMIT);
+    Some(Vec3::new(
+        horizontal.x * pitch.cos(),
+        pitch.sin(),
+        horizontal.y * pitch.cos(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_is_sanitized() {
+        let mut violations = ViolationCounts::default();
+        let current = Quat::from_rotation_y(3.0);
+        assert_eq!(
+            validate_rotation(
+                current,
+                Quat::from_xyzw(f32::NAN, 0.0, 0.0, 1.0),
+                1.0,
+                &mut violations
+            ),
+            None
+        );
+        assert_eq!(violations.malformed, 1);
+
+        // unnormalized and rolled, across the wrap around
+        let requested = Quat::from_euler(EulerRot::YXZ, -3.0, 0.5, 0.7) * 3.0;
+        let rotation = validate_rotation(current, requested, 1.0, &mut violations).unwrap();
+        let (yaw, pitch, roll) = rotation.to_euler(EulerRot::YXZ);
+        assert!((yaw - (-3.0)).abs() < 1e-4);
+        assert!(pitch.abs() < 1e-5 && roll.abs() < 1e-5);
+        assert_eq!(violations.turn_speed, 0);
+
+        let rotation =
+            validate_rotation(current, Quat::from_rotation_y(0.0), 0.5, &mut violations).unwrap();
+        assert!((rotation.to_euler(EulerRot::YXZ).0 - 2.5).abs() < 1e-4);
+        assert_eq!(violations.turn_speed, 1);
+    }
+
+    #[test]
+    fn test_look_direction_is_sanitized() {
+        let mut violations = ViolationCounts::default();
+        assert_eq!(validate_look_direction(Vec3::ZERO, &mut violations), None);
+        assert_eq!(validate_look_direction(Vec3::Y, &mut violations), None);
+        assert_eq!(violations.malformed, 2);
+        // straight up is past the limit before it's found to have no yaw
+        assert_eq!(violations.pitch, 1);
+
+        let dir = validate_look_direction(Vec3::new(0.0, 0.0, -2.0), &mut violations).unwrap();
+        assert!((dir - Vec3::NEG_Z).length() < 1e-6);
+        assert_eq!(violations.pitch, 1);
+
+        let dir = validate_look_direction(Vec3::new(0.001, 1.0, 0.0), &mut violations).unwrap();
+        assert!((dir.length() - 1.0).abs() < 1e-5);
+        assert!((dir.y.asin() - PITCH_LIMIT).abs() < 1e-4);
+        assert_eq!(violations.pitch, 2);
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts::SNAPSHOT_HISTORY_SIZE,
+    replication::{ComponentId, ComponentStates},
+    server::NetworkedEntities,
+};
+
+/// What is actually sent on `ServerChannel::NetworkedEntities`: the components that changed
+/// since a snapshot the client acknowledged, or everything if there is no usable baseline.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    // tick of the snapshot this delta applies to, None for a full snapshot
+    pub baseline: Option<u32>,
+    pub changed: Vec<EntityDelta>,
+    pub removed: Vec<Entity>,
+}
+
+/// Changed components of a single entity. Unchanged entities are not sent at all.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct EntityDelta {
+    pub entity: Entity,
+    pub changed: Vec<(ComponentId, Vec<u8>)>,
+    // components the entity lost since the baseline
+    pub removed: Vec<ComponentId>,
+}
+
+impl EntityDelta {
+    fn between(
+        entity: Entity,
+        baseline: Option<&ComponentStates>,
+        current: &ComponentStates,
+    ) -> Self {
+        let changed = current
+            .iter()
+            .filter(|(id, state)| baseline.and_then(|b| b.get(id)) != Some(state))
+            .map(|(id, state)| (*id, state.clone()))
+            .collect();
+        let removed = baseline
+            .into_iter()
+            .flat_map(|b| b.keys())
+            .filter(|id| !current.contains_key(id))
+            .copied()
+            .collect();
+        Self {
+            entity,
+            changed,
+            removed,
+        }
+    }
+
+    // an entity that is new and has no replicated component still needs to be sent
+    fn is_empty(&self, is_new: bool) -> bool {
+        !is_new && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    fn apply(&self, baseline: Option<&ComponentStates>) -> ComponentStates {
+        let mut states = baseline.cloned().unwrap_or_default();
+        for id in self.removed.iter() {
+            states.remove(id);
+        }
+        for (id, state) in self.changed.iter() {
+            states.insert(*id, state.clone());
+        }
+        states
+    }
+}
+
+/// Encodes `current` against `baseline`. A `None` baseline produces a full snapshot.
+pub fn diff(baseline: Option<&NetworkedEntities>, current: &NetworkedEntities) -> SnapshotDelta {
+    let changed = current
+        .entities
+        .iter()
+        .filter_map(|(entity, states)| {
+            let previous = baseline.and_then(|b| b.entities.get(entity));
+            let delta = EntityDelta::between(*entity, previous, states);
+            (!delta.is_empty(previous.is_none())).then_some(delta)
+        })
+        .collect();
+
+    let removed = baseline
+        .map(|b| {
+            b.entities
+                .keys()
+                .filter(|entity| !current.entities.contains_key(entity))
+                .copied()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    SnapshotDelta {
+        tick: current.tick,
+        baseline: baseline.map(|b| b.tick),
+        changed,
+        removed,
+    }
+}
+
+/// Encoded size of what changed in one entity since `baseline`, None if there is nothing to send.
+pub fn delta_size(
+    entity: Entity,
+    baseline: Option<&ComponentStates>,
+    current: &ComponentStates,
+) -> Option<usize> {
+    let delta = EntityDelta::between(entity, baseline, current);
+    if delta.is_empty(baseline.is_none()) {
+        return None;
+    }
+    Some(bincode::serialized_size(&delta).unwrap() as usize)
+}
+
+/// Rebuilds the full snapshot from `delta` and the baseline it was encoded against.
+pub fn apply(baseline: Option<&NetworkedEntities>, delta: &SnapshotDelta) -> NetworkedEntities {
+    let mut entities: BTreeMap<Entity, ComponentStates> =
+        baseline.map(|b| b.entities.clone()).unwrap_or_default();
+
+    for entity in delta.removed.iter() {
+        entities.remove(entity);
+    }
+    for entity_delta in delta.changed.iter() {
+        let states = entity_delta.apply(entities.get(&entity_delta.entity));
+        entities.insert(entity_delta.entity, states);
+    }
+
+    NetworkedEntities {
+        tick: delta.tick,
+        entities,
+    }
+}
+
+/// Last few snapshots, either sent to one client (server) or received from the server (client).
+#[derive(Debug, Default)]
+pub struct SnapshotHistory(VecDeque<NetworkedEntities>);
+
+impl SnapshotHistory {
+    pub fn get(&self, tick: u32) -> Option<&NetworkedEntities> {
+        self.0.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn push(&mut self, snapshot: NetworkedEntities) {
+        self.0.push_back(snapshot);
+        while self.0.len() > SNAPSHOT_HISTORY_SIZE {
+            self.0.pop_front();
+        }
+    }
+
+    /// Forgets every snapshot older than `tick`, they can't be used as baselines anymore.
+    pub fn discard_before(&mut self, tick: u32) {
+        self.0.retain(|snapshot| snapshot.tick >= tick);
+    }
+}
+
+/// Server side bookkeeping of what one client has.
+#[derive(Debug, Default)]
+pub struct ClientBaseline {
+    sent: SnapshotHistory,
+    acked: Option<u32>,
+}
+
+impl ClientBaseline {
+    /// Snapshot the next delta should be encoded against. None means the client needs a full
+    /// snapshot, either because it never acked one or because we don't have it anymore.
+    pub fn baseline(&self) -> Option<&NetworkedEntities> {
+        self.acked.and_then(|tick| self.sent.get(tick))
+    }
+
+    pub fn record_sent(&mut self, snapshot: NetworkedEntities) {
+        self.sent.push(snapshot);
+        if self.acked.is_some() && self.baseline().is_none() {
+            debug!("acked baseline {:?} was lost, resyncing", self.acked);
+            self.acked = None;
+        }
+    }
+
+    pub fn acknowledge(&mut self, tick: u32) {
+        if self.acked.is_some_and(|acked| acked >= tick) || self.sent.get(tick).is_none() {
+            return;
+        }
+        self.acked = Some(tick);
+        self.sent.discard_before(tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a position and a health component, the bytes don't matter here
+    fn state(x: u8, health: u8) -> ComponentStates {
+        [(0, vec![x]), (1, vec![health])].into()
+    }
+
+    fn snapshot(tick: u32, states: &[(u32, ComponentStates)]) -> NetworkedEntities {
+        NetworkedEntities {
+            tick,
+            entities: states
+                .iter()
+                .map(|(index, state)| (Entity::from_raw(*index), state.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_full_snapshot_round_trip() {
+        let current = snapshot(3, &[(1, state(1, 100)), (2, state(2, 50))]);
+        let delta = diff(None, &current);
+        assert_eq!(delta.baseline, None);
+        assert_eq!(apply(None, &delta).entities, current.entities);
+    }
+
+    #[test]
+    fn test_unchanged_entities_are_not_sent() {
+        let baseline = snapshot(3, &[(1, state(1, 100)), (2, state(2, 50))]);
+        let current = snapshot(6, &[(1, state(1, 100)), (2, state(3, 50))]);
+        let delta = diff(Some(&baseline), &current);
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(
+            delta.changed[0],
+            EntityDelta {
+                entity: Entity::from_raw(2),
+                changed: vec![(0, vec![3])],
+                removed: vec![],
+            }
+        );
+        assert_eq!(apply(Some(&baseline), &delta).entities, current.entities);
+    }
+
+    #[test]
+    fn test_removed_entities_and_components() {
+        let baseline = snapshot(3, &[(1, state(1, 100)), (2, state(2, 50))]);
+        let mut current = snapshot(6, &[(1, state(1, 100)), (3, ComponentStates::new())]);
+        current
+            .entities
+            .get_mut(&Entity::from_raw(1))
+            .unwrap()
+            .remove(&1);
+        let delta = diff(Some(&baseline), &current);
+        assert_eq!(delta.removed, vec![Entity::from_raw(2)]);
+        // entity 3 has nothing replicated yet but still has to be spawned
+        assert_eq!(delta.changed.len(), 2);
+        assert_eq!(apply(Some(&baseline), &delta).entities, current.entities);
+    }
+
+    #[test]
+    fn test_lost_baseline_forces_full_snapshot() {
+        let mut client = ClientBaseline::default();
+        client.record_sent(snapshot(0, &[]));
+        client.acknowledge(0);
+        assert!(client.baseline().is_some());
+        for tick in 1..=SNAPSHOT_HISTORY_SIZE as u32 {
+            client.record_sent(snapshot(tick, &[]));
+        }
+        assert!(client.baseline().is_none());
+    }
+}
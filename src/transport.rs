@@ -0,0 +1,513 @@
+//! Netcode transports with a `LinkConditioner` between the socket and renet. They behave like
+//! the ones from `bevy_renet::netcode` until the `net_*` console commands degrade the link.
+//!
+//! Forked from renet_netcode 1.0.0 (`NetcodeServerTransport` in src/server.rs and
+//! `NetcodeClientTransport` in src/client.rs) and the bevy_renet 1.0.0 plugins in
+//! src/netcode.rs, the stock transports only take a `std::net::UdpSocket`. The only changes are
+//! the `ConditionedSocket` packets go through, the `flush`/`flush_all` calls releasing delayed
+//! packets, `set_conditions` and the plugin systems applying `LinkConditions`, all marked with
+//! `fork:`. Methods the game doesn't call are left out, diff against those files when upgrading
+//! renet.
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bevy_renet::{
+    netcode::NetcodeTransportError,
+    renet::{ClientId, RenetClient, RenetServer},
+    RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin,
+};
+use renetcode::{
+    ClientAuthentication, DisconnectReason, NetcodeClient, NetcodeError, NetcodeServer,
+    ServerConfig, ServerResult, NETCODE_MAX_PACKET_BYTES, NETCODE_USER_DATA_BYTES,
+};
+
+use crate::link_conditioner::{LinkConditioner, LinkConditions};
+
+/// A UDP socket seen through the simulated link, outgoing and incoming packets are conditioned
+/// separately.
+#[derive(Debug)]
+struct ConditionedSocket {
+    socket: UdpSocket,
+    outgoing: LinkConditioner,
+    incoming: LinkConditioner,
+}
+
+impl ConditionedSocket {
+    fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            outgoing: LinkConditioner::default(),
+            incoming: LinkConditioner::default(),
+        })
+    }
+
+    fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.outgoing.conditions = conditions;
+        self.incoming.conditions = conditions;
+    }
+
+    fn send_to(&mut self, payload: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if self.outgoing.is_idle() {
+            return self.socket.send_to(payload, addr).map(|_| ());
+        }
+        let now = Instant::now();
+        self.outgoing.push(now, addr, payload);
+        self.flush(now)
+    }
+
+    /// Sends the outgoing packets that are done being delayed.
+    fn flush(&mut self, now: Instant) -> io::Result<()> {
+        while let Some((addr, payload)) = self.outgoing.pop(now) {
+            self.socket.send_to(&payload, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Sends everything still in flight, the app is exiting and won't wait for the delays.
+    fn flush_all(&mut self) {
+        for (addr, payload) in self.outgoing.drain() {
+            if let Err(err) = self.socket.send_to(&payload, addr) {
+                error!("Failed to send packet to {addr}: {err}");
+            }
+        }
+    }
+
+    /// Same contract as `UdpSocket::recv_from` on a non blocking socket: `WouldBlock` once no
+    /// packet made it through the link yet.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if self.incoming.is_idle() {
+            return self.socket.recv_from(buffer);
+        }
+        let now = Instant::now();
+        loop {
+            match self.socket.recv_from(buffer) {
+                Ok((len, addr)) => self.incoming.push(now, addr, &buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => break,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.flush(now)?;
+        match self.incoming.pop(now) {
+            Some((addr, payload)) => {
+                buffer[..payload.len()].copy_from_slice(&payload);
+                Ok((payload.len(), addr))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// `NetcodeServerTransport` over a `ConditionedSocket`.
+#[derive(Debug, Resource)]
+pub struct ConditionedServerTransport {
+    // fork: a `UdpSocket` upstream
+    socket: ConditionedSocket,
+    netcode_server: NetcodeServer,
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+}
+
+impl ConditionedServerTransport {
+    pub fn new(server_config: ServerConfig, socket: UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            // fork: `ConditionedSocket::new` makes it non blocking
+            socket: ConditionedSocket::new(socket)?,
+            netcode_server: NetcodeServer::new(server_config),
+            buffer: [0; NETCODE_MAX_PACKET_BYTES],
+        })
+    }
+
+    /// Returns the user data for client if connected.
+    pub fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        self.netcode_server.user_data(client_id)
+    }
+
+    // fork
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.socket.set_conditions(conditions);
+    }
+
+    /// Disconnects all connected clients, without waiting for the simulated latency.
+    pub fn disconnect_all(&mut self, server: &mut RenetServer) {
+        for client_id in self.netcode_server.clients_id() {
+            let server_result = self.netcode_server.disconnect(client_id);
+            handle_server_result(server_result, &mut self.socket, server);
+        }
+        // fork
+        self.socket.flush_all();
+    }
+
+    /// Advances the transport by the duration, and receive packets from the network.
+    pub fn update(
+        &mut self,
+        duration: Duration,
+        server: &mut RenetServer,
+    ) -> Result<(), NetcodeTransportError> {
+        self.netcode_server.update(duration);
+
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    let server_result = self
+                        .netcode_server
+                        .process_packet(addr, &mut self.buffer[..len]);
+                    handle_server_result(server_result, &mut self.socket, server);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => break,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        for client_id in self.netcode_server.clients_id() {
+            let server_result = self.netcode_server.update_client(client_id);
+            handle_server_result(server_result, &mut self.socket, server);
+        }
+
+        for disconnection_id in server.disconnections_id() {
+            let server_result = self.netcode_server.disconnect(disconnection_id);
+            handle_server_result(server_result, &mut self.socket, server);
+        }
+
+        Ok(())
+    }
+
+    /// Send packets to connected clients.
+    pub fn send_packets(&mut self, server: &mut RenetServer) {
+        'clients: for client_id in server.clients_id() {
+            let packets = server.get_packets_to_send(client_id).unwrap();
+            for packet in packets {
+                match self
+                    .netcode_server
+                    .generate_payload_packet(client_id, &packet)
+                {
+                    Ok((addr, payload)) => {
+                        if let Err(e) = self.socket.send_to(payload, addr) {
+                            error!("Failed to send packet to client {client_id} ({addr}): {e}");
+                            continue 'clients;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to encrypt payload packet for client {client_id}: {e}");
+                        continue 'clients;
+                    }
+                }
+            }
+        }
+        // fork
+        if let Err(e) = self.socket.flush(Instant::now()) {
+            error!("Failed to send delayed packets: {e}");
+        }
+    }
+}
+
+fn handle_server_result(
+    server_result: ServerResult,
+    // fork: a `&UdpSocket` upstream
+    socket: &mut ConditionedSocket,
+    reliable_server: &mut RenetServer,
+) {
+    let mut send_packet = |packet: &[u8], addr: SocketAddr| {
+        if let Err(err) = socket.send_to(packet, addr) {
+            error!("Failed to send packet to {addr}: {err}");
+        }
+    };
+
+    match server_result {
+        ServerResult::None => {}
+        ServerResult::PacketToSend { payload, addr } => {
+            send_packet(payload, addr);
+        }
+        ServerResult::Payload { client_id, payload } => {
+            if let Err(e) = reliable_server.process_packet_from(payload, client_id) {
+                error!("Error while processing payload for {}: {}", client_id, e);
+            }
+        }
+        ServerResult::ClientConnected {
+            client_id,
+            user_data: _,
+            addr,
+            payload,
+        } => {
+            reliable_server.add_connection(client_id);
+            send_packet(payload, addr);
+        }
+        ServerResult::ClientDisconnected {
+            client_id,
+            addr,
+            payload,
+        } => {
+            reliable_server.remove_connection(client_id);
+            if let Some(payload) = payload {
+                send_packet(payload, addr);
+            }
+        }
+    }
+}
+
+/// `NetcodeClientTransport` over a `ConditionedSocket`.
+#[derive(Debug, Resource)]
+pub struct ConditionedClientTransport {
+    // fork: a `UdpSocket` upstream
+    socket: ConditionedSocket,
+    netcode_client: NetcodeClient,
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+}
+
+impl ConditionedClientTransport {
+    pub fn new(
+        current_time: Duration,
+        authentication: ClientAuthentication,
+        socket: UdpSocket,
+    ) -> Result<Self, NetcodeError> {
+        Ok(Self {
+            // fork: `ConditionedSocket::new` makes it non blocking
+            socket: ConditionedSocket::new(socket)?,
+            netcode_client: NetcodeClient::new(current_time, authentication)?,
+            buffer: [0; NETCODE_MAX_PACKET_BYTES],
+        })
+    }
+
+    // fork
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.socket.set_conditions(conditions);
+    }
+
+    /// Disconnect the client from the transport layer, without waiting for the simulated
+    /// latency. Use `RenetClient::disconnect` unless the app is exiting.
+    pub fn disconnect(&mut self) {
+        if self.netcode_client.is_disconnected() {
+            return;
+        }
+
+        match self.netcode_client.disconnect() {
+            Ok((addr, packet)) => {
+                if let Err(e) = self.socket.send_to(packet, addr) {
+                    error!("Failed to send disconnect packet: {e}");
+                }
+            }
+            Err(e) => error!("Failed to generate disconnect packet: {e}"),
+        }
+        // fork
+        self.socket.flush_all();
+    }
+
+    /// If the client is disconnected, returns the reason.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.netcode_client.disconnect_reason()
+    }
+
+    /// Send packets to the server.
+    pub fn send_packets(
+        &mut self,
+        connection: &mut RenetClient,
+    ) -> Result<(), NetcodeTransportError> {
+        if let Some(reason) = self.netcode_client.disconnect_reason() {
+            return Err(NetcodeError::Disconnected(reason).into());
+        }
+
+        let packets = connection.get_packets_to_send();
+        for packet in packets {
+            let (addr, payload) = self.netcode_client.generate_payload_packet(&packet)?;
+            self.socket.send_to(payload, addr)?;
+        }
+        // fork
+        self.socket.flush(Instant::now())?;
+
+        Ok(())
+    }
+
+    /// Advances the transport by the duration, and receive packets from the network.
+    pub fn update(
+        &mut self,
+        duration: Duration,
+        client: &mut RenetClient,
+    ) -> Result<(), NetcodeTransportError> {
+        if let Some(reason) = self.netcode_client.disconnect_reason() {
+            // Mark the client as disconnected if an error occured in the transport layer
+            client.disconnect_due_to_transport();
+
+            return Err(NetcodeError::Disconnected(reason).into());
+        }
+
+        if let Some(error) = client.disconnect_reason() {
+            let (addr, disconnect_packet) = self.netcode_client.disconnect()?;
+            self.socket.send_to(disconnect_packet, addr)?;
+            return Err(error.into());
+        }
+
+        if self.netcode_client.is_connected() {
+            client.set_connected();
+        } else if self.netcode_client.is_connecting() {
+            client.set_connecting();
+        }
+
+        loop {
+            let packet = match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    if addr != self.netcode_client.server_addr() {
+                        debug!("Discarded packet from unknown server {:?}", addr);
+                        continue;
+                    }
+
+                    &mut self.buffer[..len]
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => break,
+                Err(e) => return Err(NetcodeTransportError::IO(e)),
+            };
+
+            if let Some(payload) = self.netcode_client.process_packet(packet) {
+                client.process_packet(payload);
+            }
+        }
+
+        if let Some((packet, addr)) = self.netcode_client.update(duration) {
+            self.socket.send_to(packet, addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces `NetcodeServerPlugin`, drives a `ConditionedServerTransport`.
+pub struct ConditionedServerPlugin;
+
+impl Plugin for ConditionedServerPlugin {
+    fn build(&self, app: &mut App) {
+        // fork: `LinkConditions`
+        app.add_event::<NetcodeTransportError>()
+            .init_resource::<LinkConditions>();
+
+        app.add_systems(
+            PreUpdate,
+            update_server_transport
+                .in_set(RenetReceive)
+                .run_if(resource_exists::<ConditionedServerTransport>)
+                .run_if(resource_exists::<RenetServer>)
+                .after(RenetServerPlugin::update_system)
+                .before(RenetServerPlugin::emit_server_events_system),
+        );
+        app.add_systems(
+            PostUpdate,
+            send_server_packets
+                .in_set(RenetSend)
+                .run_if(resource_exists::<ConditionedServerTransport>)
+                .run_if(resource_exists::<RenetServer>),
+        );
+        app.add_systems(
+            Last,
+            disconnect_server_on_exit
+                .run_if(resource_exists::<ConditionedServerTransport>)
+                .run_if(resource_exists::<RenetServer>),
+        );
+    }
+}
+
+fn update_server_transport(
+    mut transport: ResMut<ConditionedServerTransport>,
+    mut server: ResMut<RenetServer>,
+    conditions: Res<LinkConditions>,
+    time: Res<Time>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+) {
+    // fork
+    if conditions.is_changed() {
+        transport.set_conditions(*conditions);
+    }
+    if let Err(e) = transport.update(time.delta(), &mut server) {
+        transport_errors.send(e);
+    }
+}
+
+fn send_server_packets(
+    mut transport: ResMut<ConditionedServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    transport.send_packets(&mut server);
+}
+
+fn disconnect_server_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<ConditionedServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    if !exit.is_empty() {
+        transport.disconnect_all(&mut server);
+    }
+}
+
+/// Replaces `NetcodeClientPlugin`, drives a `ConditionedClientTransport`.
+pub struct ConditionedClientPlugin;
+
+impl Plugin for ConditionedClientPlugin {
+    fn build(&self, app: &mut App) {
+        // fork: `LinkConditions`
+        app.add_event::<NetcodeTransportError>()
+            .init_resource::<LinkConditions>();
+
+        app.add_systems(
+            PreUpdate,
+            update_client_transport
+                .in_set(RenetReceive)
+                .run_if(resource_exists::<ConditionedClientTransport>)
+                .run_if(resource_exists::<RenetClient>)
+                .after(RenetClientPlugin::update_system),
+        );
+        app.add_systems(
+            PostUpdate,
+            send_client_packets
+                .in_set(RenetSend)
+                .run_if(resource_exists::<ConditionedClientTransport>)
+                .run_if(resource_exists::<RenetClient>),
+        );
+        app.add_systems(
+            Last,
+            disconnect_client_on_exit
+                .run_if(resource_exists::<ConditionedClientTransport>)
+                .run_if(resource_exists::<RenetClient>),
+        );
+    }
+}
+
+fn update_client_transport(
+    mut transport: ResMut<ConditionedClientTransport>,
+    mut client: ResMut<RenetClient>,
+    conditions: Res<LinkConditions>,
+    time: Res<Time>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+) {
+    // fork: a transport replaced on reconnect starts with an ideal link
+    if conditions.is_changed() || transport.is_added() {
+        transport.set_conditions(*conditions);
+    }
+    if let Err(e) = transport.update(time.delta(), &mut client) {
+        transport_errors.send(e);
+    }
+}
+
+fn send_client_packets(
+    mut transport: ResMut<ConditionedClientTransport>,
+    mut client: ResMut<RenetClient>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+) {
+    if let Err(e) = transport.send_packets(&mut client) {
+        transport_errors.send(e);
+    }
+}
+
+fn disconnect_client_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<ConditionedClientTransport>,
+) {
+    if !exit.is_empty() {
+        transport.disconnect();
+    }
+}
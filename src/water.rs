@@ -14,6 +14,11 @@ use bevy_renet::renet::RenetServer;
 use crate::{
     camera::{CameraSensitivity, PlayerMarker},
     character::*,
+    client::ControlledPlayer,
+    codec::QuantizedPosition,
+    prediction::PredictionHistory,
+    replication::ServerMotion,
+    server::{relevancy::RelevantMessages, ServerMessages},
 };
 use bevy::render::view::RenderLayers;
 
@@ -24,11 +29,19 @@ impl Plugin for WaterPlugin {
         app.add_systems(OnEnter(GameState::Game), (water_setup /*spawn_player*/,))
             .add_systems(
                 FixedUpdate,
-                (handle_rocket_collision, handle_rocket_explosion).chain(),
+                (
+                    handle_rocket_collision,
+                    handle_rocket_explosion,
+                    send_rocket_explosions.run_if(resource_exists::<RenetServer>),
+                )
+                    .chain(),
             )
             .add_systems(Update, debug_rocket_explosion)
             .add_event::<RocketExplosion>()
-            .init_state::<GameState>();
+            .init_resource::<MapName>()
+            .init_state::<GameState>()
+            // the client goes back to the menu when it loses the server, the map is loaded again
+            .enable_state_scoped_entities::<GameState>();
     }
 }
 #[derive(States, Debug, Clone, PartialEq, Eq, Hash, Default)]
@@ -38,19 +51,34 @@ pub enum GameState {
     Game,
 }
 
+/// Map loaded when the game starts, see `DEFAULT_MAP`.
+#[derive(Resource, Debug, Clone)]
+pub struct MapName(pub String);
+
+impl Default for MapName {
+    fn default() -> Self {
+        Self(DEFAULT_MAP.to_string())
+    }
+}
+
 fn water_setup(
+    map: Res<MapName>,
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
 ) {
     commands.spawn((
-        SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset("maps/map_test.glb"))),
+        StateScoped(GameState::Game),
+        SceneRoot(
+            asset_server.load(GltfAssetLabel::Scene(0).from_asset(format!("maps/{}.glb", map.0))),
+        ),
         ColliderConstructorHierarchy::new(ColliderConstructor::TrimeshFromMesh),
         RigidBody::Static,
     ));
 
     commands.spawn((
+        StateScoped(GameState::Game),
         DirectionalLight {
             illuminance: light_consts::lux::OVERCAST_DAY,
             shadows_enabled: true,
@@ -109,6 +137,7 @@ fn water_setup(
      */
 
     commands.spawn((
+        StateScoped(GameState::Game),
         Name::new("Big Cube"),
         RigidBody::Static,
         Collider::cuboid(10.0, 10.0, 10.0),
@@ -150,6 +179,7 @@ fn water_setup(
      */
     // light
     commands.spawn((
+        StateScoped(GameState::Game),
         PointLight {
             shadows_enabled: true,
             ..default()
@@ -157,6 +187,7 @@ fn water_setup(
         Transform::from_xyz(4.0, 8.0, 4.0),
     ));
     commands.spawn((
+        StateScoped(GameState::Game),
         PointLight {
             shadows_enabled: true,
             ..default()
@@ -164,6 +195,7 @@ fn water_setup(
         Transform::from_xyz(6.0, 10.0, 83.0),
     ));
     commands.spawn((
+        StateScoped(GameState::Game),
         PointLight {
             shadows_enabled: true,
             ..default()
@@ -172,6 +204,7 @@ fn water_setup(
     ));
 
     commands.spawn((
+        StateScoped(GameState::Game),
         PointLight {
             shadows_enabled: true,
             ..default()
@@ -179,6 +212,7 @@ fn water_setup(
         Transform::from_xyz(17.0, -2.0, 75.0),
     ));
     commands.spawn((
+        StateScoped(GameState::Game),
         PointLight {
             shadows_enabled: true,
             ..default()
@@ -262,13 +296,32 @@ fn spawn_player(
         .add_child(arm);
 }
 
+/// Rockets are owned by the server, clients get a replicated copy without a collider.
 #[derive(Component)]
 pub struct Rocket;
 
+/// What a rocket looks like, on the server and on clients.
+pub fn rocket_visuals(
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+) -> impl Bundle {
+    (
+        Name::new("Bullet"),
+        Rocket,
+        Mesh3d(meshes.add(Cuboid::from_length(0.2))),
+        MeshMaterial3d(materials.add(Color::srgb_u8(154, 109, 100))),
+    )
+}
+
+/// Sent by `handle_rocket_collision` on the server, and by clients when the server tells them
+/// about it with `ServerMessages::RocketExploded`.
 #[derive(Event, Clone)]
 pub struct RocketExplosion {
-    pos: Vec3,
-    ent: Entity,
+    pub pos: Vec3,
+    // None on clients that never got to see the rocket
+    pub ent: Option<Entity>,
+    // server tick it exploded on, only set on clients
+    pub tick: Option<u32>,
 }
 
 fn handle_rocket_collision(
@@ -277,6 +330,8 @@ fn handle_rocket_collision(
     collider_parents: Query<&ColliderParent, Without<Sensor>>,
     mut explosion: EventWriter<RocketExplosion>,
 ) {
+    // a rocket touching several colliders still explodes once
+    let mut exploded = Vec::new();
     for contacts in collisions.iter() {
         let Ok([collider_parent1, collider_parent2]) =
             collider_parents.get_many([contacts.entity1, contacts.entity2])
@@ -291,10 +346,15 @@ fn handle_rocket_collision(
         } else {
             continue;
         };
+        if exploded.contains(&ent) {
+            continue;
+        }
+        exploded.push(ent);
 
         explosion.send(RocketExplosion {
             pos: rocket_tf.translation,
-            ent,
+            ent: Some(ent),
+            tick: None,
         });
     }
 }
@@ -305,24 +365,54 @@ pub struct PreviousImpulses {
     pub end: Vec<Vec3>,
 }
 
+/// What an explosion knocks back, and on clients which predicted tick to record it on.
+type ExplosionTargets<'a> = (
+    &'a mut LinearVelocity,
+    &'a Transform,
+    &'a mut Health,
+    Option<&'a ServerMotion>,
+    Has<ControlledPlayer>,
+);
+
 fn handle_rocket_explosion(
     mut explosion: EventReader<RocketExplosion>,
     mut commands: Commands,
-    mut players_q: Query<(&mut LinearVelocity, &Transform, &mut Health), With<PlayerMarker>>,
-    server: Option<ResMut<RenetServer>>,
+    mut players_q: Query<ExplosionTargets, With<PlayerMarker>>,
+    server: Option<Res<RenetServer>>,
+    mut prediction_history: Option<ResMut<PredictionHistory>>,
 ) {
     for ev in explosion.read() {
         debug!("explosion at {:?}", ev.pos);
-        commands.entity(ev.ent).despawn();
+        if let Some(ent) = ev.ent {
+            if let Some(mut rocket) = commands.get_entity(ent) {
+                rocket.despawn();
+            }
+        }
 
-        for (mut player_vel, player_tf, player_health) in players_q.iter_mut() {
+        // clients predict the knockback on their own player, the others are interpolated
+        for (mut player_vel, player_tf, player_health, motion, controlled) in players_q.iter_mut() {
+            if server.is_none() && !controlled {
+                continue;
+            }
             debug!("player health {:?}", player_health);
             if player_tf.translation.distance(ev.pos) <= ROCKET_EXPLOSION_RADIUS {
                 let distance = player_tf.translation - ev.pos;
                 let normalized_impulse = distance.normalize();
 
                 //player_vel.0 += normalized_impulse * ROCKET_EXPLOSION_FORCE * (1.0 / distance.norm_squared());
-                player_vel.0 += normalized_impulse * ROCKET_EXPLOSION_FORCE;
+                let impulse = normalized_impulse * ROCKET_EXPLOSION_FORCE;
+                player_vel.0 += impulse;
+
+                // the server applied it on the input it was simulating at that tick, one input
+                // per tick since the last one it acknowledged
+                if let (Some(history), Some(motion), Some(tick)) =
+                    (prediction_history.as_mut(), motion, ev.tick)
+                {
+                    let sequence = motion
+                        .input_sequence
+                        .wrapping_add(tick.wrapping_sub(motion.tick));
+                    history.record_impulse(sequence, impulse);
+                }
 
                 if server.is_some() {
                     let damage = (MAX_ROCKET_DAMAGE as f32
@@ -331,15 +421,38 @@ fn handle_rocket_explosion(
                     debug!("Damage computed: {:?}", damage);
                     //player_health.0 = player_health.0.saturating_sub(damage);
                 }
-                debug!(
-                    "impulse vector {:?}",
-                    normalized_impulse * ROCKET_EXPLOSION_FORCE
-                );
+                debug!("impulse vector {:?}", impulse);
             }
         }
     }
 }
 
+/// Tells the clients that can see an explosion, or get knocked back by it, where it was.
+fn send_rocket_explosions(
+    mut explosion: EventReader<RocketExplosion>,
+    players_q: Query<(Entity, &Transform), With<PlayerMarker>>,
+    mut messages: RelevantMessages,
+) {
+    for ev in explosion.read() {
+        let Some(ent) = ev.ent else {
+            continue;
+        };
+        // reliable, clients show it and apply the knockback exactly where we detonated
+        let message = ServerMessages::RocketExploded {
+            id: ent,
+            pos: QuantizedPosition::new(ev.pos),
+        };
+        let knocked_back = players_q
+            .iter()
+            .filter(|(_, player_tf)| {
+                player_tf.translation.distance(ev.pos) <= ROCKET_EXPLOSION_RADIUS
+            })
+            .map(|(player, _)| player);
+        let entities: Vec<Entity> = [ent].into_iter().chain(knocked_back).collect();
+        messages.send(message, &entities, &[ev.pos]);
+    }
+}
+
 #[derive(Default)]
 pub struct PreviousExplosions {
     pub explosions: Vec<Vec3>,
@@ -365,8 +478,6 @@ fn debug_rocket_explosion(
                 gizmos.line(previous_impulses.start[i], previous_impulses.end[i], GREEN);
             });
         for ev in explosion.read() {
-            // todo make this server authoritative.
-            // it sometimes collides only on the server but then the client will not see the explosion
             debug!("explosion at {:?}", ev.pos);
             previous_explosions.explosions.push(ev.pos);
 